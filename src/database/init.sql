@@ -51,7 +51,7 @@ CREATE TABLE IF NOT EXISTS nar (
     nar_size INTEGER NOT NULL,
 
     deriver TEXT NULL,
-    sig TEXT NULL, -- Space separated
+    sig TEXT NULL, -- Newline separated
     ca TEXT NULL,
 
     -- Pending, Available, Trashed
@@ -0,0 +1,444 @@
+//! Portable dump of roots and NAR metadata, one JSON object per line.
+//!
+//! `{"type":"nar","store_path":..,"status":..,"meta":{..},"references":..}`
+//! `{"type":"root","channel_url":..,..,"status":..,"paths":[..]}`
+use super::{format_time, model::*, Database, Error, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, NO_PARAMS};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+fn nar_status_str(status: NarStatus) -> &'static str {
+    match status {
+        NarStatus::Pending => "pending",
+        NarStatus::Available => "available",
+        NarStatus::Trashed => "trashed",
+    }
+}
+
+fn root_status_str(status: RootStatus) -> &'static str {
+    match status {
+        RootStatus::Pending => "pending",
+        RootStatus::Downloading => "downloading",
+        RootStatus::Available => "available",
+    }
+}
+
+fn parse_err(msg: String) -> Error {
+    Error::ParseError(failure::err_msg(msg))
+}
+
+fn get_str<'a>(obj: &'a Value, key: &str) -> Result<&'a str> {
+    obj[key]
+        .as_str()
+        .ok_or_else(|| parse_err(format!("Missing string field '{}'", key)))
+}
+
+fn get_opt_string(obj: &Value, key: &str) -> Result<Option<String>> {
+    match &obj[key] {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s.clone())),
+        _ => Err(parse_err(format!("Invalid field '{}'", key))),
+    }
+}
+
+/// A parsed line of a dump.
+enum Entry {
+    Nar(NarStatus, Nar),
+    Root(Root, Vec<StorePath>),
+}
+
+impl Database {
+    /// Export all roots and NARs, NARs first. Each row is written as it's read.
+    pub fn export_jsonl(&self, mut w: impl Write) -> Result<()> {
+        // The first error, since callbacks cannot return one.
+        let mut ret = Ok(());
+        let mut write = |line: Result<Value>| {
+            if ret.is_ok() {
+                ret = line.and_then(|line| writeln!(w, "{}", line).map_err(Error::IoError));
+            }
+        };
+
+        for &status in &[NarStatus::Pending, NarStatus::Available, NarStatus::Trashed] {
+            self.select_all_nar(status, |_, nar| {
+                write(Ok(json!({
+                    "type": "nar",
+                    "store_path": nar.store_path.path(),
+                    "status": nar_status_str(status),
+                    "meta": nar.meta,
+                    "references": nar.references,
+                })));
+            })?;
+        }
+
+        self.select_all_root(|id, root| {
+            write(self.select_root_paths(id).map(|paths| {
+                json!({
+                    "type": "root",
+                    "channel_url": root.channel_url,
+                    "cache_url": root.cache_url,
+                    "git_revision": root.git_revision,
+                    "fetch_time": root.fetch_time.as_ref().map(format_time),
+                    "status": root_status_str(root.status),
+                    "paths": paths.iter().map(|path| path.path()).collect::<Vec<_>>(),
+                })
+            }));
+        })?;
+        ret
+    }
+
+    /// Import a dump from `export_jsonl` in one transaction, inserting rows
+    /// while reading. NARs already present are skipped, and so are roots
+    /// matched one-to-one with existing ones of identical metadata, so
+    /// importing twice is harmless.
+    pub fn import_jsonl(&mut self, r: impl BufRead) -> Result<()> {
+        // The input cannot be read twice, so only starting it is retried.
+        self.with_busy_retry(|conn| Ok(conn.execute_batch("BEGIN IMMEDIATE")?))?;
+        match self.import_jsonl_in_txn(r) {
+            Ok(()) => Ok(self.conn.execute_batch("COMMIT")?),
+            Err(err) => {
+                if let Err(err) = self.conn.execute_batch("ROLLBACK") {
+                    log::error!("Cannot rollback import: {}", err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn import_jsonl_in_txn(&self, r: impl BufRead) -> Result<()> {
+        let conn = &self.conn;
+        // References and root paths are linked after all NARs are inserted,
+        // since they may come in any order.
+        conn.execute_batch(
+            r"
+            CREATE TEMP TABLE IF NOT EXISTS import_ref (
+                nar_id INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                base_name TEXT NOT NULL
+            );
+            CREATE TEMP TABLE IF NOT EXISTS import_root_nar (
+                root_id INTEGER NOT NULL,
+                hash TEXT NOT NULL
+            );
+            DELETE FROM temp.import_ref;
+            DELETE FROM temp.import_root_nar;
+            ",
+        )?;
+        let mut stmt_insert_ref =
+            conn.prepare_cached(r"INSERT INTO temp.import_ref VALUES (?, ?, ?)")?;
+        let mut stmt_insert_root_nar =
+            conn.prepare_cached(r"INSERT INTO temp.import_root_nar VALUES (?, ?)")?;
+        let mut stmt_count_roots = conn.prepare_cached(
+            r"
+            SELECT COUNT(*)
+                FROM root
+                WHERE channel_url IS ?
+                    AND cache_url IS ?
+                    AND git_revision IS ?
+                    AND fetch_time IS ?
+                    AND id <= ?
+            ",
+        )?;
+        // Only roots existing before the import are matched, each at most once.
+        let max_root_id: i64 =
+            conn.query_row("SELECT IFNULL(MAX(id), 0) FROM root", NO_PARAMS, |row| {
+                row.get(0)
+            })?;
+        let mut matched_roots = HashMap::new();
+
+        for (idx, line) in r.lines().enumerate() {
+            let line = line.map_err(Error::IoError)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = parse_entry(&line).map_err(|err| match err {
+                Error::ParseError(err) => parse_err(format!("Invalid line {}: {}", idx + 1, err)),
+                err => err,
+            })?;
+            match entry {
+                Entry::Nar(status, nar) => {
+                    let nar_id =
+                        match Self::insert_nar_row(conn, status, &nar.store_path, &nar.meta, None)?
+                        {
+                            Some(id) => id,
+                            None => continue,
+                        };
+                    for path in nar.ref_paths() {
+                        let path = path.map_err(Error::ParseError)?;
+                        stmt_insert_ref.execute(params![
+                            nar_id,
+                            path.hash_str(),
+                            path.base_name()
+                        ])?;
+                    }
+                }
+                Entry::Root(root, paths) => {
+                    let fetch_time = root.fetch_time.as_ref().map(format_time);
+                    let key = (
+                        root.channel_url.clone(),
+                        root.cache_url.clone(),
+                        root.git_revision.clone(),
+                        fetch_time.clone(),
+                    );
+                    let existing: i64 = stmt_count_roots
+                        .query_row(params![key.0, key.1, key.2, key.3, max_root_id], |row| {
+                            row.get(0)
+                        })?;
+                    let matched = matched_roots.entry(key).or_insert(0);
+                    if *matched < existing {
+                        *matched += 1;
+                        continue;
+                    }
+                    conn.execute(
+                        r"
+                        INSERT INTO root
+                            (channel_url, cache_url, git_revision, fetch_time, status)
+                            VALUES (?, ?, ?, ?, ?)
+                        ",
+                        params![
+                            root.channel_url,
+                            root.cache_url,
+                            root.git_revision,
+                            fetch_time,
+                            root.status,
+                        ],
+                    )?;
+                    let root_id = conn.last_insert_rowid();
+                    for path in &paths {
+                        stmt_insert_root_nar.execute(params![root_id, path.hash_str()])?;
+                    }
+                }
+            }
+        }
+
+        conn.execute_batch(
+            r"
+            INSERT INTO nar_ref (nar_id, ref_id)
+                SELECT i.nar_id, nar.id
+                    FROM temp.import_ref AS i
+                    JOIN nar ON nar.hash = i.hash
+                    WHERE true
+                    ON CONFLICT DO NOTHING;
+            UPDATE nar
+                SET missing_refs = (
+                    SELECT GROUP_CONCAT(i.base_name, ' ')
+                        FROM temp.import_ref AS i
+                        WHERE i.nar_id = nar.id
+                            AND i.hash NOT IN (SELECT hash FROM main.nar)
+                )
+                WHERE id IN (SELECT nar_id FROM temp.import_ref);
+            INSERT INTO root_nar (root_id, nar_id)
+                SELECT i.root_id, nar.id
+                    FROM temp.import_root_nar AS i
+                    JOIN nar ON nar.hash = i.hash
+                    WHERE true
+                    ON CONFLICT DO NOTHING;
+            DELETE FROM temp.import_ref;
+            DELETE FROM temp.import_root_nar;
+            ",
+        )?;
+        Ok(())
+    }
+}
+
+fn parse_entry(line: &str) -> Result<Entry> {
+    let obj: Value = serde_json::from_str(line).map_err(|err| Error::ParseError(err.into()))?;
+    match get_str(&obj, "type")? {
+        "nar" => {
+            let (status, nar) = parse_nar(&obj)?;
+            Ok(Entry::Nar(status, nar))
+        }
+        "root" => {
+            let (root, paths) = parse_root(&obj)?;
+            Ok(Entry::Root(root, paths))
+        }
+        ty => Err(parse_err(format!("Unknown type '{}'", ty))),
+    }
+}
+
+fn parse_nar(obj: &Value) -> Result<(NarStatus, Nar)> {
+    let status = match get_str(obj, "status")? {
+        "pending" => NarStatus::Pending,
+        "available" => NarStatus::Available,
+        "trashed" => NarStatus::Trashed,
+        s => return Err(parse_err(format!("Invalid NAR status '{}'", s))),
+    };
+    let mut meta = obj["meta"].clone();
+    // `sig` was a single optional string in older dumps.
+    if let Some(sig @ Value::String(_)) = meta.get_mut("sig") {
+        *sig = Value::Array(vec![sig.take()]);
+    }
+    let nar = Nar {
+        store_path: StorePath::try_from_any_root(get_str(obj, "store_path")?)
+            .map_err(Error::ParseError)?,
+        meta: serde_json::from_value(meta).map_err(|err| Error::ParseError(err.into()))?,
+        references: get_str(obj, "references")?.to_owned(),
+    };
+    Ok((status, nar))
+}
+
+fn parse_root(obj: &Value) -> Result<(Root, Vec<StorePath>)> {
+    let status = match get_str(obj, "status")? {
+        "pending" => RootStatus::Pending,
+        "downloading" => RootStatus::Downloading,
+        "available" => RootStatus::Available,
+        s => return Err(parse_err(format!("Invalid root status '{}'", s))),
+    };
+    let fetch_time = match get_opt_string(obj, "fetch_time")? {
+        None => None,
+        Some(s) => Some(
+            s.parse::<DateTime<Utc>>()
+                .map_err(|err| Error::ParseError(err.into()))?,
+        ),
+    };
+    let root = Root {
+        channel_url: get_opt_string(obj, "channel_url")?,
+        cache_url: get_opt_string(obj, "cache_url")?,
+        git_revision: get_opt_string(obj, "git_revision")?,
+        fetch_time,
+        status,
+    };
+    let paths = obj["paths"]
+        .as_array()
+        .ok_or_else(|| parse_err("Missing array field 'paths'".to_owned()))?
+        .iter()
+        .map(|path| {
+            let path = path
+                .as_str()
+                .ok_or_else(|| parse_err("Invalid path".to_owned()))?;
+            StorePath::try_from_any_root(path).map_err(Error::ParseError)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((root, paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_nar;
+
+    fn sorted_export(db: &Database) -> Vec<String> {
+        let mut buf = vec![];
+        db.export_jsonl(&mut buf).unwrap();
+        let mut lines = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut db = Database::open_in_memory().unwrap();
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let mut a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            1,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b \
+             dddddddddddddddddddddddddddddddd-d",
+        );
+        a.meta.compression = Some("xz".to_owned());
+        a.meta.sig = vec!["some:sig".to_owned(), "other:sig".to_owned()];
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&b, &a])
+            .unwrap();
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c])
+            .unwrap();
+        let root = Root {
+            channel_url: Some("https://nixos.org/channels/nixos-unstable".to_owned()),
+            cache_url: Some("https://cache.nixos.org".to_owned()),
+            git_revision: Some("0123456789abcdef0123456789abcdef01234567".to_owned()),
+            fetch_time: Some("2019-12-01T00:00:00.123456Z".parse().unwrap()),
+            status: RootStatus::Available,
+        };
+        db.insert_root(&root, vec![a.store_path.hash()]).unwrap();
+        // Distinct roots with identical metadata.
+        db.insert_root(&Root::default(), vec![c.store_path.hash()])
+            .unwrap();
+        db.insert_root(&Root::default(), vec![b.store_path.hash()])
+            .unwrap();
+
+        let mut dump = vec![];
+        db.export_jsonl(&mut dump).unwrap();
+        assert_eq!(String::from_utf8_lossy(&dump).lines().count(), 6);
+        assert!(String::from_utf8_lossy(&dump).contains("\"2019-12-01T00:00:00.123456Z\""));
+
+        let mut db2 = Database::open_in_memory().unwrap();
+        db2.import_jsonl(&dump[..]).unwrap();
+        assert_eq!(sorted_export(&db2), sorted_export(&db));
+
+        // Idempotent.
+        db2.import_jsonl(&dump[..]).unwrap();
+        assert_eq!(sorted_export(&db2), sorted_export(&db));
+        let roots: i64 = db2
+            .conn
+            .query_row("SELECT COUNT(*) FROM root", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(roots, 3);
+    }
+
+    #[test]
+    fn test_import_roots_first() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&a])
+            .unwrap();
+        db.insert_root(&Root::default(), vec![a.store_path.hash()])
+            .unwrap();
+        let mut dump = vec![];
+        db.export_jsonl(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let mut lines = dump.lines().collect::<Vec<_>>();
+        lines.reverse();
+
+        let mut db2 = Database::open_in_memory().unwrap();
+        db2.import_jsonl(lines.join("\n").as_bytes()).unwrap();
+        assert_eq!(sorted_export(&db2), sorted_export(&db));
+    }
+
+    #[test]
+    fn test_import_rollback() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&a])
+            .unwrap();
+        let mut dump = vec![];
+        db.export_jsonl(&mut dump).unwrap();
+        dump.extend_from_slice(b"{}\n");
+
+        let mut db2 = Database::open_in_memory().unwrap();
+        assert!(db2.import_jsonl(&dump[..]).is_err());
+        assert_eq!(sorted_export(&db2), Vec::<String>::new());
+        // Usable afterwards.
+        db2.import_jsonl(&dump[..dump.len() - 3]).unwrap();
+        assert_eq!(sorted_export(&db2), sorted_export(&db));
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let mut db = Database::open_in_memory().unwrap();
+        let err = db
+            .import_jsonl(&b"\n{\"type\":\"what\"}\n"[..])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error: Invalid line 2: Unknown type 'what'",
+        );
+    }
+
+    #[test]
+    fn test_import_single_sig() {
+        let mut db = Database::open_in_memory().unwrap();
+        let line = r#"{"type":"nar","store_path":"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a","status":"pending","meta":{"url":"some/url","compression":"xz","file_hash":null,"file_size":1,"nar_hash":"sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73","nar_size":2,"sig":"some:sig"},"references":""}"#;
+        db.import_jsonl(line.as_bytes()).unwrap();
+        let mut sigs = vec![];
+        db.select_all_nar(NarStatus::Pending, |_, nar| sigs = nar.meta.sig)
+            .unwrap();
+        assert_eq!(sigs, vec!["some:sig"]);
+    }
+}
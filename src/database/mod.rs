@@ -1,11 +1,22 @@
-use chrono::SecondsFormat;
+use chrono::{DateTime, SecondsFormat, Utc};
 use failure::Fail;
-use rusqlite::{self, named_params, params, types, Connection, TransactionBehavior, NO_PARAMS};
+use fs2::FileExt as _;
+use rusqlite::{
+    self, named_params, params, types, Connection, ErrorCode, TransactionBehavior, NO_PARAMS,
+};
 use static_assertions::*;
-use std::{convert::TryInto, path::Path};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    path::Path,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 type Result<T> = std::result::Result<T, Error>;
 
+mod jsonl;
 pub mod model;
 use self::model::*;
 
@@ -55,6 +66,20 @@ impl types::ToSql for NarStatus {
     }
 }
 
+impl types::FromSql for Hash {
+    fn column_result(value: types::ValueRef) -> types::FromSqlResult<Self> {
+        let v: String = types::FromSql::column_result(value)?;
+        v.parse()
+            .map_err(|err: failure::Error| types::FromSqlError::Other(Box::new(err.compat())))
+    }
+}
+
+impl types::ToSql for Hash {
+    fn to_sql(&self) -> rusqlite::Result<types::ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "Sqlite error: {}", 0)]
@@ -65,6 +90,14 @@ pub enum Error {
     NotFound,
     #[fail(display = "Parse error: {}", 0)]
     ParseError(failure::Error),
+    #[fail(display = "Database is locked by another writer: {}", 0)]
+    Locked(String),
+    #[fail(display = "Cannot lock database: {}", 0)]
+    LockError(std::io::Error),
+    #[fail(display = "The single writer from `open_exclusive` is required")]
+    NotExclusive,
+    #[fail(display = "IO error: {}", 0)]
+    IoError(std::io::Error),
 }
 
 impl From<rusqlite::Error> for Error {
@@ -85,19 +118,92 @@ impl From<std::num::TryFromIntError> for Error {
 #[derive(Debug)]
 pub struct Database {
     conn: Connection,
+    // Held until drop.
+    _lock: Option<File>,
+    // No other writer can exist, either by the lock or being in memory.
+    exclusive: bool,
+    // Whether `with_busy_retry` blocks the thread waiting for a busy database.
+    busy_wait: bool,
 }
 
 assert_not_impl_any!(Database: Sync);
 
+fn is_busy(err: &Error) -> bool {
+    match err {
+        Error::SqliteError(rusqlite::Error::SqliteFailure(err, _)) => {
+            err.code == ErrorCode::DatabaseBusy || err.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+/// Timestamps are stored in RFC 3339 at full precision.
+fn format_time(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
 impl Database {
     const APPLICATION_ID: i32 = 0x2237186b;
-    const USER_VERSION: i32 = 1;
+    const USER_VERSION: i32 = 6;
+    // Schema of version 1. Newer versions are reached by `MIGRATIONS`.
     const INIT_SQL: &'static str = include_str!("./init.sql");
+    /// Schema upgrades as `(from_version, sql)`, each advancing by one version.
+    const MIGRATIONS: &'static [(i32, &'static str)] = &[
+        // The cache a NAR was fetched from. NULL means the cache of its root.
+        (1, "ALTER TABLE nar ADD COLUMN cache_url TEXT NULL;"),
+        // `hash` is already indexed by its `UNIQUE` constraint.
+        (
+            2,
+            "CREATE INDEX IF NOT EXISTS nar_status_idx ON nar (status);",
+        ),
+        (
+            3,
+            "CREATE INDEX IF NOT EXISTS root_git_revision_idx ON root (git_revision);",
+        ),
+        // Numbered changes of NARs, for refreshing caches incrementally.
+        // The counter never goes back, even if the latest NARs are deleted.
+        (
+            4,
+            r"
+            ALTER TABLE nar ADD COLUMN status_seq INTEGER NOT NULL DEFAULT 0;
+            UPDATE nar SET status_seq = id;
+            CREATE INDEX IF NOT EXISTS nar_status_seq_idx ON nar (status_seq);
+            CREATE TABLE IF NOT EXISTS nar_seq (seq INTEGER NOT NULL);
+            INSERT INTO nar_seq SELECT COALESCE(MAX(id), 0) FROM nar;
+            CREATE TRIGGER IF NOT EXISTS nar_insert_seq
+                AFTER INSERT ON nar
+                BEGIN
+                    UPDATE nar_seq SET seq = seq + 1;
+                    UPDATE nar SET status_seq = (SELECT seq FROM nar_seq) WHERE id = NEW.id;
+                END;
+            CREATE TRIGGER IF NOT EXISTS nar_status_seq
+                AFTER UPDATE OF status ON nar
+                WHEN OLD.status != NEW.status
+                BEGIN
+                    UPDATE nar_seq SET seq = seq + 1;
+                    UPDATE nar SET status_seq = (SELECT seq FROM nar_seq) WHERE id = NEW.id;
+                END;
+            ",
+        ),
+        // References not in the database, space separated, so that narinfos
+        // are still served with their original `References`.
+        (5, "ALTER TABLE nar ADD COLUMN missing_refs TEXT NULL;"),
+    ];
     const RUN_SQL: &'static str = include_str!("./run.sql");
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_BUSY_RETRY: u32 = 5;
+    // Covering `BUSY_TIMEOUT` as well, since SQLite doesn't wait in between.
+    const MAX_BUSY_RETRY_ASYNC: u32 = 9;
+    const BUSY_RETRY_DELAY: Duration = Duration::from_millis(10);
+    /// `channel_url` of the root keeping uploaded NARs.
+    pub const UPLOAD_ROOT_URL: &'static str = "upload:";
 
     pub fn open_in_memory() -> Result<Self> {
         Self {
             conn: Connection::open_in_memory()?,
+            _lock: None,
+            exclusive: true,
+            busy_wait: true,
         }
         .check_init()
     }
@@ -105,6 +211,39 @@ impl Database {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         Self {
             conn: Connection::open(path.as_ref())?,
+            _lock: None,
+            exclusive: false,
+            busy_wait: true,
+        }
+        .check_init()
+    }
+
+    /// Open as the single writer, holding an advisory lock on `<path>.lock`
+    /// until drop. Fail with `Error::Locked` if another writer holds it.
+    /// Connections from `open` are not affected.
+    pub fn open_exclusive(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(Error::LockError)?;
+        if let Err(err) = lock.try_lock_exclusive() {
+            return Err(if err.kind() == fs2::lock_contended_error().kind() {
+                Error::Locked(path.display().to_string())
+            } else {
+                Error::LockError(err)
+            });
+        }
+
+        Self {
+            conn: Connection::open(path)?,
+            _lock: Some(lock),
+            exclusive: true,
+            busy_wait: true,
         }
         .check_init()
     }
@@ -119,155 +258,591 @@ impl Database {
             .map_err(Into::into)
     }
 
-    fn check_init(self) -> Result<Self> {
+    fn check_init(mut self) -> Result<Self> {
         let (app_id, user_ver) = self.query_version()?;
         if (app_id, user_ver) == (0, 0) {
             self.conn.execute_batch(Self::INIT_SQL)?;
         }
         let (app_id, user_ver) = self.query_version()?;
-        if (app_id, user_ver) != (Self::APPLICATION_ID, Self::USER_VERSION) {
+        if app_id != Self::APPLICATION_ID || !(1..=Self::USER_VERSION).contains(&user_ver) {
             return Err(Error::InvalidDatabase(format!(
                 "Invalid database, expect (app_id, user_ver): {:?}, found {:?}",
                 (Self::APPLICATION_ID, Self::USER_VERSION),
                 (app_id, user_ver),
             )));
         }
+        if user_ver < Self::USER_VERSION {
+            self.migrate(Self::MIGRATIONS, Self::USER_VERSION)?;
+        }
         self.conn.execute_batch(Self::RUN_SQL)?;
+        self.conn.busy_timeout(Self::BUSY_TIMEOUT)?;
         Ok(self)
     }
 
+    /// Apply `migrations` step by step up to version `target` in one transaction.
+    fn migrate(&mut self, migrations: &[(i32, &str)], target: i32) -> Result<()> {
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            // Others may have migrated it before we got the lock.
+            let mut ver: i32 =
+                txn.query_row("SELECT * FROM main.pragma_user_version", NO_PARAMS, |row| {
+                    row.get(0)
+                })?;
+            while ver < target {
+                let (_, sql) = migrations
+                    .iter()
+                    .find(|(from, _)| *from == ver)
+                    .ok_or_else(|| {
+                        Error::InvalidDatabase(format!("No migration from version {}", ver))
+                    })?;
+                log::info!("Migrating database from version {} to {}", ver, ver + 1);
+                txn.execute_batch(sql)?;
+                ver += 1;
+                txn.execute_batch(&format!("PRAGMA main.user_version = {}", ver))?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Run a write transaction, retrying with some jitter if the database
+    /// is still busy after `busy_timeout`.
+    fn with_busy_retry<T>(&mut self, mut f: impl FnMut(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            match f(&mut self.conn) {
+                Err(err) if self.busy_wait && is_busy(&err) && retry < Self::MAX_BUSY_RETRY => {
+                    retry += 1;
+                    let delay = Self::busy_retry_delay(retry);
+                    log::debug!("Database busy, retry {} after {:?}", retry, delay);
+                    thread::sleep(delay);
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    /// Run `f` doing writes, waiting for a busy database with a timer
+    /// instead of blocking the thread. For callers in async code.
+    pub(crate) async fn with_busy_retry_async<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            self.conn.busy_timeout(Duration::from_millis(0))?;
+            self.busy_wait = false;
+            let ret = f(self);
+            self.busy_wait = true;
+            self.conn.busy_timeout(Self::BUSY_TIMEOUT)?;
+            match ret {
+                Err(err) if is_busy(&err) && retry < Self::MAX_BUSY_RETRY_ASYNC => {
+                    retry += 1;
+                    let delay = Self::busy_retry_delay(retry);
+                    log::debug!("Database busy, retry {} after {:?}", retry, delay);
+                    async_std::task::sleep(delay).await;
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    /// Exponential backoff with some jitter.
+    fn busy_retry_delay(retry: u32) -> Duration {
+        let jitter = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos())
+            % Self::BUSY_RETRY_DELAY.subsec_nanos();
+        Self::BUSY_RETRY_DELAY * (1 << retry) + Duration::from_nanos(jitter.into())
+    }
+
     pub(crate) fn insert_root(
         &mut self,
         root: &Root,
         root_hashes: impl IntoIterator<Item = StorePathHash>,
     ) -> Result<i64> {
-        let txn = self
-            .conn
-            .transaction_with_behavior(TransactionBehavior::Immediate)?;
-        txn.execute_named(
+        let root_hashes: Vec<_> = root_hashes.into_iter().collect();
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute_named(
+                r"
+                INSERT INTO root
+                    (channel_url, cache_url, git_revision, fetch_time, status)
+                    VALUES
+                    (:channel_url, :cache_url, :git_revision, :fetch_time, :status)
+                ",
+                named_params! {
+                    ":channel_url": root.channel_url,
+                    ":cache_url": root.cache_url,
+                    ":git_revision": root.git_revision,
+                    ":fetch_time": root.fetch_time.as_ref().map(format_time),
+                    ":status": root.status,
+                },
+            )?;
+            let root_id = txn.last_insert_rowid();
+
+            let mut stmt = txn.prepare_cached(
+                r"
+                INSERT INTO root_nar (root_id, nar_id)
+                SELECT :root_id, id
+                    FROM nar
+                    WHERE hash = :hash
+                    ON CONFLICT DO NOTHING
+                ",
+            )?;
+
+            for hash in &root_hashes {
+                stmt.execute_named(named_params! {
+                    ":root_id": root_id,
+                    ":hash": hash.as_str(),
+                })?;
+            }
+
+            drop(stmt);
+            txn.commit()?;
+            Ok(root_id)
+        })
+    }
+
+    /// Get the latest root with the given git revision.
+    pub fn select_root_by_revision(&self, git_revision: &str) -> Result<Option<(i64, Root)>> {
+        match self.conn.query_row_and_then(
             r"
-            INSERT INTO root
-                (channel_url, cache_url, git_revision, fetch_time, status)
-                VALUES
-                (:channel_url, :cache_url, :git_revision, :fetch_time, :status)
+            SELECT id, channel_url, cache_url, git_revision, fetch_time, status
+                FROM root
+                WHERE git_revision = ?
+                ORDER BY id DESC
+                LIMIT 1
+            ",
+            params![git_revision],
+            |row| -> Result<_> { Ok((row.get("id")?, Self::root_from_row(row)?)) },
+        ) {
+            Ok(ret) => Ok(Some(ret)),
+            Err(Error::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the latest root of the channel with the given git revision.
+    pub fn select_channel_root_by_revision(
+        &self,
+        channel_url: &str,
+        git_revision: &str,
+    ) -> Result<Option<(i64, Root)>> {
+        match self.conn.query_row_and_then(
+            r"
+            SELECT id, channel_url, cache_url, git_revision, fetch_time, status
+                FROM root
+                WHERE git_revision = ? AND channel_url = ?
+                ORDER BY id DESC
+                LIMIT 1
+            ",
+            params![git_revision, channel_url],
+            |row| -> Result<_> { Ok((row.get("id")?, Self::root_from_row(row)?)) },
+        ) {
+            Ok(ret) => Ok(Some(ret)),
+            Err(Error::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn select_all_root(&self, mut f: impl FnMut(i64, Root)) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            r"
+            SELECT id, channel_url, cache_url, git_revision, fetch_time, status
+                FROM root
             ",
-            named_params! {
-                ":channel_url": root.channel_url,
-                ":cache_url": root.cache_url,
-                ":git_revision": root.git_revision,
-                ":fetch_time": root
-                    .fetch_time
-                    .as_ref()
-                    .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
-                ":status": root.status,
-            },
         )?;
-        let root_id = txn.last_insert_rowid();
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+            Ok((row.get("id")?, Self::root_from_row(row)?))
+        })?;
+        for row in rows {
+            let (id, root) = row?;
+            f(id, root);
+        }
+        Ok(())
+    }
+
+    /// Columns for `root_record_from_row`.
+    const ROOT_RECORD_COLUMNS: &str = r"
+        id, channel_url, cache_url, git_revision, fetch_time, status,
+        (SELECT COUNT(*) FROM root_nar WHERE root_id = root.id) AS nar_count
+    ";
+
+    /// All roots in ascending order of id.
+    pub fn select_all_roots(&self) -> Result<Vec<RootRecord>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM root ORDER BY id",
+            Self::ROOT_RECORD_COLUMNS,
+        ))?;
+        let roots = stmt
+            .query_and_then(NO_PARAMS, Self::root_record_from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(roots)
+    }
+
+    /// Roots with `status` in ascending order of id, e.g. `Downloading` ones
+    /// left by an interrupted download.
+    pub fn select_roots_by_status(&self, status: RootStatus) -> Result<Vec<RootRecord>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM root WHERE status = ? ORDER BY id",
+            Self::ROOT_RECORD_COLUMNS,
+        ))?;
+        let roots = stmt
+            .query_and_then(params![status], Self::root_record_from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(roots)
+    }
 
-        let mut stmt = txn.prepare_cached(
+    /// Get a root by id, or fail with `Error::NotFound`.
+    pub fn select_root(&self, id: i64) -> Result<RootRecord> {
+        self.conn.query_row_and_then(
+            &format!(
+                "SELECT {} FROM root WHERE id = ?",
+                Self::ROOT_RECORD_COLUMNS,
+            ),
+            params![id],
+            Self::root_record_from_row,
+        )
+    }
+
+    fn root_from_row(row: &rusqlite::Row) -> Result<Root> {
+        Ok(Root {
+            channel_url: row.get("channel_url")?,
+            cache_url: row.get("cache_url")?,
+            git_revision: row.get("git_revision")?,
+            fetch_time: row.get("fetch_time")?,
+            status: row.get("status")?,
+        })
+    }
+
+    fn root_record_from_row(row: &rusqlite::Row) -> Result<RootRecord> {
+        Ok(RootRecord {
+            id: row.get("id")?,
+            root: Self::root_from_row(row)?,
+            nar_count: row.get::<_, i64>("nar_count")?.try_into()?,
+        })
+    }
+
+    /// Set the status of a root, or fail with `Error::NotFound`.
+    pub fn set_root_status(&mut self, id: i64, status: RootStatus) -> Result<()> {
+        let changed = self.with_busy_retry(|conn| {
+            Ok(conn.execute(
+                "UPDATE root SET status = ? WHERE id = ?",
+                params![status, id],
+            )?)
+        })?;
+        if changed == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Delete a root with its links to NARs, and return the number of links
+    /// removed. NARs which become unreachable are left for `gc_unreferenced`.
+    pub fn delete_root(&mut self, id: i64) -> Result<usize> {
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let links = txn.execute("DELETE FROM root_nar WHERE root_id = ?", params![id])?;
+            if txn.execute("DELETE FROM root WHERE id = ?", params![id])? == 0 {
+                return Err(Error::NotFound);
+            }
+            txn.commit()?;
+            Ok(links)
+        })
+    }
+
+    /// Top-level store paths of a root, without following references.
+    pub fn select_root_paths(&self, root_id: i64) -> Result<Vec<StorePath>> {
+        let mut stmt = self.conn.prepare_cached(
             r"
-            INSERT INTO root_nar (root_id, nar_id)
-            SELECT :root_id, id
+            SELECT store_root, hash, name
+                FROM root_nar
+                JOIN nar ON nar.id = nar_id
+                WHERE root_id = ?
+                ORDER BY nar.id
+            ",
+        )?;
+        let paths = stmt
+            .query_and_then(params![root_id], |row| -> Result<StorePath> {
+                Self::store_path_from_row(row)
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(paths)
+    }
+
+    /// Total `file_size` of the closure of a root, counting each NAR once.
+    pub fn root_closure_size(&self, root_id: i64) -> Result<u64> {
+        let size: i64 = self.conn.query_row(
+            r"
+            WITH RECURSIVE closure (id) AS (
+                SELECT nar_id FROM root_nar WHERE root_id = ?
+                UNION
+                SELECT ref_id FROM nar_ref JOIN closure ON nar_id = closure.id
+            )
+            SELECT COALESCE(SUM(file_size), 0)
                 FROM nar
-                WHERE hash = :hash
+                JOIN closure USING (id)
+            ",
+            params![root_id],
+            |row| row.get(0),
+        )?;
+        Ok(size.try_into()?)
+    }
+
+    /// Insert the NAR row, returning its id, or `None` if it already exists.
+    fn insert_nar_row(
+        conn: &Connection,
+        status: NarStatus,
+        store_path: &StorePath,
+        meta: &NarMeta,
+        cache_url: Option<&str>,
+    ) -> Result<Option<i64>> {
+        let mut stmt = conn.prepare_cached(
+            r"
+            INSERT INTO nar
+                ( store_root, hash, name
+                , url, compression
+                , file_hash, file_size, nar_hash, nar_size
+                , deriver, sig, ca
+                , status, cache_url )
+                VALUES
+                ( :store_root, :hash, :name
+                , :url, :compression
+                , :file_hash, :file_size, :nar_hash, :nar_size
+                , :deriver, :sig, :ca
+                , :status, :cache_url )
+                ON CONFLICT DO NOTHING
             ",
         )?;
+        let ret = stmt.execute_named(named_params! {
+            ":store_root": store_path.root(),
+            ":hash": store_path.hash_str(),
+            ":name": store_path.name(),
 
-        for hash in root_hashes {
-            stmt.execute_named(named_params! {
-                ":root_id": root_id,
-                ":hash": hash.as_str(),
-            })?;
+            ":url": meta.url,
+            ":compression": meta.compression,
+
+            ":file_hash": meta.file_hash,
+            ":file_size": meta.file_size.map(|s| s as i64),
+            ":nar_hash": meta.nar_hash,
+            ":nar_size": meta.nar_size as i64,
+
+            ":deriver": meta.deriver,
+            // Signatures never contain newlines. NULL if there is none.
+            ":sig": Some(meta.sig.join("\n")).filter(|s| !s.is_empty()),
+            ":ca": meta.ca,
+
+            ":status": status,
+            ":cache_url": cache_url,
+        })?;
+        match ret {
+            0 => Ok(None),
+            1 => Ok(Some(conn.last_insert_rowid())),
+            _ => unreachable!(),
         }
+    }
+
+    /// Insert a single NAR with references given as NAR ids, which must be
+    /// already present in database. `self_ref` adds a reference to itself.
+    ///
+    /// Return the id of the new NAR, or `None` if it already exists, in which
+    /// case nothing is changed.
+    pub fn insert_or_ignore_nar(
+        &mut self,
+        status: NarStatus,
+        store_path: &StorePath,
+        meta: &NarMeta,
+        self_ref: bool,
+        ref_ids: &[i64],
+    ) -> Result<Option<i64>> {
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let nar_id = match Self::insert_nar_row(&txn, status, store_path, meta, None)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            {
+                let mut stmt_insert_ref = txn.prepare_cached(
+                    r"
+                    INSERT INTO nar_ref (nar_id, ref_id)
+                        VALUES (?, ?)
+                        ON CONFLICT DO NOTHING
+                    ",
+                )?;
+                let self_id = if self_ref { Some(nar_id) } else { None };
+                for &ref_id in self_id.iter().chain(ref_ids) {
+                    stmt_insert_ref.execute(params![nar_id, ref_id])?;
+                }
+            }
+
+            txn.commit()?;
+            Ok(Some(nar_id))
+        })
+    }
 
-        drop(stmt);
-        txn.commit()?;
-        Ok(root_id)
+    /// Available NARs grouped by compression, as `(compression, count, total_file_size)`.
+    pub fn compression_stats(&self) -> Result<Vec<(Option<String>, u64, u64)>> {
+        let mut stmt = self.conn.prepare_cached(
+            r"
+            SELECT compression, COUNT(*), COALESCE(SUM(file_size), 0)
+                FROM nar
+                WHERE status = 'A'
+                GROUP BY compression
+                ORDER BY compression
+            ",
+        )?;
+        let stats = stmt
+            .query_and_then(NO_PARAMS, |row| -> Result<_> {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, i64>(1)?.try_into()?,
+                    row.get::<_, i64>(2)?.try_into()?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(stats)
+    }
+
+    /// Count and total sizes of available NARs. NARs without `file_size`
+    /// are counted by `nar_size` on disk.
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        self.conn.query_row_and_then(
+            r"
+            SELECT
+                COUNT(*),
+                COALESCE(SUM(COALESCE(file_size, nar_size)), 0),
+                COALESCE(SUM(nar_size), 0)
+                FROM nar
+                WHERE status = 'A'
+            ",
+            NO_PARAMS,
+            |row| -> Result<_> {
+                Ok(StorageStats {
+                    nar_count: row.get::<_, i64>(0)?.try_into()?,
+                    file_size: row.get::<_, i64>(1)?.try_into()?,
+                    nar_size: row.get::<_, i64>(2)?.try_into()?,
+                })
+            },
+        )
+    }
+
+    /// Number of NARs with `status`. Cheap enough for health checks.
+    pub fn count_nars(&self, status: NarStatus) -> Result<u64> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM nar WHERE status = ?",
+            params![status],
+            |row| row.get(0),
+        )?;
+        Ok(count.try_into()?)
     }
 
     /// References must be already present in database.
-    pub(crate) fn insert_or_ignore_nars<N, I>(&mut self, status: NarStatus, nars: I) -> Result<()>
+    #[cfg(test)]
+    pub(crate) fn insert_or_ignore_nars<N, I>(
+        &mut self,
+        status: NarStatus,
+        nars: I,
+    ) -> Result<InsertSummary>
     where
         I: IntoIterator<Item = N>,
         N: std::borrow::Borrow<Nar>,
     {
-        let txn = self
-            .conn
-            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        self.insert_or_ignore_nars_from(status, nars.into_iter().map(|nar| (nar, None)))
+    }
 
-        {
-            let mut stmt_insert_nar = txn.prepare_cached(
-                r"
-                INSERT INTO nar
-                    ( store_root, hash, name
-                    , url, compression
-                    , file_hash, file_size, nar_hash, nar_size
-                    , deriver, sig, ca
-                    , status )
-                    VALUES
-                    ( :store_root, :hash, :name
-                    , :url, :compression
-                    , :file_hash, :file_size, :nar_hash, :nar_size
-                    , :deriver, :sig, :ca
-                    , :status )
-                    ON CONFLICT DO NOTHING
-                ",
-            )?;
+    /// Insert NARs with the cache each one is fetched from, which is recorded
+    /// only if it's not the one of its root.
+    /// References should be already present in database. Missing ones are
+    /// recorded by name only, and are not followed.
+    pub(crate) fn insert_or_ignore_nars_from<'a, N, I>(
+        &mut self,
+        status: NarStatus,
+        nars: I,
+    ) -> Result<InsertSummary>
+    where
+        I: IntoIterator<Item = (N, Option<&'a str>)>,
+        N: std::borrow::Borrow<Nar>,
+    {
+        let nars: Vec<(N, Option<&str>)> = nars.into_iter().collect();
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut summary = InsertSummary::default();
 
-            let mut stmt_insert_ref = txn.prepare_cached(
-                r"
-                INSERT INTO nar_ref (nar_id, ref_id)
-                SELECT :nar_id, id
-                    FROM nar
-                    WHERE hash = :hash
-                ",
-            )?;
+            {
+                let mut stmt_select_id =
+                    txn.prepare_cached(r"SELECT id FROM nar WHERE hash = ?")?;
+                let mut stmt_insert_ref =
+                    txn.prepare_cached(r"INSERT INTO nar_ref (nar_id, ref_id) VALUES (?, ?)")?;
+                let mut stmt_set_missing =
+                    txn.prepare_cached(r"UPDATE nar SET missing_refs = ? WHERE id = ?")?;
+                // Ids of NARs inserted in this batch. Most references point to them.
+                let mut ids: HashMap<StorePathHash, i64> = HashMap::with_capacity(nars.len());
 
-            for nar in nars {
-                let nar = nar.borrow();
-                let ret = stmt_insert_nar.execute_named(named_params! {
-                    ":store_root": nar.store_path.root(),
-                    ":hash": nar.store_path.hash_str(),
-                    ":name": nar.store_path.name(),
-
-                    ":url": nar.meta.url,
-                    ":compression": nar.meta.compression,
-
-                    ":file_hash": nar.meta.file_hash,
-                    ":file_size": nar.meta.file_size.map(|s| s as i64),
-                    ":nar_hash": nar.meta.nar_hash,
-                    ":nar_size": nar.meta.nar_size as i64,
-
-                    ":deriver": nar.meta.deriver,
-                    ":sig": nar.meta.sig,
-                    ":ca": nar.meta.ca,
-
-                    ":status": status,
-                });
-
-                match ret {
-                    Ok(0) => {}
-                    Ok(1) => {
-                        let nar_id = txn.last_insert_rowid();
-                        for hash in nar.ref_hashes() {
-                            // Self reference works here.
-                            stmt_insert_ref.execute_named(named_params! {
-                                ":nar_id": nar_id,
-                                ":hash": hash.expect("Invalid nar to insert").as_str(),
-                            })?;
+                for (nar, cache_url) in &nars {
+                    let nar = nar.borrow();
+                    let nar_id = match Self::insert_nar_row(
+                        &txn,
+                        status,
+                        &nar.store_path,
+                        &nar.meta,
+                        *cache_url,
+                    )? {
+                        None => {
+                            summary.skipped += 1;
+                            continue;
                         }
+                        Some(nar_id) => nar_id,
+                    };
+                    summary.inserted += 1;
+                    summary.inserted_file_size += nar.meta.file_size.unwrap_or(0);
+                    // Self reference works here.
+                    ids.insert(nar.store_path.hash(), nar_id);
+                    let mut missing = vec![];
+                    for path in nar.ref_paths() {
+                        let path = path.map_err(Error::ParseError)?;
+                        let hash = path.hash();
+                        let ref_id = match ids.get(&hash) {
+                            Some(&id) => Some(id),
+                            None => match stmt_select_id
+                                .query_row(params![hash.as_str()], |row| row.get(0))
+                            {
+                                Ok(id) => Some(id),
+                                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                                Err(err) => return Err(err.into()),
+                            },
+                        };
+                        match ref_id {
+                            Some(ref_id) => {
+                                stmt_insert_ref.execute(params![nar_id, ref_id])?;
+                            }
+                            None => missing.push(path.base_name().to_owned()),
+                        }
+                    }
+                    if !missing.is_empty() {
+                        stmt_set_missing.execute(params![missing.join(" "), nar_id])?;
                     }
-                    Ok(_) => unreachable!(),
-                    Err(err) => return Err(err.into()),
                 }
             }
-        }
 
-        txn.commit()?;
-        Ok(())
+            txn.commit()?;
+            Ok(summary)
+        })
     }
 
-    pub(crate) fn select_nar_id_by_hash(&self, hash: &StorePathHash) -> Result<Option<i64>> {
+    /// The cache the NAR was fetched from, or `None` if it's the one of its root.
+    pub(crate) fn select_nar_cache_url(&self, id: i64) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                r"SELECT cache_url FROM nar WHERE id = ?",
+                params![id],
+                |row| row.get(0),
+            )
+            .map_err(Into::into)
+    }
+
+    pub fn select_nar_id_by_hash(&self, hash: &StorePathHash) -> Result<Option<i64>> {
         match self.conn.query_row_and_then(
             r"SELECT id FROM nar WHERE hash = ? AND status != 'T'",
             params![hash.as_str()],
@@ -279,65 +854,392 @@ impl Database {
         }
     }
 
+    /// The pending NAR with the given `url`, if any.
+    pub(crate) fn select_pending_nar_by_url(&self, url: &str) -> Result<Option<(i64, Nar)>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            r"
+            SELECT {}
+                FROM nar
+                WHERE status = 'P' AND url = ?
+                ORDER BY id DESC
+                LIMIT 1
+            ",
+            Self::NAR_COLUMNS,
+        ))?;
+        let mut rows = stmt.query_and_then(params![url], Self::nar_from_row)?;
+        rows.next().transpose()
+    }
+
+    /// Columns for `nar_from_row`, with the table aliased as `nar`.
+    const NAR_COLUMNS: &str = r"
+        nar.id, nar.store_root, nar.hash, nar.name,
+        nar.url, nar.compression,
+        nar.file_hash, nar.file_size, nar.nar_hash, nar.nar_size,
+        nar.deriver, nar.sig, nar.ca, nar.missing_refs,
+        (SELECT COALESCE(GROUP_CONCAT(ref.hash || '-' || ref.name, ' '), '')
+            FROM nar_ref
+            JOIN nar AS ref ON ref.id = ref_id
+            WHERE nar_id = nar.id
+        ) AS refs
+    ";
+
+    fn store_path_from_row(row: &rusqlite::Row) -> Result<StorePath> {
+        let store_root = row.get::<_, String>("store_root")?;
+        StorePath::try_from_with_root(
+            format!(
+                "{}/{}-{}",
+                store_root,
+                row.get::<_, String>("hash")?,
+                row.get::<_, String>("name")?,
+            ),
+            &store_root,
+        )
+        .map_err(Error::ParseError)
+    }
+
+    fn nar_from_row(row: &rusqlite::Row) -> Result<(i64, Nar)> {
+        Ok((
+            row.get("id")?,
+            Nar {
+                store_path: Self::store_path_from_row(row)?,
+                meta: NarMeta {
+                    url: row.get("url")?,
+                    compression: row.get("compression")?,
+                    file_hash: row.get("file_hash")?,
+                    file_size: row.get::<_, Option<i64>>("file_size")?.map(|s| s as u64),
+                    nar_hash: row.get("nar_hash")?,
+                    nar_size: row.get::<_, i64>("nar_size")? as u64,
+                    deriver: row.get("deriver")?,
+                    sig: row
+                        .get::<_, Option<String>>("sig")?
+                        .map_or_else(Vec::new, |s| s.lines().map(|s| s.to_owned()).collect()),
+                    ca: row.get("ca")?,
+                },
+                references: {
+                    let refs: String = row.get("refs")?;
+                    match row.get::<_, Option<String>>("missing_refs")? {
+                        Some(missing) if refs.is_empty() => missing,
+                        Some(missing) => format!("{} {}", refs, missing),
+                        None => refs,
+                    }
+                },
+            },
+        ))
+    }
+
     pub(crate) fn select_all_nar(
         &self,
         status: NarStatus,
         mut f: impl FnMut(i64, Nar),
     ) -> Result<()> {
-        let mut stmt = self.conn.prepare_cached(
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM nar WHERE status = ? ORDER BY id",
+            Self::NAR_COLUMNS,
+        ))?;
+        for row in stmt.query_and_then(params![status], Self::nar_from_row)? {
+            let (id, nar) = row?;
+            f(id, nar);
+        }
+        Ok(())
+    }
+
+    /// NARs inserted or changing status after change number `after_seq`, in
+    /// order of it. `f` gets the change number, row id, status and the NAR.
+    pub(crate) fn select_nar_changed_after(
+        &self,
+        after_seq: i64,
+        mut f: impl FnMut(i64, i64, NarStatus, Nar),
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            r"
+            SELECT {}, nar.status, nar.status_seq
+                FROM nar
+                WHERE status_seq > ?
+                ORDER BY status_seq
+            ",
+            Self::NAR_COLUMNS,
+        ))?;
+        let rows = stmt.query_and_then(params![after_seq], |row| -> Result<_> {
+            let (id, nar) = Self::nar_from_row(row)?;
+            Ok((row.get("status_seq")?, id, row.get("status")?, nar))
+        })?;
+        for row in rows {
+            let (seq, id, status, nar) = row?;
+            f(seq, id, status, nar);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn select_nar_by_id(&self, id: i64) -> Result<Option<Nar>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM nar WHERE id = ?",
+            Self::NAR_COLUMNS,
+        ))?;
+        let mut rows = stmt.query_and_then(params![id], Self::nar_from_row)?;
+        rows.next().transpose().map(|row| row.map(|(_, nar)| nar))
+    }
+
+    /// Get a non-trashed NAR with its references by hash.
+    pub fn select_nar_by_hash(&self, hash: &StorePathHash) -> Result<Option<Nar>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM nar WHERE hash = ? AND status != 'T'",
+            Self::NAR_COLUMNS,
+        ))?;
+        let mut rows = stmt.query_and_then(params![hash.as_str()], Self::nar_from_row)?;
+        rows.next().transpose().map(|row| row.map(|(_, nar)| nar))
+    }
+
+    /// NARs with `status` in the closure of a root.
+    pub(crate) fn select_root_closure(
+        &self,
+        root_id: i64,
+        status: NarStatus,
+        mut f: impl FnMut(i64, Nar),
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(&format!(
             r"
-            SELECT  id, store_root, hash, name,
-                    url, compression,
-                    file_hash, file_size, nar_hash, nar_size,
-                    deriver, sig, ca,
-                    (SELECT COALESCE(GROUP_CONCAT(ref.hash || '-' || ref.name, ' '), '')
-                        FROM nar_ref
-                        JOIN nar AS ref ON ref.id = ref_id
-                        WHERE nar_id = nar.id
-                    ) AS refs
+            WITH RECURSIVE closure (id) AS (
+                SELECT nar_id FROM root_nar WHERE root_id = :root_id
+                UNION
+                SELECT ref_id FROM nar_ref JOIN closure ON nar_id = closure.id
+            )
+            SELECT {}
                 FROM nar
-                WHERE status = ?
+                JOIN closure USING (id)
+                WHERE status = :status
             ",
+            Self::NAR_COLUMNS,
+        ))?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":root_id": root_id,
+                ":status": status,
+            },
+            Self::nar_from_row,
         )?;
+        for row in rows {
+            let (id, nar) = row?;
+            f(id, nar);
+        }
+        Ok(())
+    }
 
-        stmt.query_and_then(params![status], |row| -> Result<_> {
-            Ok((
-                row.get("id")?,
-                Nar {
-                    store_path: format!(
-                        "{}/{}-{}",
-                        row.get::<_, String>("store_root")?,
-                        row.get::<_, String>("hash")?,
-                        row.get::<_, String>("name")?,
-                    )
-                    .try_into()
-                    .map_err(Error::ParseError)?,
-                    meta: NarMeta {
-                        url: row.get("url")?,
-                        compression: row.get("compression")?,
-                        file_hash: row.get("file_hash")?,
-                        file_size: row.get::<_, Option<i64>>("file_size")?.map(|s| s as u64),
-                        nar_hash: row.get("nar_hash")?,
-                        nar_size: row.get::<_, i64>("nar_size")? as u64,
-                        deriver: row.get("deriver")?,
-                        sig: row.get("sig")?,
-                        ca: row.get("ca")?,
-                    },
-                    references: row.get("refs")?,
-                },
-            ))
-        })?
-        .map(|r| r.map(|(id, nar)| f(id, nar)))
-        .collect::<Result<()>>()?;
+    /// References `(nar_id, ref_id)` between NARs in the closure of a root,
+    /// excluding self references.
+    pub(crate) fn select_root_closure_refs(
+        &self,
+        root_id: i64,
+        mut f: impl FnMut(i64, i64),
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            r"
+            WITH RECURSIVE closure (id) AS (
+                SELECT nar_id FROM root_nar WHERE root_id = ?
+                UNION
+                SELECT ref_id FROM nar_ref JOIN closure ON nar_id = closure.id
+            )
+            SELECT nar_id, ref_id
+                FROM nar_ref
+                JOIN closure ON nar_id = closure.id
+                WHERE nar_id != ref_id
+            ",
+        )?;
+        let rows = stmt.query_and_then(params![root_id], |row| -> Result<_> {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        for row in rows {
+            let (nar_id, ref_id) = row?;
+            f(nar_id, ref_id);
+        }
+        Ok(())
+    }
+
+    /// All references `(nar_id, ref_id)`, excluding self references.
+    pub(crate) fn select_nar_refs(&self, mut f: impl FnMut(i64, i64)) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT nar_id, ref_id FROM nar_ref WHERE nar_id != ref_id")?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        for row in rows {
+            let (nar_id, ref_id) = row?;
+            f(nar_id, ref_id);
+        }
+        Ok(())
+    }
 
+    pub(crate) fn update_nar_status(&mut self, id: i64, status: NarStatus) -> Result<()> {
+        let changed = self.with_busy_retry(|conn| {
+            Ok(conn.execute(
+                "UPDATE nar SET status = ? WHERE id = ?",
+                params![status, id],
+            )?)
+        })?;
+        if changed == 0 {
+            return Err(Error::NotFound);
+        }
         Ok(())
     }
+
+    /// Mark a NAR as trashed, or fail with `Error::NotFound` if there is no
+    /// such NAR or it's already trashed. Its file is left for the caller.
+    /// It's also unlinked from the upload root, so that it can be collected.
+    pub fn trash_nar(&mut self, hash: &StorePathHash) -> Result<()> {
+        let changed = self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let changed = txn.execute(
+                "UPDATE nar SET status = 'T' WHERE hash = ? AND status != 'T'",
+                params![hash.as_str()],
+            )?;
+            txn.execute(
+                r"
+                DELETE FROM root_nar
+                    WHERE nar_id = (SELECT id FROM nar WHERE hash = ?)
+                    AND root_id IN (SELECT id FROM root WHERE channel_url = ?)
+                ",
+                params![hash.as_str(), Self::UPLOAD_ROOT_URL],
+            )?;
+            txn.commit()?;
+            Ok(changed)
+        })?;
+        if changed == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Link a NAR to the upload root, creating the root if missing, so that
+    /// it's kept by garbage collection.
+    pub(crate) fn link_uploaded_nar(&mut self, nar_id: i64) -> Result<()> {
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let root_id = match txn.query_row(
+                "SELECT id FROM root WHERE channel_url = ? ORDER BY id LIMIT 1",
+                params![Self::UPLOAD_ROOT_URL],
+                |row| row.get(0),
+            ) {
+                Ok(id) => id,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    txn.execute(
+                        "INSERT INTO root (channel_url, status) VALUES (?, ?)",
+                        params![Self::UPLOAD_ROOT_URL, RootStatus::Available],
+                    )?;
+                    txn.last_insert_rowid()
+                }
+                Err(err) => return Err(err.into()),
+            };
+            txn.execute(
+                r"
+                INSERT INTO root_nar (root_id, nar_id)
+                    VALUES (?, ?)
+                    ON CONFLICT DO NOTHING
+                ",
+                params![root_id, nar_id],
+            )?;
+            txn.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Mark NARs not reachable from any root as trashed, and return all
+    /// unreachable trashed ones, including those trashed before.
+    /// NARs saved by `fetch_meta_rec` are unreachable until their root is
+    /// inserted, so it requires the single writer to exclude a concurrent one,
+    /// or fails with `Error::NotExclusive`.
+    pub fn gc_unreferenced(&mut self) -> Result<Vec<StorePathHash>> {
+        if !self.exclusive {
+            return Err(Error::NotExclusive);
+        }
+        const CLOSURE: &str = r"
+            WITH RECURSIVE closure (id) AS (
+                SELECT nar_id FROM root_nar
+                UNION
+                SELECT ref_id FROM nar_ref JOIN closure ON nar_id = closure.id
+            )
+        ";
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute(
+                &format!(
+                    "{} UPDATE nar SET status = 'T' WHERE status != 'T' AND id NOT IN closure",
+                    CLOSURE,
+                ),
+                NO_PARAMS,
+            )?;
+            let hashes = {
+                let mut stmt = txn.prepare_cached(&format!(
+                    r"
+                    {}
+                    SELECT store_root, hash, name
+                        FROM nar
+                        WHERE status = 'T' AND id NOT IN closure
+                        ORDER BY id
+                    ",
+                    CLOSURE,
+                ))?;
+                let rows = stmt
+                    .query_and_then(NO_PARAMS, |row| Ok(Self::store_path_from_row(row)?.hash()))?;
+                rows.collect::<Result<Vec<_>>>()?
+            };
+            txn.commit()?;
+            Ok(hashes)
+        })
+    }
+
+    /// Delete trashed NARs of `hashes` with their references, unless they are
+    /// reachable from a root again. Return the number of deleted ones.
+    pub(crate) fn delete_trashed_nars(&mut self, hashes: &[StorePathHash]) -> Result<usize> {
+        self.with_busy_retry(|conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute(
+                r"
+                CREATE TEMP TABLE IF NOT EXISTS gc_nar (id INTEGER NOT NULL PRIMARY KEY)
+                ",
+                NO_PARAMS,
+            )?;
+            txn.execute("DELETE FROM temp.gc_nar", NO_PARAMS)?;
+            {
+                let mut stmt = txn.prepare_cached(
+                    r"
+                    INSERT OR IGNORE INTO temp.gc_nar (id)
+                    SELECT id FROM nar WHERE hash = ? AND status = 'T'
+                    ",
+                )?;
+                for hash in hashes {
+                    stmt.execute(params![hash.as_str()])?;
+                }
+            }
+            // Keep ones which become reachable again.
+            txn.execute(
+                r"
+                WITH RECURSIVE closure (id) AS (
+                    SELECT nar_id FROM root_nar
+                    UNION
+                    SELECT ref_id FROM nar_ref JOIN closure ON nar_id = closure.id
+                )
+                DELETE FROM temp.gc_nar WHERE id IN closure
+                ",
+                NO_PARAMS,
+            )?;
+            txn.execute(
+                "DELETE FROM nar_ref WHERE nar_id IN temp.gc_nar OR ref_id IN temp.gc_nar",
+                NO_PARAMS,
+            )?;
+            let deleted = txn.execute("DELETE FROM nar WHERE id IN temp.gc_nar", NO_PARAMS)?;
+            txn.execute("DELETE FROM temp.gc_nar", NO_PARAMS)?;
+            txn.commit()?;
+            Ok(deleted)
+        })
+    }
 }
 
 // FIXME: More test
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tests::test_nar;
+    use std::{convert::TryFrom, iter};
     use tempfile;
 
     #[test]
@@ -350,4 +1252,832 @@ mod tests {
         // Reopen
         let _ = Database::open(file.path()).unwrap();
     }
+
+    #[test]
+    fn test_migrate() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        {
+            // A database of version 1.
+            let conn = Connection::open(file.path()).unwrap();
+            conn.execute_batch(Database::INIT_SQL).unwrap();
+        }
+        let mut db = Database::open(file.path()).unwrap();
+        assert_eq!(
+            db.query_version().unwrap(),
+            (Database::APPLICATION_ID, Database::USER_VERSION),
+        );
+
+        let migrations = &[
+            (Database::USER_VERSION + 1, "CREATE TABLE bar (id INTEGER)"),
+            (Database::USER_VERSION, "CREATE TABLE foo (id INTEGER)"),
+        ];
+        db.migrate(migrations, Database::USER_VERSION + 2).unwrap();
+        assert_eq!(db.query_version().unwrap().1, Database::USER_VERSION + 2);
+        db.conn
+            .execute("INSERT INTO foo VALUES (1)", NO_PARAMS)
+            .unwrap();
+        db.conn
+            .execute("INSERT INTO bar VALUES (1)", NO_PARAMS)
+            .unwrap();
+        // Newer than supported.
+        drop(db);
+        assert!(matches!(
+            Database::open(file.path()),
+            Err(Error::InvalidDatabase(_)),
+        ));
+
+        // Missing steps fail without partial changes.
+        let mut db = Database::open_in_memory().unwrap();
+        let migrations = &[(Database::USER_VERSION, "CREATE TABLE foo (id INTEGER)")];
+        assert!(matches!(
+            db.migrate(migrations, Database::USER_VERSION + 2),
+            Err(Error::InvalidDatabase(_)),
+        ));
+        assert_eq!(db.query_version().unwrap().1, Database::USER_VERSION);
+        assert!(db
+            .conn
+            .execute("INSERT INTO foo VALUES (1)", NO_PARAMS)
+            .is_err());
+    }
+
+    #[test]
+    fn test_query_plan_indexes() {
+        let db = Database::open_in_memory().unwrap();
+        let plan = |sql: &str| {
+            let mut stmt = db
+                .conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+                .unwrap();
+            stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(3))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap()
+                .join("\n")
+        };
+
+        let by_status = plan("SELECT id FROM nar WHERE status = 'A' ORDER BY id");
+        assert!(
+            by_status.contains("USING COVERING INDEX nar_status_idx")
+                || by_status.contains("USING INDEX nar_status_idx"),
+            "{}",
+            by_status,
+        );
+        assert!(!by_status.contains("TEMP B-TREE"), "{}", by_status);
+
+        let by_hash = plan("SELECT id FROM nar WHERE hash = 'x' AND status != 'T'");
+        assert!(
+            by_hash.contains("USING INDEX sqlite_autoindex_nar_1 (hash=?)"),
+            "{}",
+            by_hash
+        );
+
+        let by_seq = plan("SELECT id FROM nar WHERE status_seq > 0 ORDER BY status_seq");
+        assert!(
+            by_seq.contains("INDEX nar_status_seq_idx (status_seq>?)"),
+            "{}",
+            by_seq
+        );
+        assert!(!by_seq.contains("TEMP B-TREE"), "{}", by_seq);
+
+        let by_revision = plan("SELECT id FROM root WHERE git_revision = 'x'");
+        assert!(
+            by_revision.contains("INDEX root_git_revision_idx (git_revision=?)"),
+            "{}",
+            by_revision
+        );
+    }
+
+    #[test]
+    fn test_root_closure_size() {
+        let mut db = Database::open_in_memory().unwrap();
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 100, "");
+        let b = test_nar(
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+            20,
+            "cccccccccccccccccccccccccccccccc-c",
+        );
+        // Self reference and diamond dependency.
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            3,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b cccccccccccccccccccccccccccccccc-c",
+        );
+        let d = test_nar("/nix/store/dddddddddddddddddddddddddddddddd-d", 4000, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c, &b, &a, &d])
+            .unwrap();
+
+        let root_a = db
+            .insert_root(&Root::default(), vec![a.store_path.hash()])
+            .unwrap();
+        let root_bd = db
+            .insert_root(
+                &Root::default(),
+                vec![b.store_path.hash(), d.store_path.hash()],
+            )
+            .unwrap();
+        let root_empty = db.insert_root(&Root::default(), vec![]).unwrap();
+
+        assert_eq!(db.root_closure_size(root_a).unwrap(), 3 + 20 + 100);
+        assert_eq!(db.root_closure_size(root_bd).unwrap(), 20 + 100 + 4000);
+        assert_eq!(db.root_closure_size(root_empty).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_insert_nars_summary() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+
+        assert_eq!(
+            db.insert_or_ignore_nars(NarStatus::Pending, vec![&a, &b])
+                .unwrap(),
+            InsertSummary {
+                inserted: 2,
+                skipped: 0,
+                inserted_file_size: 2,
+            },
+        );
+        assert_eq!(
+            db.insert_or_ignore_nars(NarStatus::Pending, vec![&b, &c, &a])
+                .unwrap(),
+            InsertSummary {
+                inserted: 1,
+                skipped: 2,
+                inserted_file_size: 1,
+            },
+        );
+    }
+
+    #[test]
+    fn test_insert_nars_refs() {
+        let mut db = Database::open_in_memory().unwrap();
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c])
+            .unwrap();
+        // To the same batch, an earlier batch, itself, and a missing one.
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            1,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b \
+             cccccccccccccccccccccccccccccccc-c dddddddddddddddddddddddddddddddd-d",
+        );
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&b, &a])
+            .unwrap();
+
+        let id = |nar: &Nar| {
+            db.select_nar_id_by_hash(&nar.store_path.hash())
+                .unwrap()
+                .unwrap()
+        };
+        let (a_id, b_id, c_id) = (id(&a), id(&b), id(&c));
+        let mut refs = vec![];
+        db.select_nar_refs(|nar_id, ref_id| refs.push((nar_id, ref_id)))
+            .unwrap();
+        refs.sort();
+        assert_eq!(refs, vec![(a_id, c_id), (a_id, b_id)]);
+        // The missing one is still listed.
+        let mut listed = db
+            .select_nar_by_hash(&a.store_path.hash())
+            .unwrap()
+            .unwrap()
+            .references
+            .split(' ')
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed.join(" "), a.references);
+        let self_refs: i64 = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM nar_ref WHERE nar_id = ?1 AND ref_id = ?1",
+                params![a_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(self_refs, 1);
+    }
+
+    #[test]
+    fn test_insert_nar_with_ref_ids() {
+        let mut db = Database::open_in_memory().unwrap();
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let insert = |db: &mut Database, nar: &Nar, self_ref, ref_ids: &[i64]| {
+            db.insert_or_ignore_nar(
+                NarStatus::Pending,
+                &nar.store_path,
+                &nar.meta,
+                self_ref,
+                ref_ids,
+            )
+            .unwrap()
+        };
+
+        let b_id = insert(&mut db, &b, false, &[]).unwrap();
+        let c_id = insert(&mut db, &c, false, &[]).unwrap();
+        let a_id = insert(&mut db, &a, true, &[b_id, c_id]).unwrap();
+        // Already present.
+        assert_eq!(insert(&mut db, &a, false, &[]), None);
+
+        let mut stmt = db
+            .conn
+            .prepare("SELECT nar_id, ref_id FROM nar_ref ORDER BY nar_id, ref_id")
+            .unwrap();
+        let refs = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i64, i64)>>>()
+            .unwrap();
+        assert_eq!(refs, vec![(a_id, b_id), (a_id, c_id), (a_id, a_id)]);
+
+        let mut got = Vec::new();
+        db.select_all_nar(NarStatus::Pending, |id, nar| {
+            if id == a_id {
+                got.push(nar.references);
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            got,
+            vec!["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b cccccccccccccccccccccccccccccccc-c aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a"],
+        );
+    }
+
+    #[test]
+    fn test_open_exclusive() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let db = Database::open_exclusive(file.path()).unwrap();
+        match Database::open_exclusive(file.path()) {
+            Err(Error::Locked(_)) => {}
+            ret => panic!("Expect locked, got {:?}", ret),
+        }
+        // Readers are not affected.
+        let _ = Database::open(file.path()).unwrap();
+
+        drop(db);
+        let _ = Database::open_exclusive(file.path()).unwrap();
+    }
+
+    #[test]
+    fn test_gc_exclusive() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Database::open(file.path()).unwrap();
+        match db.gc_unreferenced() {
+            Err(Error::NotExclusive) => {}
+            ret => panic!("Expect not exclusive, got {:?}", ret),
+        }
+        let mut db = Database::open_exclusive(file.path()).unwrap();
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_insert_busy_retry() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Database::open(file.path()).unwrap();
+        // Fail immediately on contention, to exercise the retry loop.
+        db.conn.busy_timeout(Duration::from_millis(0)).unwrap();
+        let hold = || {
+            let other = Connection::open(file.path()).unwrap();
+            other.execute_batch("BEGIN IMMEDIATE").unwrap();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                other.execute_batch("COMMIT").unwrap();
+            })
+        };
+
+        let holder = hold();
+        let nar = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let summary = db
+            .insert_or_ignore_nars(NarStatus::Pending, iter::once(&nar))
+            .unwrap();
+        assert_eq!(summary.inserted, 1);
+        holder.join().unwrap();
+
+        let holder = hold();
+        let nar = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let id = db
+            .insert_or_ignore_nar(NarStatus::Pending, &nar.store_path, &nar.meta, false, &[])
+            .unwrap();
+        assert!(id.is_some());
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_insert_busy_retry_async() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Database::open(file.path()).unwrap();
+
+        let other = Connection::open(file.path()).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            other.execute_batch("COMMIT").unwrap();
+        });
+
+        let nar = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let mut attempts = 0;
+        let summary = futures::executor::block_on(db.with_busy_retry_async(|db| {
+            attempts += 1;
+            db.insert_or_ignore_nars(NarStatus::Pending, iter::once(&nar))
+        }))
+        .unwrap();
+        assert_eq!(summary.inserted, 1);
+        // Not waiting in SQLite or `with_busy_retry`.
+        assert!(attempts > 1);
+        assert!(db.busy_wait);
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_select_root_paths() {
+        let mut db = Database::open_in_memory().unwrap();
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            1,
+            "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+        );
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&b, &a, &c])
+            .unwrap();
+        let root_id = db
+            .insert_root(
+                &Root::default(),
+                vec![a.store_path.hash(), c.store_path.hash()],
+            )
+            .unwrap();
+
+        // References are not followed.
+        assert_eq!(
+            db.select_root_paths(root_id).unwrap(),
+            vec![a.store_path, c.store_path],
+        );
+        assert_eq!(db.select_root_paths(root_id + 1).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_custom_store_root() {
+        let mut db = Database::open_in_memory().unwrap();
+        let mut nar = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        nar.store_path = StorePath::try_from_with_root(
+            "/data/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            "/data/nix/store",
+        )
+        .unwrap();
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&nar])
+            .unwrap();
+
+        let mut got = vec![];
+        db.select_all_nar(NarStatus::Pending, |_, nar| got.push(nar))
+            .unwrap();
+        assert_eq!(got, vec![nar]);
+        assert_eq!(got[0].store_path.root(), "/data/nix/store");
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let mut db = Database::open_in_memory().unwrap();
+        let xz = |path, size| {
+            let mut nar = test_nar(path, size, "");
+            nar.meta.compression = Some("xz".to_owned());
+            nar
+        };
+        db.insert_or_ignore_nars(
+            NarStatus::Available,
+            vec![
+                xz("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 10),
+                xz("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 20),
+                test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 300, ""),
+            ],
+        )
+        .unwrap();
+        // Not counted.
+        db.insert_or_ignore_nars(
+            NarStatus::Pending,
+            vec![xz("/nix/store/dddddddddddddddddddddddddddddddd-d", 4000)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.compression_stats().unwrap(),
+            vec![(None, 1, 300), (Some("xz".to_owned()), 2, 30)],
+        );
+    }
+
+    #[test]
+    fn test_select_nar_by_hash() {
+        let mut db = Database::open_in_memory().unwrap();
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            2,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+        );
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 3, "");
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&b, &a])
+            .unwrap();
+        db.insert_or_ignore_nars(NarStatus::Trashed, vec![&c])
+            .unwrap();
+
+        // References are in no particular order.
+        let mut got = db
+            .select_nar_by_hash(&a.store_path.hash())
+            .unwrap()
+            .unwrap();
+        let mut refs: Vec<_> = got.references.split(' ').collect();
+        refs.sort();
+        assert_eq!(refs.join(" "), a.references);
+        got.references = a.references.clone();
+        assert_eq!(got, a);
+        assert_eq!(
+            db.select_nar_by_hash(&b.store_path.hash()).unwrap(),
+            Some(b)
+        );
+        assert_eq!(db.select_nar_by_hash(&c.store_path.hash()).unwrap(), None);
+        let d = StorePath::try_from("/nix/store/dddddddddddddddddddddddddddddddd-d").unwrap();
+        assert_eq!(db.select_nar_by_hash(&d.hash()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_storage_stats() {
+        let mut db = Database::open_in_memory().unwrap();
+        assert_eq!(db.storage_stats().unwrap(), StorageStats::default());
+
+        let mut a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 10, "");
+        a.meta.nar_size = 100;
+        let mut b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 0, "");
+        b.meta.file_size = None;
+        b.meta.nar_size = 200;
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&a, &b])
+            .unwrap();
+        // Not counted.
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1000, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c])
+            .unwrap();
+
+        assert_eq!(
+            db.storage_stats().unwrap(),
+            StorageStats {
+                nar_count: 2,
+                file_size: 210,
+                nar_size: 300,
+            },
+        );
+    }
+
+    #[test]
+    fn test_count_nars() {
+        let mut db = Database::open_in_memory().unwrap();
+        let nar = |c: char| {
+            test_nar(
+                &format!("/nix/store/{}-{}", c.to_string().repeat(32), c),
+                1,
+                "",
+            )
+        };
+        db.insert_or_ignore_nars(NarStatus::Available, vec![nar('a'), nar('b'), nar('c')])
+            .unwrap();
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![nar('d')])
+            .unwrap();
+
+        assert_eq!(db.count_nars(NarStatus::Available).unwrap(), 3);
+        assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 1);
+        assert_eq!(db.count_nars(NarStatus::Trashed).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_trash_nar() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Available, vec![&a, &b])
+            .unwrap();
+
+        db.trash_nar(&a.store_path.hash()).unwrap();
+        assert_eq!(db.count_nars(NarStatus::Available).unwrap(), 1);
+        assert_eq!(db.count_nars(NarStatus::Trashed).unwrap(), 1);
+        assert_eq!(db.select_nar_by_hash(&a.store_path.hash()).unwrap(), None);
+
+        // Already trashed, or missing.
+        assert!(matches!(
+            db.trash_nar(&a.store_path.hash()),
+            Err(Error::NotFound),
+        ));
+        let c = StorePath::try_from("/nix/store/cccccccccccccccccccccccccccccccc-c").unwrap();
+        assert!(matches!(db.trash_nar(&c.hash()), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_select_nar_changed_after() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        let changes = |db: &Database, after: i64| {
+            let mut ret = vec![];
+            db.select_nar_changed_after(after, |seq, _, status, nar| {
+                ret.push((seq, status, nar.store_path.name().to_owned()));
+            })
+            .unwrap();
+            ret
+        };
+
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&a, &b])
+            .unwrap();
+        let all = changes(&db, i64::MIN);
+        assert_eq!(all.len(), 2);
+        let last = all[1].0;
+        assert_eq!(changes(&db, last), vec![]);
+
+        // Status changes in place are numbered after all previous ones.
+        let id_a = db
+            .select_nar_id_by_hash(&a.store_path.hash())
+            .unwrap()
+            .unwrap();
+        db.update_nar_status(id_a, NarStatus::Available).unwrap();
+        db.update_nar_status(id_a, NarStatus::Available).unwrap();
+        assert_eq!(
+            changes(&db, last),
+            vec![(last + 1, NarStatus::Available, "a".to_owned())],
+        );
+
+        // Numbers are never reused after deletion.
+        db.trash_nar(&a.store_path.hash()).unwrap();
+        db.delete_trashed_nars(&[a.store_path.hash()]).unwrap();
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&a])
+            .unwrap();
+        assert_eq!(
+            changes(&db, last + 2),
+            vec![(last + 3, NarStatus::Pending, "a".to_owned())],
+        );
+    }
+
+    #[test]
+    fn test_upload_root() {
+        let mut db = Database::open_in_memory().unwrap();
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&a, &b])
+            .unwrap();
+        let id_a = db.select_nar_id_by_hash(&a.store_path.hash()).unwrap();
+        let id_b = db.select_nar_id_by_hash(&b.store_path.hash()).unwrap();
+        db.link_uploaded_nar(id_a.unwrap()).unwrap();
+        db.link_uploaded_nar(id_a.unwrap()).unwrap();
+        db.link_uploaded_nar(id_b.unwrap()).unwrap();
+        assert_eq!(db.select_all_roots().unwrap().len(), 1);
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![]);
+
+        assert_eq!(
+            db.select_pending_nar_by_url("some/url").unwrap(),
+            Some((id_b.unwrap(), b.clone())),
+        );
+        assert_eq!(db.select_pending_nar_by_url("other/url").unwrap(), None);
+
+        // Trashing unlinks it.
+        db.trash_nar(&a.store_path.hash()).unwrap();
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![a.store_path.hash()]);
+    }
+
+    #[test]
+    fn test_insert_root_duplicated_paths() {
+        let mut db = Database::open_in_memory().unwrap();
+        let nar = Nar {
+            store_path: StorePath::try_from(
+                "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10",
+            )
+            .unwrap(),
+            meta: NarMeta {
+                url: "some/url".to_owned(),
+                compression: None,
+                file_hash: None,
+                file_size: None,
+                nar_hash: "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+                    .parse()
+                    .unwrap(),
+                nar_size: 1,
+                deriver: None,
+                sig: vec![],
+                ca: None,
+            },
+            references: String::new(),
+        };
+        db.insert_or_ignore_nars(NarStatus::Pending, iter::once(&nar))
+            .unwrap();
+
+        let hash = nar.store_path.hash();
+        let root_id = db.insert_root(&Root::default(), vec![hash, hash]).unwrap();
+        let links: i64 = db
+            .conn
+            .query_row(
+                r"SELECT COUNT(*) FROM root_nar WHERE root_id = ?",
+                params![root_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(links, 1);
+    }
+
+    #[test]
+    fn test_select_roots() {
+        let mut db = Database::open_in_memory().unwrap();
+        assert_eq!(db.select_all_roots().unwrap(), vec![]);
+        assert!(matches!(db.select_root(1), Err(Error::NotFound)));
+
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, "");
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&a, &b])
+            .unwrap();
+        let root = Root {
+            git_revision: Some("0123456".to_owned()),
+            fetch_time: Some("2019-12-01T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        let id1 = db
+            .insert_root(&root, vec![a.store_path.hash(), b.store_path.hash()])
+            .unwrap();
+        let id2 = db.insert_root(&Root::default(), vec![]).unwrap();
+
+        let record1 = db.select_root(id1).unwrap();
+        assert_eq!(
+            record1,
+            RootRecord {
+                id: id1,
+                root,
+                nar_count: 2,
+            },
+        );
+        let roots = db.select_all_roots().unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0], record1);
+        assert_eq!((roots[1].id, roots[1].nar_count), (id2, 0));
+        assert!(matches!(db.select_root(id2 + 1), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_set_root_status() {
+        let mut db = Database::open_in_memory().unwrap();
+        let id1 = db.insert_root(&Root::default(), vec![]).unwrap();
+        let id2 = db.insert_root(&Root::default(), vec![]).unwrap();
+        let ids = |db: &Database, status: RootStatus| {
+            db.select_roots_by_status(status)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&db, RootStatus::Pending), vec![id1, id2]);
+        assert!(ids(&db, RootStatus::Downloading).is_empty());
+
+        db.set_root_status(id2, RootStatus::Downloading).unwrap();
+        assert_eq!(ids(&db, RootStatus::Pending), vec![id1]);
+        assert_eq!(ids(&db, RootStatus::Downloading), vec![id2]);
+
+        db.set_root_status(id2, RootStatus::Available).unwrap();
+        assert!(ids(&db, RootStatus::Downloading).is_empty());
+        assert_eq!(ids(&db, RootStatus::Available), vec![id2]);
+        assert_eq!(
+            db.select_root(id2).unwrap().root.status,
+            RootStatus::Available,
+        );
+
+        assert!(matches!(
+            db.set_root_status(id2 + 1, RootStatus::Available),
+            Err(Error::NotFound),
+        ));
+    }
+
+    #[test]
+    fn test_delete_root() {
+        let mut db = Database::open_in_memory().unwrap();
+        let glibc = test_nar("/nix/store/gggggggggggggggggggggggggggggggg-glibc", 1, "");
+        let refs = "gggggggggggggggggggggggggggggggg-glibc";
+        let a = test_nar("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a", 1, refs);
+        let b = test_nar("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b", 1, refs);
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&glibc, &a, &b])
+            .unwrap();
+        let root1 = db
+            .insert_root(
+                &Root::default(),
+                vec![a.store_path.hash(), glibc.store_path.hash()],
+            )
+            .unwrap();
+        let root2 = db
+            .insert_root(&Root::default(), vec![b.store_path.hash()])
+            .unwrap();
+
+        assert_eq!(db.delete_root(root1).unwrap(), 2);
+        assert!(matches!(db.select_root(root1), Err(Error::NotFound)));
+        assert!(matches!(db.delete_root(root1), Err(Error::NotFound)));
+        assert_eq!(db.select_all_roots().unwrap().len(), 1);
+
+        // `glibc` is still referenced by `b`.
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![a.store_path.hash()]);
+        assert_eq!(db.root_closure_size(root2).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_gc_unreferenced() {
+        let mut db = Database::open_in_memory().unwrap();
+        // a -> [a, c], b -> [c]
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            1,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a cccccccccccccccccccccccccccccccc-c",
+        );
+        let b = test_nar(
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+            1,
+            "cccccccccccccccccccccccccccccccc-c",
+        );
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c, &a, &b])
+            .unwrap();
+        let root_a = db
+            .insert_root(&Root::default(), vec![a.store_path.hash()])
+            .unwrap();
+        let root_b = db
+            .insert_root(&Root::default(), vec![b.store_path.hash()])
+            .unwrap();
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![]);
+
+        // `c` is still used by `b`. The self reference of `a` doesn't keep it.
+        assert_eq!(db.delete_root(root_a).unwrap(), 1);
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![a.store_path.hash()]);
+        assert_eq!(
+            db.select_nar_id_by_hash(&a.store_path.hash()).unwrap(),
+            None
+        );
+        assert_eq!(db.delete_trashed_nars(&[a.store_path.hash()]).unwrap(), 1);
+        assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 2);
+
+        assert_eq!(db.delete_root(root_b).unwrap(), 1);
+        let trashed = db.gc_unreferenced().unwrap();
+        assert_eq!(trashed, vec![c.store_path.hash(), b.store_path.hash()]);
+
+        // Reachable again before purging.
+        db.insert_root(&Root::default(), vec![c.store_path.hash()])
+            .unwrap();
+        assert_eq!(db.delete_trashed_nars(&trashed).unwrap(), 1);
+        assert_eq!(db.count_nars(NarStatus::Trashed).unwrap(), 1);
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![]);
+        let refs: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM nar_ref", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(refs, 0);
+    }
+
+    #[test]
+    fn test_select_root_by_revision() {
+        let mut db = Database::open_in_memory().unwrap();
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let root = Root {
+            channel_url: Some("https://nixos.org/channels/nixos-unstable".to_owned()),
+            cache_url: Some("https://cache.nixos.org".to_owned()),
+            git_revision: Some(rev.to_owned()),
+            fetch_time: Some("2019-12-01T00:00:00Z".parse().unwrap()),
+            status: RootStatus::Pending,
+        };
+        let root_id = db.insert_root(&root, vec![]).unwrap();
+
+        assert_eq!(
+            db.select_root_by_revision(rev).unwrap(),
+            Some((root_id, root))
+        );
+        assert_eq!(
+            db.select_root_by_revision("fedcba9876543210fedcba9876543210fedcba98")
+                .unwrap(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_select_channel_root_by_revision() {
+        let mut db = Database::open_in_memory().unwrap();
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let channel = |url: &str| Root {
+            channel_url: Some(url.to_owned()),
+            cache_url: Some("https://cache.nixos.org".to_owned()),
+            git_revision: Some(rev.to_owned()),
+            fetch_time: Some("2019-12-01T00:00:00Z".parse().unwrap()),
+            status: RootStatus::Pending,
+        };
+        let stable = "https://nixos.org/channels/nixos-19.09";
+        let unstable = "https://nixos.org/channels/nixos-unstable";
+        let stable_id = db.insert_root(&channel(stable), vec![]).unwrap();
+        // The latest root with this revision is of another channel.
+        db.insert_root(&channel(unstable), vec![]).unwrap();
+
+        assert_eq!(
+            db.select_channel_root_by_revision(stable, rev).unwrap(),
+            Some((stable_id, channel(stable)))
+        );
+        assert_eq!(
+            db.select_channel_root_by_revision("https://nixos.org/channels/nixos-20.03", rev)
+                .unwrap(),
+            None,
+        );
+    }
 }
@@ -1,9 +1,10 @@
+use crate::keys::TrustedKeys;
 use chrono::{DateTime, Utc};
 use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, convert::TryFrom, fmt};
+use std::{borrow::Borrow, collections::BTreeSet, convert::TryFrom, fmt, str::FromStr};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct Root {
     pub channel_url: Option<String>,
     pub cache_url: Option<String>,
@@ -12,6 +13,15 @@ pub struct Root {
     pub status: RootStatus,
 }
 
+/// A stored root with its row id.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RootRecord {
+    pub id: i64,
+    pub root: Root,
+    /// Number of top-level NARs, without following references.
+    pub nar_count: u64,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RootStatus {
     Pending,
@@ -25,7 +35,7 @@ impl Default for RootStatus {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Nar {
     pub store_path: StorePath,
     pub meta: NarMeta,
@@ -33,25 +43,140 @@ pub struct Nar {
 }
 
 // https://github.com/NixOS/nix/blob/61e816217bfdfffd39c130c7cd24f07e640098fc/src/libstore/schema.sql
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NarMeta {
     pub url: String,
 
     pub compression: Option<String>,
-    pub file_hash: Option<String>,
+    pub file_hash: Option<Hash>,
     pub file_size: Option<u64>,
-    pub nar_hash: String,
+    pub nar_hash: Hash,
     pub nar_size: u64,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deriver: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub sig: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sig: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ca: Option<String>,
 }
 
+// https://github.com/NixOS/nix/blob/61e816217bfdfffd39c130c7cd24f07e640098fc/src/libutil/hash.cc#L72
+pub(crate) const NIX_BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HashAlgo {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Length of the digest in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+}
+
+/// A validated `FileHash` or `NarHash`, as `<algo>:<digest>` or a bare sha256
+/// digest from older Nix, with the digest in Nix base32 or base16.
+/// It's kept as is, and formatted the same way.
+#[derive(PartialEq, Eq, Clone)]
+pub struct Hash {
+    raw: String,
+    algo: HashAlgo,
+}
+
+impl Hash {
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+
+    /// The digest without the algorithm prefix.
+    pub fn digest(&self) -> &str {
+        match self.raw.find(':') {
+            Some(pos) => &self.raw[pos + 1..],
+            None => &self.raw,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+}
+
+impl FromStr for Hash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algo, digest) = match s.find(':') {
+            None => (HashAlgo::Sha256, s),
+            Some(pos) => {
+                let algo = match &s[..pos] {
+                    "sha1" => HashAlgo::Sha1,
+                    "sha256" => HashAlgo::Sha256,
+                    "sha512" => HashAlgo::Sha512,
+                    _ => return Err(format_err!("Unsupported hash algorithm: {}", s)),
+                };
+                (algo, &s[pos + 1..])
+            }
+        };
+        let base32_len = (algo.digest_len() * 8 - 1) / 5 + 1;
+        let valid = if digest.len() == base32_len {
+            digest.bytes().all(|b| NIX_BASE32_CHARS.contains(&b))
+        } else if digest.len() == algo.digest_len() * 2 {
+            digest.bytes().all(|b| b.is_ascii_hexdigit())
+        } else {
+            false
+        };
+        if !valid {
+            return Err(format_err!("Invalid {} digest: {}", algo.name(), s));
+        }
+        Ok(Self {
+            raw: s.to_owned(),
+            algo,
+        })
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum NarStatus {
     Pending,
@@ -65,11 +190,80 @@ impl Default for NarStatus {
     }
 }
 
+/// Outcome of inserting a batch of NARs.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct InsertSummary {
+    pub inserted: usize,
+    pub skipped: usize,
+    /// Total `FileSize` of inserted ones.
+    pub inserted_file_size: u64,
+}
+
+/// Size of available NARs in the mirror.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct StorageStats {
+    pub nar_count: u64,
+    /// Total size of (compressed) files on disk.
+    pub file_size: u64,
+    /// Total size of uncompressed NARs.
+    pub nar_size: u64,
+}
+
+impl fmt::Display for StorageStats {
+    /// Like `mirror holds 12,345 paths, 48.0 GiB compressed / 190.2 GiB uncompressed`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_size(size: u64) -> String {
+            const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+            let mut x = size as f64;
+            let mut unit = 0;
+            while x >= 1024.0 && unit + 1 < UNITS.len() {
+                x /= 1024.0;
+                unit += 1;
+            }
+            if unit == 0 {
+                format!("{} B", size)
+            } else {
+                format!("{:.1} {}", x, UNITS[unit])
+            }
+        }
+
+        let count = self.nar_count.to_string();
+        let mut grouped = String::new();
+        for (i, c) in count.chars().enumerate() {
+            if i != 0 && (count.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(c);
+        }
+        write!(
+            f,
+            "mirror holds {} paths, {} compressed / {} uncompressed",
+            grouped,
+            fmt_size(self.file_size),
+            fmt_size(self.nar_size),
+        )
+    }
+}
+
 impl Nar {
-    fn ref_paths(&self) -> impl Iterator<Item = Result<StorePath, Error>> + '_ {
+    pub(crate) fn ref_paths(&self) -> impl Iterator<Item = Result<StorePath, Error>> + '_ {
         // Yield nothing on empty string.
-        self.references.split_terminator(" ").map(move |basename| {
-            StorePath::try_from(format!("{}/{}", self.store_path.root(), basename))
+        // Entries are usually basenames, but full paths are also accepted.
+        self.references.split_terminator(" ").map(move |entry| {
+            let root = self.store_path.root();
+            if entry.starts_with('/') {
+                StorePath::try_from_with_root(entry, root)
+            } else {
+                StorePath::try_from_with_root(format!("{}/{}", root, entry), root)
+            }
+            .map_err(|err| {
+                format_err!(
+                    "Invalid reference '{}' of {}: {}",
+                    entry,
+                    self.store_path,
+                    err,
+                )
+            })
         })
     }
 
@@ -77,6 +271,39 @@ impl Nar {
         self.ref_paths().map(|r| r.map(|path| path.hash()))
     }
 
+    /// The message signed by `Sig`, as `ValidPathInfo::fingerprint` in Nix,
+    /// which always has `NarHash` in Nix base32.
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        use crate::update::{decode_digest, nix_base32_encode};
+
+        let mut refs = self
+            .ref_paths()
+            .map(|r| r.map(|path| path.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        refs.sort();
+        let nar_hash = &self.meta.nar_hash;
+        Ok(format!(
+            "1;{};{}:{};{};{}",
+            self.store_path,
+            nar_hash.algo().name(),
+            nix_base32_encode(&decode_digest(nar_hash)?),
+            self.meta.nar_size,
+            refs.join(","),
+        ))
+    }
+
+    /// Whether any of the signatures is valid by one of `keys`.
+    pub fn verify_signature(&self, keys: &TrustedKeys) -> bool {
+        let msg = match self.fingerprint() {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+        self.meta
+            .sig
+            .iter()
+            .any(|sig| keys.verify(msg.as_bytes(), sig))
+    }
+
     pub fn format_nar_info<'a>(&'a self) -> impl fmt::Display + 'a {
         struct Fmt<'a>(&'a Nar);
 
@@ -97,7 +324,7 @@ impl Nar {
                 write!(f, "NarHash: {}\n", meta.nar_hash)?;
                 write!(f, "NarSize: {}\n", meta.nar_size)?;
                 write!(f, "References: {}\n", nar.references)?;
-                if let Some(sig) = &meta.sig {
+                for sig in &meta.sig {
                     write!(f, "Sig: {}\n", sig)?;
                 }
                 if let Some(deriver) = &meta.deriver {
@@ -114,10 +341,18 @@ impl Nar {
     }
 
     pub fn parse_nar_info(info: &str) -> Result<Self, Error> {
-        Self::parse_nar_info_inner(info).map_err(|err| format_err!("Invalid narinfo: {}", err))
+        Self::parse_nar_info_inner(info).map_err(|err| match err.line {
+            Some((lineno, line)) => format_err!(
+                "Invalid narinfo at line {} '{}': {}",
+                lineno,
+                line,
+                err.reason,
+            ),
+            None => format_err!("Invalid narinfo: {}", err.reason),
+        })
     }
 
-    fn parse_nar_info_inner(info: &str) -> Result<Self, &'static str> {
+    fn parse_nar_info_inner(info: &str) -> Result<Self, NarInfoParseError<'_>> {
         let (
             mut store_path,
             mut url,
@@ -128,59 +363,106 @@ impl Nar {
             mut nar_size,
             mut references,
             mut deriver,
-            mut sig,
             mut ca,
         ) = Default::default();
+        let mut sig = Vec::new();
+        // Fields seen so far. Only `Sig` can be repeated.
+        let mut seen = Vec::new();
 
-        for line in info.lines() {
+        for (idx, line) in info.lines().enumerate() {
             if line.is_empty() {
                 continue;
             }
 
-            let sep = line.find(": ").ok_or("Missing colon")?;
+            let at = |reason| NarInfoParseError {
+                reason,
+                line: Some((idx + 1, line)),
+            };
+            let sep = line.find(": ").ok_or_else(|| at("Missing colon"))?;
             let (k, v) = (&line[..sep], &line[sep + 2..]);
+            if k != "Sig" {
+                if seen.contains(&k) {
+                    return Err(at("Duplicate field"));
+                }
+                seen.push(k);
+            }
             match k {
                 "StorePath" => {
-                    store_path = Some(StorePath::try_from(v).map_err(|_| "Invalid StorePath")?);
+                    store_path =
+                        Some(StorePath::try_from_any_root(v).map_err(|_| at("Invalid StorePath"))?);
                 }
                 "URL" => url = Some(v),
                 "Compression" => compression = Some(v),
-                "FileHash" => file_hash = Some(v),
-                "FileSize" => file_size = Some(v.parse().map_err(|_| "Invalid FileSize")?),
-                "NarHash" => nar_hash = Some(v),
-                "NarSize" => nar_size = Some(v.parse().map_err(|_| "Invalid NarSize")?),
+                "FileHash" => file_hash = Some(v.parse().map_err(|_| at("Invalid FileHash"))?),
+                "FileSize" => file_size = Some(v.parse().map_err(|_| at("Invalid FileSize"))?),
+                "NarHash" => nar_hash = Some(v.parse().map_err(|_| at("Invalid NarHash"))?),
+                "NarSize" => nar_size = Some(v.parse().map_err(|_| at("Invalid NarSize"))?),
                 "References" => references = Some(v),
                 "Deriver" => deriver = Some(v),
-                "Sig" => sig = Some(v),
+                "Sig" => sig.push(v.to_owned()),
                 "CA" => ca = Some(v),
-                _ => return Err("Unknown field"),
+                _ => return Err(at("Unknown field")),
             }
         }
 
-        Ok(Nar {
+        let mut nar = Nar {
             store_path: store_path.ok_or("Missing StorePath")?,
             meta: NarMeta {
                 compression: compression.map(|s| s.to_owned()),
                 url: url.ok_or("Missing URL")?.to_owned(),
-                file_hash: file_hash.map(|s| s.to_owned()),
-                file_size: file_size,
-                nar_hash: nar_hash.ok_or("Missing NarHash")?.to_owned(),
+                file_hash,
+                file_size,
+                nar_hash: nar_hash.ok_or("Missing NarHash")?,
                 nar_size: nar_size.ok_or("Missing NarSize")?,
                 deriver: deriver.map(|s| s.to_owned()),
-                sig: sig.map(|s| s.to_owned()),
+                sig,
                 ca: ca.map(|s| s.to_owned()),
             },
             references: references.ok_or("Missing References")?.to_owned(),
-        })
+        };
+        // Sorted and deduplicated basenames.
+        let refs = nar
+            .ref_paths()
+            .collect::<Result<BTreeSet<_>, _>>()
+            .map_err(|_| "Invalid References")?;
+        nar.references = refs
+            .iter()
+            .map(|path| path.base_name())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(nar)
+    }
+}
+
+#[derive(Debug)]
+struct NarInfoParseError<'a> {
+    reason: &'static str,
+    // 1-based line number and the content.
+    line: Option<(usize, &'a str)>,
+}
+
+impl From<&'static str> for NarInfoParseError<'_> {
+    fn from(reason: &'static str) -> Self {
+        Self { reason, line: None }
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct StorePathHash([u8; Self::LEN]);
 
 impl StorePathHash {
     pub const LEN: usize = 32;
 
+    /// Whether `s` has the length and Nix base32 alphabet of a store path hash.
+    pub fn is_valid(s: &str) -> bool {
+        s.len() == Self::LEN
+            && s.bytes().all(|b| match b {
+                b'e' | b'o' | b'u' | b't' => false,
+                b'a'..=b'z' | b'0'..=b'9' => true,
+                _ => false,
+            })
+    }
+
     pub fn as_str(&self) -> &str {
         std::str::from_utf8(&self.0).unwrap()
     }
@@ -200,93 +482,179 @@ impl fmt::Debug for StorePathHash {
     }
 }
 
+impl FromStr for StorePathHash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !Self::is_valid(s) {
+            return Err(format_err!("Invalid store path hash '{}'", s));
+        }
+        Ok(Self(<[u8; Self::LEN]>::try_from(s.as_bytes()).unwrap()))
+    }
+}
+
+impl TryFrom<&'_ str> for StorePathHash {
+    type Error = Error;
+
+    fn try_from(s: &'_ str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
 impl Borrow<[u8]> for StorePathHash {
     fn borrow(&self) -> &[u8] {
         &self.0
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Ordered by the full path.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct StorePath {
     path: String,
+    /// Length of the store directory, without the trailing `/`.
+    root_len: usize,
 }
 
-// FIXME: Allow non-default store root.
 impl StorePath {
-    const STORE_PREFIX: &'static str = "/nix/store/";
-    const SEP_POS: usize = Self::STORE_PREFIX.len() + StorePathHash::LEN;
-    const MIN_LEN: usize = Self::SEP_POS + 1 + 1;
-    const MAX_LEN: usize = 212;
+    pub const DEFAULT_STORE_DIR: &'static str = "/nix/store";
+    const MIN_BASE_LEN: usize = StorePathHash::LEN + 1 + 1;
+    // The limit of `/nix/store/<hash>-<name>` in Nix, kept for any store directory.
+    const MAX_BASE_LEN: usize = 212 - Self::DEFAULT_STORE_DIR.len() - 1;
+
+    /// Whether `store_dir` is an absolute path without the trailing `/`.
+    pub fn is_valid_store_dir(store_dir: &str) -> bool {
+        store_dir.starts_with('/') && !store_dir.ends_with('/')
+    }
+
+    /// Parse a store path inside `store_dir`, which has no trailing `/`.
+    pub fn try_from_with_root(path: impl Into<String>, store_dir: &str) -> Result<Self, Error> {
+        use failure::ensure;
+
+        let path = path.into();
+        let root_len = store_dir.len();
+        ensure!(
+            Self::is_valid_store_dir(store_dir),
+            "Invalid store directory '{}'",
+            store_dir,
+        );
+        ensure!(
+            path.len() > root_len
+                && path.as_bytes()[root_len] == b'/'
+                && path.starts_with(store_dir),
+            "Path {} is not in store directory {}",
+            path,
+            store_dir,
+        );
+        Self::parse(path, root_len)
+    }
+
+    /// Parse a store path, taking its parent directory as the store directory.
+    pub fn try_from_any_root(path: impl Into<String>) -> Result<Self, Error> {
+        use failure::ensure;
+
+        let path = path.into();
+        let root_len = path.rfind('/').unwrap_or(0);
+        ensure!(
+            root_len != 0 && path.starts_with('/'),
+            "Path {} is not in a store directory",
+            path,
+        );
+        Self::parse(path, root_len)
+    }
+
+    // https://github.com/NixOS/nix/blob/abb8ef619ba2fab3ae16fb5b5430215905bac723/src/libstore/store-api.cc#L85
+    fn parse(path: String, root_len: usize) -> Result<Self, Error> {
+        use failure::ensure;
+
+        let base = &path[root_len + 1..];
+        ensure!(
+            Self::MIN_BASE_LEN <= base.len() && base.len() <= Self::MAX_BASE_LEN,
+            "Length {} is not in range [{}, {}]",
+            base.len(),
+            Self::MIN_BASE_LEN,
+            Self::MAX_BASE_LEN,
+        );
+        ensure!(path.is_ascii(), "Not ascii string: {}", path);
+        ensure!(
+            base.as_bytes()[StorePathHash::LEN] == b'-',
+            "Hash seperator `-` not found",
+        );
+
+        let hash = &base[..StorePathHash::LEN];
+        let name = &base[StorePathHash::LEN + 1..];
+        ensure!(StorePathHash::is_valid(hash), "Invalid hash '{}'", hash);
+        Self::check_name(name)?;
+
+        // Already checked
+        Ok(Self { path, root_len })
+    }
 
     pub fn path(&self) -> &str {
         &self.path
     }
 
     pub fn root(&self) -> &str {
-        &Self::STORE_PREFIX[..Self::STORE_PREFIX.len() - 1]
+        &self.path[..self.root_len]
     }
 
     pub fn hash_str(&self) -> &str {
-        &self.path[Self::STORE_PREFIX.len()..Self::SEP_POS]
+        &self.path[self.root_len + 1..self.sep_pos()]
     }
 
     pub fn hash(&self) -> StorePathHash {
-        StorePathHash(
-            <[u8; StorePathHash::LEN]>::try_from(
-                self.path[Self::STORE_PREFIX.len()..Self::SEP_POS].as_bytes(),
-            )
-            .unwrap(),
-        )
+        StorePathHash(<[u8; StorePathHash::LEN]>::try_from(self.hash_str().as_bytes()).unwrap())
     }
 
-    pub fn name(&self) -> &str {
-        &self.path[Self::SEP_POS + 1..]
+    /// `<hash>-<name>`, as in `References`.
+    pub fn base_name(&self) -> &str {
+        &self.path[self.root_len + 1..]
     }
-}
 
-impl TryFrom<String> for StorePath {
-    type Error = Error;
+    pub fn name(&self) -> &str {
+        &self.path[self.sep_pos() + 1..]
+    }
 
-    // https://github.com/NixOS/nix/blob/abb8ef619ba2fab3ae16fb5b5430215905bac723/src/libstore/store-api.cc#L85
-    fn try_from(path: String) -> Result<Self, Self::Error> {
-        use failure::ensure;
+    fn sep_pos(&self) -> usize {
+        self.root_len + 1 + StorePathHash::LEN
+    }
 
-        fn is_valid_hash(s: &[u8]) -> bool {
-            s.iter().all(|&b| match b {
-                b'e' | b'o' | b'u' | b't' => false,
-                b'a'..=b'z' | b'0'..=b'9' => true,
-                _ => false,
-            })
-        }
+    /// Assemble a store path in the default store directory from an already
+    /// valid hash and a name. Only the name is validated.
+    pub fn from_parts(hash: &StorePathHash, name: &str) -> Result<Self, Error> {
+        Self::check_name(name)?;
+        Ok(Self {
+            path: format!("{}/{}-{}", Self::DEFAULT_STORE_DIR, hash, name),
+            root_len: Self::DEFAULT_STORE_DIR.len(),
+        })
+    }
 
-        fn is_valid_name(s: &[u8]) -> bool {
-            const VALID_CHARS: &[u8] = b"+-._?=";
-            s.iter()
-                .all(|&b| b.is_ascii_alphanumeric() || VALID_CHARS.contains(&b))
-        }
+    fn check_name(name: &str) -> Result<(), Error> {
+        use failure::ensure;
 
+        const VALID_CHARS: &[u8] = b"+-._?=";
+        let max_name_len = Self::MAX_BASE_LEN - StorePathHash::LEN - 1;
         ensure!(
-            Self::MIN_LEN <= path.len() && path.len() <= Self::MAX_LEN,
-            "Length {} is not in range [{}, {}]",
-            path.len(),
-            Self::MIN_LEN,
-            Self::MAX_LEN,
+            !name.is_empty() && name.len() <= max_name_len,
+            "Name length {} is not in range [1, {}]",
+            name.len(),
+            max_name_len,
         );
-        ensure!(path.is_ascii(), "Not ascii string: {}", path);
         ensure!(
-            path.as_bytes()[Self::SEP_POS] == b'-',
-            "Hash seperator `-` not found",
+            name.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || VALID_CHARS.contains(&b)),
+            "Invalid name '{}'",
+            name,
         );
+        Ok(())
+    }
+}
 
-        let hash = &path[Self::STORE_PREFIX.len()..Self::SEP_POS];
-        let name = &path[Self::SEP_POS + 1..];
-        ensure!(is_valid_hash(hash.as_bytes()), "Invalid hash '{}'", hash);
-        ensure!(is_valid_name(name.as_bytes()), "Invalid name '{}'", name);
+impl TryFrom<String> for StorePath {
+    type Error = Error;
 
-        // Already checked
-        Ok(Self {
-            path: path.to_owned(),
-        })
+    /// Parse a store path in the default store directory.
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        Self::try_from_with_root(path, Self::DEFAULT_STORE_DIR)
     }
 }
 
@@ -298,6 +666,21 @@ impl TryFrom<&'_ str> for StorePath {
     }
 }
 
+/// Serialized as the path string.
+impl Serialize for StorePath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.path())
+    }
+}
+
+/// Validated as a path in the default store directory.
+impl<'de> Deserialize<'de> for StorePath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Self::try_from(path).map_err(serde::de::Error::custom)
+    }
+}
+
 impl fmt::Display for StorePath {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(self.path(), f)
@@ -319,12 +702,18 @@ mod tests {
             meta: NarMeta {
                 url: "some/url".to_owned(),
                 compression: Some("xz".to_owned()),
-                file_hash: Some("file:hash".to_owned()),
+                file_hash: Some(
+                    "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+                        .parse()
+                        .unwrap(),
+                ),
                 file_size: Some(123),
-                nar_hash: "nar:hash".to_owned(),
+                nar_hash: "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+                    .parse()
+                    .unwrap(),
                 nar_size: 456,
                 deriver: Some("some.drv".to_owned()),
-                sig: Some("s:i/g 2".to_owned()),
+                sig: vec!["s:i/g 2".to_owned(), "s2:sig".to_owned()],
                 ca: Some("fixed:hash".to_owned()),
             },
             references: "ref1 ref2".to_owned(),
@@ -334,12 +723,13 @@ mod tests {
         StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
         URL: some/url
         Compression: xz
-        FileHash: file:hash
+        FileHash: sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic
         FileSize: 123
-        NarHash: nar:hash
+        NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
         NarSize: 456
         References: ref1 ref2
         Sig: s:i/g 2
+        Sig: s2:sig
         Deriver: some.drv
         CA: fixed:hash
         "###);
@@ -351,12 +741,13 @@ mod tests {
         StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
         URL: some/url
         Compression: xz
-        FileHash: file:hash
+        FileHash: sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic
         FileSize: 123
-        NarHash: nar:hash
+        NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
         NarSize: 456
         References: 
         Sig: s:i/g 2
+        Sig: s2:sig
         "###);
     }
 
@@ -366,12 +757,13 @@ mod tests {
 StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
 URL: some/url
 Compression: xz
-FileHash: file:hash
+FileHash: sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic
 FileSize: 123
-NarHash: nar:hash
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
 NarSize: 456
-References: ref1 ref2
+References: xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
 Sig: s:i/g 2
+Sig: s2:sig
 Deriver: some.drv
 CA: fixed:hash
 "###;
@@ -384,17 +776,387 @@ CA: fixed:hash
             meta: NarMeta {
                 url: "some/url".to_owned(),
                 compression: Some("xz".to_owned()),
-                file_hash: Some("file:hash".to_owned()),
+                file_hash: Some(
+                    "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+                        .parse()
+                        .unwrap(),
+                ),
                 file_size: Some(123),
-                nar_hash: "nar:hash".to_owned(),
+                nar_hash: "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+                    .parse()
+                    .unwrap(),
                 nar_size: 456,
                 deriver: Some("some.drv".to_owned()),
-                sig: Some("s:i/g 2".to_owned()),
+                sig: vec!["s:i/g 2".to_owned(), "s2:sig".to_owned()],
                 ca: Some("fixed:hash".to_owned()),
             },
-            references: "ref1 ref2".to_owned(),
+            references: "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10".to_owned(),
         };
 
         assert_eq!(Nar::parse_nar_info(raw).unwrap(), nar);
+
+        let raw = raw.replace("Compression: xz", "Compression: zstd");
+        let nar = Nar::parse_nar_info(&raw).unwrap();
+        assert_eq!(nar.meta.compression.as_deref(), Some("zstd"));
+        assert_eq!(nar.format_nar_info().to_string(), raw.trim_start());
+    }
+
+    #[test]
+    fn test_nar_info_verify_signature() {
+        let keys =
+            TrustedKeys::parse("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=")
+                .unwrap();
+        let raw = "\
+StorePath: /nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz
+URL: nar/0rv8nbz4dwg9ipm3xl42m8wfihqbq9rdr9gxsqw5p3rjnw2f8mzi.nar.xz
+Compression: xz
+NarHash: sha256:0i6abchw6pa0p313ahhz0myrr2sbk1npxkkprbbw1qmz6javbc6x
+NarSize: 8845976
+References: 
+Sig: other-1:+t+LMZdteGZ6dasXA1yZqv61RpQBfp5C5gXSktiUun/A7REwDO1Zo/u388sTGF8Vg8GX2VdggWTG2WCi03ceCg==
+Sig: cache.nixos.org-1:+t+LMZdteGZ6dasXA1yZqv61RpQBfp5C5gXSktiUun/A7REwDO1Zo/u388sTGF8Vg8GX2VdggWTG2WCi03ceCg==
+";
+        let mut nar = Nar::parse_nar_info(raw).unwrap();
+        assert_eq!(
+            nar.fingerprint().unwrap(),
+            "1;/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz;\
+             sha256:0i6abchw6pa0p313ahhz0myrr2sbk1npxkkprbbw1qmz6javbc6x;8845976;",
+        );
+        assert!(nar.verify_signature(&keys));
+        assert_eq!(nar.format_nar_info().to_string(), raw);
+
+        // Only signed by a non-trusted key.
+        let sig = nar.meta.sig.pop().unwrap();
+        assert!(!nar.verify_signature(&keys));
+        nar.meta.sig.clear();
+        assert!(!nar.verify_signature(&keys));
+
+        nar.meta.sig.push(sig);
+        nar.meta.nar_size += 1;
+        assert!(!nar.verify_signature(&keys));
+        nar.meta.nar_size -= 1;
+
+        // Signed the same in base16.
+        let base16 = crate::update::decode_digest(&nar.meta.nar_hash)
+            .unwrap()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        nar.meta.nar_hash = format!("sha256:{}", base16).parse().unwrap();
+        assert!(nar.verify_signature(&keys));
+    }
+
+    #[test]
+    fn test_nar_info_parse_error() {
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: notanumber
+References: 
+";
+        let err = Nar::parse_nar_info(raw).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid narinfo at line 4 'NarSize: notanumber': Invalid NarSize",
+        );
+
+        let err = Nar::parse_nar_info("URL: some/url\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Invalid narinfo: Missing StorePath");
+
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 456
+References: 
+Sig: s:1
+Sig: s:2
+";
+        assert_eq!(Nar::parse_nar_info(raw).unwrap().meta.sig, ["s:1", "s:2"]);
+        // Appended at line 8.
+        for dup in &[
+            "StorePath: /nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27",
+            "URL: some/url",
+            "NarSize: 123",
+        ] {
+            let err = Nar::parse_nar_info(&format!("{}{}\n", raw, dup))
+                .unwrap_err()
+                .to_string();
+            assert_eq!(
+                err,
+                format!("Invalid narinfo at line 8 '{}': Duplicate field", dup),
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_parse() {
+        const SHA256: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+        let hash: Hash = format!("sha256:{}", SHA256).parse().unwrap();
+        assert_eq!(hash.algo(), HashAlgo::Sha256);
+        assert_eq!(hash.digest(), SHA256);
+        assert_eq!(hash.to_string(), format!("sha256:{}", SHA256));
+
+        // Bare digests from older Nix are sha256, and kept bare.
+        let hash: Hash = SHA256.parse().unwrap();
+        assert_eq!(hash.algo(), HashAlgo::Sha256);
+        assert_eq!(hash.to_string(), SHA256);
+
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(hex.parse::<Hash>().unwrap().algo(), HashAlgo::Sha256);
+        let sha1 = "sha1:0000000000000000000000000000000000000000";
+        assert_eq!(sha1.parse::<Hash>().unwrap().algo(), HashAlgo::Sha1);
+        let sha512 = format!("sha512:{}", "1".repeat(103));
+        assert_eq!(sha512.parse::<Hash>().unwrap().algo(), HashAlgo::Sha512);
+
+        for bad in &[
+            "".to_owned(),
+            "sha256:".to_owned(),
+            format!("md5:{}", SHA256),
+            format!("sha256:{}", &SHA256[1..]),
+            format!("sha1:{}", SHA256),
+            format!("sha256:{}", SHA256.replace('m', "e")),
+            format!("sha256:{}g", &hex[1..]),
+            format!("sha256:sha256:{}", SHA256),
+        ] {
+            assert!(bad.parse::<Hash>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_nar_info_bare_hash() {
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: 0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 456
+References: 
+";
+        let nar = Nar::parse_nar_info(raw).unwrap();
+        assert_eq!(nar.meta.nar_hash.algo(), HashAlgo::Sha256);
+        assert_eq!(nar.format_nar_info().to_string(), raw);
+        assert!(nar
+            .fingerprint()
+            .unwrap()
+            .contains(";sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73;",));
+
+        let err = Nar::parse_nar_info(&raw.replace("NarHash: 0", "NarHash: sha256:"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with(": Invalid NarHash"), "{}", err);
+        let err = Nar::parse_nar_info(&raw.replace("References:", "FileHash: x\nReferences:"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with(": Invalid FileHash"), "{}", err);
+    }
+
+    #[test]
+    fn test_nar_info_full_path_references() {
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 456
+References: /nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+";
+        let nar = Nar::parse_nar_info(raw).unwrap();
+        assert_eq!(nar.references, "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10");
+        let hashes = nar.ref_hashes().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            hashes.iter().map(|h| h.as_str()).collect::<Vec<_>>(),
+            [
+                "xlxiw4rnxx2dksa91fizjzf7jb5nqghc",
+                "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_nar_info_duplicate_references() {
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 456
+References: yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10 xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+";
+        let nar = Nar::parse_nar_info(raw).unwrap();
+        assert_eq!(nar.references, "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10");
+        assert_eq!(nar.ref_hashes().count(), 2);
+        assert!(nar
+            .format_nar_info()
+            .to_string()
+            .contains("\nReferences: xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10\n"));
+    }
+
+    #[test]
+    fn test_nar_info_invalid_reference() {
+        let raw = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: some/url
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 456
+References: xlxiw4rnxx-glibc-2.27
+";
+        let err = Nar::parse_nar_info(raw).unwrap_err().to_string();
+        assert_eq!(err, "Invalid narinfo: Invalid References");
+
+        let nar = Nar {
+            references: "xlxiw4rnxx-glibc-2.27".to_owned(),
+            ..Nar::parse_nar_info(&raw.replace("xlxiw4rnxx-glibc-2.27", "")).unwrap()
+        };
+        let err = nar.ref_hashes().next().unwrap().unwrap_err().to_string();
+        assert!(err.starts_with(
+            "Invalid reference 'xlxiw4rnxx-glibc-2.27' of /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10: ",
+        ));
+    }
+
+    #[test]
+    fn test_storage_stats_display() {
+        let stats = StorageStats {
+            nar_count: 12345,
+            file_size: 48 << 30,
+            nar_size: (190 << 30) + (200 << 20),
+        };
+        assert_eq!(
+            stats.to_string(),
+            "mirror holds 12,345 paths, 48.0 GiB compressed / 190.2 GiB uncompressed",
+        );
+        assert_eq!(
+            StorageStats::default().to_string(),
+            "mirror holds 0 paths, 0 B compressed / 0 B uncompressed",
+        );
+        let stats = StorageStats {
+            nar_count: 123,
+            file_size: 1023,
+            nar_size: 1536,
+        };
+        assert_eq!(
+            stats.to_string(),
+            "mirror holds 123 paths, 1023 B compressed / 1.5 KiB uncompressed",
+        );
+    }
+
+    #[test]
+    fn test_store_path_hash_from_str() {
+        let s = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let hash: StorePathHash = s.parse().unwrap();
+        assert_eq!(hash.as_str(), s);
+        assert_eq!(StorePathHash::try_from(s).unwrap(), hash);
+        let path = StorePath::try_from(format!("/nix/store/{}-hello-2.10", s)).unwrap();
+        assert_eq!(path.hash(), hash);
+
+        // Wrong length, or out of the Nix base32 alphabet.
+        for s in &[
+            "",
+            "yhzvzdq82lzk0kvrp3i79yhjnhps6qp",
+            "yhzvzdq82lzk0kvrp3i79yhjnhps6qpkk",
+            "ehzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "ohzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "uhzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "thzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "Yhzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "-hzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "\u{e9}zvzdq82lzk0kvrp3i79yhjnhps6qpk",
+        ] {
+            assert!(s.parse::<StorePathHash>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_store_path_ord() {
+        use std::collections::BTreeSet;
+
+        let paths = [
+            "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10",
+            "/nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27",
+            "/nix/store/0000000000000000000000000000000z-z",
+            "/nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27-bin",
+        ];
+        let set: BTreeSet<_> = paths
+            .iter()
+            .map(|s| StorePath::try_from(*s).unwrap())
+            .collect();
+        let mut expect = paths.to_vec();
+        expect.sort();
+        assert_eq!(set.iter().map(|p| p.path()).collect::<Vec<_>>(), expect);
+
+        let hashes: BTreeSet<_> = set.iter().map(|p| p.hash()).collect();
+        assert_eq!(
+            hashes.iter().map(|h| h.as_str()).collect::<Vec<_>>(),
+            [
+                "0000000000000000000000000000000z",
+                "xlxiw4rnxx2dksa91fizjzf7jb5nqghc",
+                "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_store_path_serde() {
+        let s = "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10";
+        let path = StorePath::try_from(s).unwrap();
+        let json = serde_json::to_string(&[&path]).unwrap();
+        assert_eq!(json, format!("[\"{}\"]", s));
+        assert_eq!(
+            serde_json::from_str::<Vec<StorePath>>(&json).unwrap(),
+            [path],
+        );
+
+        for bad in &[
+            r#""/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk""#,
+            r#""/data/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10""#,
+            r#""/nix/store/ehzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10""#,
+            "42",
+        ] {
+            assert!(serde_json::from_str::<StorePath>(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_store_path_from_parts() {
+        let s = "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10";
+        let parsed = StorePath::try_from(s).unwrap();
+        let hash = parsed.hash();
+        assert_eq!(StorePath::from_parts(&hash, "hello-2.10").unwrap(), parsed);
+
+        assert!(StorePath::from_parts(&hash, "").is_err());
+        assert!(StorePath::from_parts(&hash, "hello/world").is_err());
+        assert!(StorePath::from_parts(&hash, &"a".repeat(200)).is_err());
+    }
+
+    #[test]
+    fn test_store_path_custom_root() {
+        let base = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10";
+        let s = format!("/data/nix/store/{}", base);
+        let path = StorePath::try_from_with_root(&*s, "/data/nix/store").unwrap();
+        assert_eq!(path.root(), "/data/nix/store");
+        assert_eq!(path.hash_str(), "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk");
+        assert_eq!(path.name(), "hello-2.10");
+        assert_eq!(StorePath::try_from_any_root(&*s).unwrap(), path);
+
+        assert!(StorePath::try_from(&*s).is_err());
+        assert!(StorePath::try_from_with_root(&*s, "/data/nix").is_err());
+        assert!(StorePath::try_from_with_root(&*s, "/data/nix/store/").is_err());
+        assert!(StorePath::try_from_with_root(&*s, "/data/nix/sto").is_err());
+        assert!(StorePath::try_from_any_root(base).is_err());
+        assert!(StorePath::try_from(format!("/nix/stora/{}", base)).is_err());
+
+        // References are resolved in the same store directory.
+        let nar = Nar::parse_nar_info(&format!(
+            "StorePath: {}\nURL: nar/a.nar\nNarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\nNarSize: 1\n\
+             References: {} xlxiw4rnxx7h6irqbyx7klq9ligmjxw6-glibc-2.27\n",
+            s, base,
+        ))
+        .unwrap();
+        assert_eq!(
+            nar.ref_paths()
+                .map(|p| p.unwrap().root().to_owned())
+                .collect::<Vec<_>>(),
+            vec!["/data/nix/store"; 2],
+        );
     }
 }
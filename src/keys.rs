@@ -0,0 +1,112 @@
+use failure::{bail, format_err, Error, ResultExt as _};
+use ring::signature::{UnparsedPublicKey, ED25519, ED25519_PUBLIC_KEY_LEN};
+use std::{collections::HashMap, fs, path::Path};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Trusted ed25519 public keys, indexed by key name.
+#[derive(Debug, Default, Clone)]
+pub struct TrustedKeys {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl TrustedKeys {
+    /// Parse keys in Nix's `name:base64` format, one per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut ret = Self::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            ret.insert(line).with_context(|err| {
+                format_err!("Invalid public key at line {} '{}': {}", idx + 1, line, err)
+            })?;
+        }
+        Ok(ret)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|err| format_err!("Cannot read '{}': {}", path.display(), err))?;
+        Self::parse(&s)
+    }
+
+    /// Add a single key in `name:base64` format.
+    pub fn insert(&mut self, key: &str) -> Result<()> {
+        let (name, key) = split_name(key)?;
+        let key = base64::decode(key)?;
+        if key.len() != ED25519_PUBLIC_KEY_LEN {
+            bail!("Invalid key length {}", key.len());
+        }
+        self.keys.insert(name.to_owned(), key);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check a signature in `name:base64` format against `msg`.
+    /// Signatures by unknown keys are never valid.
+    pub fn verify(&self, msg: &[u8], sig: &str) -> bool {
+        let (name, sig) = match split_name(sig) {
+            Ok(ret) => ret,
+            Err(_) => return false,
+        };
+        let key = match self.keys.get(name) {
+            Some(key) => key,
+            None => return false,
+        };
+        match base64::decode(sig) {
+            Ok(sig) => UnparsedPublicKey::new(&ED25519, key)
+                .verify(msg, &sig)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+fn split_name(s: &str) -> Result<(&str, &str)> {
+    match s.find(':') {
+        Some(0) | None => bail!("Missing key name"),
+        Some(sep) => Ok((&s[..sep], &s[sep + 1..])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIXOS_KEY: &str = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+    #[test]
+    fn test_verify() {
+        let keys = TrustedKeys::parse(&format!("# Official\n\n{}\n", NIXOS_KEY)).unwrap();
+        assert_eq!(keys.len(), 1);
+
+        let msg = b"1;/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz;sha256:0i6abchw6pa0p313ahhz0myrr2sbk1npxkkprbbw1qmz6javbc6x;8845976;";
+        let sig = "cache.nixos.org-1:+t+LMZdteGZ6dasXA1yZqv61RpQBfp5C5gXSktiUun/A7REwDO1Zo/u388sTGF8Vg8GX2VdggWTG2WCi03ceCg==";
+        assert!(keys.verify(msg, sig));
+        assert!(!keys.verify(b"1;tampered", sig));
+        assert!(!keys.verify(msg, &sig.replace("cache.nixos.org-1", "other-1")));
+        assert!(!keys.verify(msg, "cache.nixos.org-1:not base64"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = TrustedKeys::parse(&format!("{}\nfoo:AAAA\n", NIXOS_KEY)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid public key at line 2 'foo:AAAA': Invalid key length 3",
+        );
+        let err = TrustedKeys::parse("6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=").unwrap_err();
+        assert!(err.to_string().ends_with(": Missing key name"));
+    }
+}
@@ -3,6 +3,8 @@ use hyper;
 use tokio;
 
 pub mod database;
+pub mod keys;
+pub mod logging;
 pub mod server;
 pub mod update;
 mod util;
@@ -44,9 +46,219 @@ pub(crate) fn spawn(fut: impl std::future::Future<Output = ()> + Send + 'static)
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use crate::database::model::{Nar, NarMeta, StorePath};
+    use futures01::Future as _;
+    use hyper::{service::service_fn, Body, Response, Server, StatusCode};
+    use std::{
+        collections::HashMap,
+        convert::TryFrom,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    lazy_static::lazy_static! {
+        static ref ACCESS_LOGS: Mutex<Vec<serde_json::Value>> = Mutex::new(vec![]);
+    }
+
+    /// `env_logger`, but also keeping access logs formatted in JSON.
+    struct TestLogger(env_logger::Logger);
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == crate::logging::ACCESS_TARGET || self.0.enabled(metadata)
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == crate::logging::ACCESS_TARGET {
+                let mut buf = vec![];
+                crate::logging::format_json(&mut buf, record).unwrap();
+                let line = serde_json::from_slice(&buf).unwrap();
+                ACCESS_LOGS.lock().unwrap().push(line);
+            }
+            if self.0.matches(record) {
+                self.0.log(record);
+            }
+        }
+
+        fn flush(&self) {
+            self.0.flush();
+        }
+    }
+
     pub fn init_logger() {
         use std::sync::Once;
         static ONCE: Once = Once::new();
-        ONCE.call_once(env_logger::init);
+        ONCE.call_once(|| {
+            let logger = env_logger::Builder::from_default_env().build();
+            log::set_max_level(logger.filter().max(log::LevelFilter::Info));
+            log::set_boxed_logger(Box::new(TestLogger(logger))).unwrap();
+        });
+    }
+
+    /// A NAR with placeholder metadata, for tests only caring about the graph and sizes.
+    pub fn test_nar(path: &str, file_size: u64, references: &str) -> Nar {
+        Nar {
+            store_path: StorePath::try_from(path).unwrap(),
+            meta: NarMeta {
+                url: "some/url".to_owned(),
+                compression: None,
+                file_hash: None,
+                file_size: Some(file_size),
+                nar_hash: "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+                    .parse()
+                    .unwrap(),
+                nar_size: 1,
+                deriver: None,
+                sig: vec![],
+                ca: None,
+            },
+            references: references.to_owned(),
+        }
+    }
+
+    /// Access logs of requests with `path`, as JSON objects.
+    pub fn access_logs(path: &str) -> Vec<serde_json::Value> {
+        ACCESS_LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line["path"] == path)
+            .cloned()
+            .collect()
+    }
+
+    /// A local HTTP server serving static files, counting requests per path.
+    pub struct MockServer {
+        pub url: String,
+        hits: Arc<Mutex<HashMap<String, usize>>>,
+        // Remaining 503 responses per path.
+        failures: Arc<Mutex<HashMap<String, usize>>>,
+        in_flight: Arc<(AtomicUsize, AtomicUsize)>,
+        // Whether `Range: bytes=<start>-` is honored.
+        ranges: Arc<AtomicBool>,
+    }
+
+    impl MockServer {
+        /// Must be called inside the runtime.
+        pub fn start(files: HashMap<String, Vec<u8>>) -> Self {
+            Self::start_with_delay(files, Duration::from_secs(0))
+        }
+
+        /// Delay every response by `delay`. Must be called inside the runtime.
+        pub fn start_with_delay(files: HashMap<String, Vec<u8>>, delay: Duration) -> Self {
+            let files = Arc::new(files);
+            let hits = Arc::new(Mutex::new(HashMap::new()));
+            let failures = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+            // (current, max)
+            let in_flight = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+            let ranges = Arc::new(AtomicBool::new(false));
+            let (hits_, failures_, in_flight_, ranges_) = (
+                hits.clone(),
+                failures.clone(),
+                in_flight.clone(),
+                ranges.clone(),
+            );
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+                let (files, hits, failures, in_flight, ranges) = (
+                    files.clone(),
+                    hits_.clone(),
+                    failures_.clone(),
+                    in_flight_.clone(),
+                    ranges_.clone(),
+                );
+                service_fn(move |req| {
+                    let path = req.uri().path().to_owned();
+                    let range_start = req
+                        .headers()
+                        .get(hyper::header::RANGE)
+                        .filter(|_| ranges.load(Ordering::SeqCst))
+                        .and_then(|v| v.to_str().ok()?.strip_prefix("bytes=")?.strip_suffix('-'))
+                        .and_then(|s| s.parse::<usize>().ok());
+                    *hits.lock().unwrap().entry(path.clone()).or_default() += 1;
+                    let fail = match failures.lock().unwrap().get_mut(&path) {
+                        Some(n) if *n > 0 => {
+                            *n -= 1;
+                            true
+                        }
+                        _ => false,
+                    };
+                    let cur = in_flight.0.fetch_add(1, Ordering::SeqCst) + 1;
+                    in_flight.1.fetch_max(cur, Ordering::SeqCst);
+
+                    let (files, in_flight) = (files.clone(), in_flight.clone());
+                    tokio::timer::Delay::new(Instant::now() + delay).then(move |_| {
+                        in_flight.0.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, hyper::Error>(match files.get(&path) {
+                            _ if fail => {
+                                let mut resp = Response::new(Body::empty());
+                                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                                resp
+                            }
+                            Some(content) => match range_start {
+                                None => Response::new(Body::from(content.clone())),
+                                Some(start) if start < content.len() => Response::builder()
+                                    .status(StatusCode::PARTIAL_CONTENT)
+                                    .header(
+                                        hyper::header::CONTENT_RANGE,
+                                        format!(
+                                            "bytes {}-{}/{}",
+                                            start,
+                                            content.len() - 1,
+                                            content.len(),
+                                        ),
+                                    )
+                                    .body(Body::from(content[start..].to_vec()))
+                                    .unwrap(),
+                                Some(_) => {
+                                    let mut resp = Response::new(Body::empty());
+                                    *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                                    resp
+                                }
+                            },
+                            None => {
+                                let mut resp = Response::new(Body::empty());
+                                *resp.status_mut() = StatusCode::NOT_FOUND;
+                                resp
+                            }
+                        })
+                    })
+                })
+            });
+            let url = format!("http://{}", server.local_addr());
+            hyper::rt::spawn(server.map_err(|err| panic!("Mock server failed: {}", err)));
+            Self {
+                url,
+                hits,
+                failures,
+                in_flight,
+                ranges,
+            }
+        }
+
+        /// Honor `Range: bytes=<start>-` requests. They are ignored by default.
+        pub fn accept_ranges(&self) {
+            self.ranges.store(true, Ordering::SeqCst);
+        }
+
+        /// Respond 503 to the next `n` requests of `path`.
+        pub fn fail_next(&self, path: &str, n: usize) {
+            self.failures.lock().unwrap().insert(path.to_owned(), n);
+        }
+
+        pub fn hits(&self, path: &str) -> usize {
+            self.hits.lock().unwrap().get(path).copied().unwrap_or(0)
+        }
+
+        pub fn total_hits(&self) -> usize {
+            self.hits.lock().unwrap().values().sum()
+        }
+
+        /// The maximum number of requests being handled at the same time.
+        pub fn max_in_flight(&self) -> usize {
+            self.in_flight.1.load(Ordering::SeqCst)
+        }
     }
 }
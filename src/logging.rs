@@ -0,0 +1,188 @@
+use chrono::{SecondsFormat, Utc};
+use log::{
+    kv::{self, VisitSource, VisitValue},
+    Record,
+};
+use std::{fmt, io, io::Write, str::FromStr, time::Duration};
+
+/// Log target of per-request access logs.
+pub const ACCESS_TARGET: &str = "access";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Human,
+    /// One JSON object per line, with fields `time`, `level`, `target` and `message`,
+    /// plus the key-values of the record. Access logs have `method`, `path`,
+    /// `status`, `bytes` and `duration_ms`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(failure::format_err!("Invalid log format '{}'", s)),
+        }
+    }
+}
+
+/// Initialize the global logger, filtered by `RUST_LOG` as `env_logger` does.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(format_json);
+    }
+    builder.init();
+}
+
+pub(crate) fn format_json(w: &mut impl Write, record: &Record) -> io::Result<()> {
+    let mut obj = serde_json::Map::new();
+    obj.insert(
+        "time".to_owned(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    obj.insert("level".to_owned(), record.level().to_string().into());
+    obj.insert("target".to_owned(), record.target().into());
+    obj.insert("message".to_owned(), record.args().to_string().into());
+    record
+        .key_values()
+        .visit(&mut JsonFields(&mut obj))
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    writeln!(w, "{}", serde_json::Value::from(obj))
+}
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        // Never shadow the common fields.
+        self.0.entry(key.as_str()).or_insert(json.0);
+        Ok(())
+    }
+}
+
+struct JsonValue(serde_json::Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct AccessEntry<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    /// Body bytes sent, which are less than expected if the transfer is aborted.
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl AccessEntry<'_> {
+    pub fn log(&self) {
+        log::info!(
+            target: ACCESS_TARGET,
+            method = self.method,
+            path = self.path,
+            status = self.status,
+            bytes = self.bytes,
+            duration_ms = self.duration.as_millis() as u64;
+            "{}", self
+        );
+    }
+}
+
+impl fmt::Display for AccessEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}B {}ms",
+            self.method,
+            self.path,
+            self.status,
+            self.bytes,
+            self.duration.as_millis(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_format_json() {
+        let kvs: &[(&str, kv::Value)] = &[
+            ("url", "https://example.com/nar/a.nar".into()),
+            ("offset", 42u64.into()),
+            ("level", "shadowed".into()),
+            ("error", kv::Value::from_display(&"timed out")),
+        ];
+        let mut buf = vec![];
+        format_json(
+            &mut buf,
+            &Record::builder()
+                .level(Level::Warn)
+                .target("nix_cache_mirror::update")
+                .args(format_args!("{{\"not\": \"fields\"}}\n"))
+                .key_values(&kvs)
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(buf.pop(), Some(b'\n'));
+        assert!(!buf.contains(&b'\n'));
+        let mut v: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert!(v.as_object_mut().unwrap().remove("time").is_some());
+        assert_eq!(
+            v,
+            serde_json::json!({
+                "level": "WARN",
+                "target": "nix_cache_mirror::update",
+                "message": "{\"not\": \"fields\"}\n",
+                "url": "https://example.com/nar/a.nar",
+                "offset": 42,
+                "error": "timed out",
+            }),
+        );
+    }
+}
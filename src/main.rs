@@ -1,67 +1,318 @@
 extern crate nix_cache_mirror;
 
-use env_logger;
-use futures::compat::Future01CompatExt as _;
-use hyper::{self, service::service_fn, Server};
-use nix_cache_mirror::{block_on, database::Database, server, update};
-use std::{path::Path, sync::Arc};
+use nix_cache_mirror::{
+    block_on,
+    database::{model, Database},
+    logging, server, update,
+};
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Mirror NARs of Nix channels and serve them as a binary cache")]
+struct Opt {
+    /// `human` or `json`, one object per line.
+    #[structopt(long, default_value = "human")]
+    log_format: logging::LogFormat,
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Add the closure of a channel as a new root.
+    AddChannel {
+        /// The database file, created if missing.
+        #[structopt(long, parse(from_os_str))]
+        db: PathBuf,
+        /// Override the binary cache of the channel.
+        #[structopt(long)]
+        cache_url: Option<String>,
+        /// Add it even if the same revision is already added.
+        #[structopt(long)]
+        force: bool,
+        channel_url: String,
+    },
+    /// Add the closure of store paths listed in a file, one per line, as a new root.
+    AddPaths {
+        /// The database file, created if missing.
+        #[structopt(long, parse(from_os_str))]
+        db: PathBuf,
+        #[structopt(long, default_value = "https://cache.nixos.org")]
+        cache_url: String,
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Download pending NARs, dependencies first, and mark them available.
+    Download {
+        /// The database file.
+        #[structopt(long, parse(from_os_str))]
+        db: PathBuf,
+        /// The directory of downloaded NAR files, created if missing.
+        #[structopt(long, parse(from_os_str))]
+        nar_dir: PathBuf,
+        /// Used for NARs not fetched from another binary cache.
+        #[structopt(long, default_value = "https://cache.nixos.org")]
+        cache_url: String,
+        /// Only download the closure of this root.
+        #[structopt(long)]
+        root: Option<i64>,
+        /// Maximum number of files downloading at the same time.
+        #[structopt(long, default_value = "8", parse(try_from_str = parse_positive))]
+        concurrency: usize,
+    },
+    /// Serve downloaded NARs as a binary cache.
+    Serve(ServeOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct ServeOpt {
+    #[structopt(long, default_value = "127.0.0.1:3000")]
+    listen: SocketAddr,
+    /// The database file.
+    #[structopt(long, parse(from_os_str))]
+    db: PathBuf,
+    /// The directory of downloaded NAR files.
+    #[structopt(long, parse(from_os_str))]
+    nar_dir: PathBuf,
+    /// `StoreDir` of served store paths.
+    #[structopt(long, default_value = "/nix/store", parse(try_from_str = parse_store_dir))]
+    store_dir: String,
+    /// Lower `Priority` wins against other substituters.
+    #[structopt(long, default_value = "40")]
+    priority: i32,
+    /// Omit `Priority` in nix-cache-info.
+    #[structopt(long, conflicts_with = "priority")]
+    no_priority: bool,
+    /// Value of `WantMassQuery` in nix-cache-info.
+    #[structopt(long, default_value = "true", parse(try_from_str))]
+    mass_query: bool,
+    /// Render narinfos on demand, trading latency for memory on large databases.
+    #[structopt(long)]
+    narinfo_on_demand: bool,
+    /// Accept pushed NARs, e.g. by `nix copy --to`, authorized by the token in this file.
+    #[structopt(
+        long = "upload-token-file",
+        value_name = "upload-token-file",
+        parse(try_from_os_str = read_token_file)
+    )]
+    upload_token: Option<String>,
+    /// Size limit in bytes of NAR files uploaded before their narinfos.
+    #[structopt(long, default_value = "4294967296")]
+    upload_max_orphan_size: u64,
+    /// Remove uploaded NAR files still waiting for narinfos after this many seconds.
+    #[structopt(long, default_value = "86400", parse(try_from_str = parse_secs))]
+    upload_staged_ttl: Duration,
+    /// Close connections idle for longer than this many seconds.
+    #[structopt(long, parse(try_from_str = parse_secs))]
+    idle_timeout: Option<Duration>,
+    /// Serve HTTPS with this PEM encoded certificate chain, leaf first.
+    #[structopt(long, requires = "tls-key", parse(try_from_os_str = existing_file))]
+    tls_cert: Option<PathBuf>,
+    /// PEM encoded PKCS #8 private key of `--tls-cert`.
+    #[structopt(long, requires = "tls-cert", parse(try_from_os_str = existing_file))]
+    tls_key: Option<PathBuf>,
+}
+
+fn parse_positive(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("Must be positive".to_owned()),
+        Ok(n) => Ok(n),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    parse_positive(s).map(|secs| Duration::from_secs(secs as u64))
+}
+
+fn parse_store_dir(s: &str) -> Result<String, String> {
+    if model::StorePath::is_valid_store_dir(s) {
+        Ok(s.to_owned())
+    } else {
+        Err("Must be an absolute path without the trailing `/`".to_owned())
+    }
+}
+
+fn existing_file(s: &OsStr) -> Result<PathBuf, OsString> {
+    let path = PathBuf::from(s);
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(format!("'{}' is not a file", path.display()).into())
+    }
+}
+
+fn read_token_file(s: &OsStr) -> Result<String, OsString> {
+    let content = fs::read_to_string(s)
+        .map_err(|err| format!("Cannot read '{}': {}", Path::new(s).display(), err))?;
+    match content.trim() {
+        "" => Err("The token is empty".into()),
+        token => Ok(token.to_owned()),
+    }
+}
 
 fn main() {
-    env_logger::init();
+    let opt = Opt::from_args();
+    logging::init(opt.log_format);
 
-    add_channel();
-    // add_raw_channel();
-    // serve();
+    match opt.cmd {
+        Command::AddChannel {
+            db,
+            cache_url,
+            force,
+            channel_url,
+        } => add_channel(&db, channel_url, cache_url, force),
+        Command::AddPaths {
+            db,
+            cache_url,
+            file,
+        } => add_paths(&db, cache_url, &file),
+        Command::Download {
+            db,
+            nar_dir,
+            cache_url,
+            root,
+            concurrency,
+        } => download(&db, nar_dir, cache_url, root, concurrency),
+        Command::Serve(opt) => serve(opt),
+    }
 }
 
-fn add_channel() {
-    let mut db = Database::open("./data/unstable.sqlite").unwrap();
+fn add_channel(db_path: &Path, channel_url: String, cache_url: Option<String>, force: bool) {
+    let mut db = Database::open_exclusive(db_path).unwrap();
     block_on(async move {
-        update::add_nix_channel_rec(&mut db, "https://nixos.org/channels/nixos-unstable", None)
+        let id = update::add_nix_channel_rec(
+            &mut db,
+            &channel_url,
+            cache_url.as_deref(),
+            force,
+            &update::FetchConfig::default(),
+        )
+        .await
+        .unwrap();
+        println!("{}", id);
+    });
+}
+
+fn add_paths(db_path: &Path, cache_url: String, file: &Path) {
+    let content = fs::read_to_string(file).unwrap();
+    let paths = update::parse_store_path_list(&content).unwrap();
+    let mut db = Database::open_exclusive(db_path).unwrap();
+    block_on(async move {
+        let id = update::add_paths_rec(&mut db, &cache_url, paths, &update::FetchConfig::default())
             .await
             .unwrap();
+        println!("{}", id);
     });
 }
 
-fn add_raw_channel() {
-    use nix_cache_mirror::database::model::*;
-    use std::convert::TryFrom;
+/// Resolve on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    use futures::{compat::Future01CompatExt as _, future::select};
+    use futures01::{Future as _, Stream as _};
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
 
-    let mut db = Database::open("./data/raw.sqlite").unwrap();
+    let signal = |sig| {
+        Signal::new(sig)
+            .flatten_stream()
+            .into_future()
+            .map_err(|(err, _)| err)
+            .compat()
+    };
+    let (ret, _) = select(signal(SIGINT), signal(SIGTERM)).await.factor_first();
+    if let Err(err) = ret {
+        log::error!("Cannot listen for signals: {}", err);
+        // Never shut down by signals.
+        futures::future::pending::<()>().await;
+    }
+}
+
+fn download(
+    db_path: &Path,
+    nar_file_dir: PathBuf,
+    cache_url: String,
+    root_id: Option<i64>,
+    concurrency: usize,
+) {
+    fs::create_dir_all(&nar_file_dir).unwrap();
+    let mut db = Database::open_exclusive(db_path).unwrap();
+    let config = update::DownloadConfig {
+        concurrency,
+        ..Default::default()
+    };
     block_on(async move {
-        let ids = update::add_root_rec(
-            &mut db,
-            &Root::default(),
-            "https://cache.nixos.org",
-            vec![
-                StorePath::try_from("/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10")
-                    .unwrap(),
-            ],
-        )
-        .await
-        .unwrap();
-        eprintln!("{:?}", ids);
+        let downloaded = match root_id {
+            Some(id) => update::download_closure(&mut db, id, &cache_url, &nar_file_dir, &config)
+                .await
+                .unwrap(),
+            None => update::download_nars(&mut db, &cache_url, &nar_file_dir, &config)
+                .await
+                .unwrap(),
+        };
+        println!("{}", downloaded);
     });
 }
 
-fn serve() {
-    let listen_addr = ([127, 0, 0, 1], 3000).into();
-    let db_path = Path::new("./data/simple.sqlite");
-    let nar_file_dir = Path::new("./data/nar").to_path_buf();
-    let want_mass_query = true;
-    let priority = Some(40);
+fn serve(opt: ServeOpt) {
+    let db_path = &*opt.db;
+    let (max_orphan_size, staged_ttl) = (opt.upload_max_orphan_size, opt.upload_staged_ttl);
+    let server_config = server::ServerConfig {
+        store_dir: opt.store_dir,
+        want_mass_query: opt.mass_query,
+        priority: if opt.no_priority {
+            None
+        } else {
+            Some(opt.priority)
+        },
+        nar_info_on_demand: if opt.narinfo_on_demand {
+            Some(db_path.to_owned())
+        } else {
+            None
+        },
+        upload: opt.upload_token.map(|token| server::UploadConfig {
+            token,
+            db_path: db_path.to_owned(),
+            max_orphan_size,
+            staged_ttl,
+        }),
+        ..Default::default()
+    };
+    let listen_config = server::ListenConfig {
+        idle_timeout: opt.idle_timeout,
+        tls: match (opt.tls_cert, opt.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(server::TlsConfig {
+                cert_path,
+                key_path,
+            }),
+            // Both or neither are required by the arguments.
+            _ => None,
+        },
+        ..Default::default()
+    };
+    let scheme = if listen_config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
 
     let server_data = Arc::new({
         let db = Database::open(db_path).unwrap();
         log::info!("Initializing data");
-        server::ServerData::init(&db, nar_file_dir, want_mass_query, priority).unwrap()
+        server::ServerData::init(&db, opt.nar_dir, &server_config).unwrap()
     });
 
-    log::info!("Listening on http://{}", listen_addr);
-
-    let server = Server::bind(&listen_addr).serve(move || {
-        let server_data = server_data.clone();
-        service_fn(move |req| server::serve(&server_data, req))
+    let listen_addr = opt.listen;
+    block_on(async move {
+        let handle = server::Server::bind(&listen_addr, server_data, &listen_config).unwrap();
+        log::info!("Listening on {}://{}", scheme, handle.local_addr());
+        handle.shutdown_on(shutdown_signal()).await.unwrap();
     });
-    block_on(async { server.compat().await.unwrap() });
 }
@@ -0,0 +1,116 @@
+use futures01::{Async, Poll};
+use hyper::{
+    body::{Body, Chunk, Payload},
+    HeaderMap,
+};
+use std::fmt;
+
+/// A response body counting bytes taken by the connection. `on_done` is called
+/// with the count when it's dropped, either finished or aborted.
+pub struct LoggedBody {
+    inner: Body,
+    sent: u64,
+    on_done: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, on_done: impl FnOnce(u64) + Send + 'static) -> Self {
+        Self {
+            inner,
+            sent: 0,
+            on_done: Some(Box::new(on_done)),
+        }
+    }
+}
+
+impl fmt::Debug for LoggedBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LoggedBody")
+            .field("inner", &self.inner)
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.sent);
+        }
+    }
+}
+
+impl Payload for LoggedBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let ret = self.inner.poll_data();
+        if let Ok(Async::Ready(Some(chunk))) = &ret {
+            self.sent += chunk.len() as u64;
+        }
+        ret
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+        self.inner.poll_trailers()
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    // Keep `Content-Length` of fixed bodies.
+    fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures01::{future::poll_fn, Future as _};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    fn logged(inner: Body) -> (LoggedBody, Arc<AtomicU64>) {
+        let done = Arc::new(AtomicU64::new(u64::MAX));
+        let done_ = done.clone();
+        let body = LoggedBody::new(inner, move |sent| done_.store(sent, Ordering::SeqCst));
+        (body, done)
+    }
+
+    #[test]
+    fn test_logged_body() {
+        let (mut body, done) = logged(Body::from("hello"));
+        assert_eq!(body.content_length(), Some(5));
+        while poll_fn(|| body.poll_data()).wait().unwrap().is_some() {}
+        assert_eq!(done.load(Ordering::SeqCst), u64::MAX);
+        drop(body);
+        assert_eq!(done.load(Ordering::SeqCst), 5);
+
+        // Aborted by the sender after a chunk.
+        let (mut tx, inner) = Body::channel();
+        let (mut body, done) = logged(inner);
+        tx.send_data(Chunk::from("abc")).unwrap();
+        assert_eq!(
+            poll_fn(|| body.poll_data())
+                .wait()
+                .unwrap()
+                .unwrap()
+                .as_ref(),
+            b"abc",
+        );
+        tx.abort();
+        assert!(poll_fn(|| body.poll_data()).wait().is_err());
+        drop(body);
+        assert_eq!(done.load(Ordering::SeqCst), 3);
+
+        // Dropped by the connection before sending anything.
+        let (body, done) = logged(Body::from("hello"));
+        drop(body);
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+    }
+}
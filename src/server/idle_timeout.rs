@@ -0,0 +1,82 @@
+use futures01::{Async, Future as _, Poll};
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    timer::Delay,
+};
+
+/// A connection which fails with `TimedOut` after being idle for `timeout`.
+/// Any successful read or write resets the timer.
+#[derive(Debug)]
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    timer: Option<Delay>,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            timer: None,
+        }
+    }
+
+    fn check<T>(&mut self, ret: io::Result<T>) -> io::Result<T> {
+        match &ret {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            _ => {
+                self.timer = None;
+                return ret;
+            }
+        }
+
+        if let Some(timeout) = self.timeout {
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+            match timer.poll() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Connection idle timeout",
+                    ))
+                }
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+        ret
+    }
+}
+
+impl<S: Read> Read for IdleTimeout<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = self.inner.read(buf);
+        self.check(ret)
+    }
+}
+
+impl<S: Write> Write for IdleTimeout<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ret = self.inner.write(buf);
+        self.check(ret)
+    }
+
+    // Flushing is not an activity, since hyper flushes on every poll.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for IdleTimeout<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for IdleTimeout<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of histogram buckets in seconds, excluding `+Inf`.
+const NAR_INFO_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1];
+const NAR_FILE_BUCKETS: &[f64] = &[0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Server metrics exposed at `/metrics` in Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    pub nar_info_duration: Histogram,
+    pub nar_file_duration: Histogram,
+    pub nar_info_hits: AtomicU64,
+    pub nar_info_misses: AtomicU64,
+    pub nar_file_bytes: AtomicU64,
+    pub not_found: AtomicU64,
+    pub nar_file_in_flight: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            nar_info_duration: Histogram::new(NAR_INFO_BUCKETS),
+            nar_file_duration: Histogram::new(NAR_FILE_BUCKETS),
+            nar_info_hits: AtomicU64::new(0),
+            nar_info_misses: AtomicU64::new(0),
+            nar_file_bytes: AtomicU64::new(0),
+            not_found: AtomicU64::new(0),
+            nar_file_in_flight: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Count a NAR file transfer as in flight until dropped.
+#[derive(Debug)]
+pub struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl<'a> InFlightGuard<'a> {
+    pub fn new(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_value(out: &mut String, name: &str, ty: &str, help: &str, values: &[(&str, u64)]) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+    for (labels, value) in values {
+        writeln!(out, "{}{} {}", name, labels, value).unwrap();
+    }
+}
+
+impl Metrics {
+    /// `cached_nars` is `None` while the cache is being rebuilt.
+    pub fn render(&self, cached_nars: Option<usize>) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        render_value(
+            &mut out,
+            "nix_cache_mirror_narinfo_requests_total",
+            "counter",
+            "Narinfo lookups by whether it's found.",
+            &[
+                ("{result=\"hit\"}", load(&self.nar_info_hits)),
+                ("{result=\"miss\"}", load(&self.nar_info_misses)),
+            ],
+        );
+        render_value(
+            &mut out,
+            "nix_cache_mirror_nar_sent_bytes_total",
+            "counter",
+            "Bytes of NAR files sent.",
+            &[("", load(&self.nar_file_bytes))],
+        );
+        render_value(
+            &mut out,
+            "nix_cache_mirror_not_found_total",
+            "counter",
+            "Responses with status 404.",
+            &[("", load(&self.not_found))],
+        );
+        render_value(
+            &mut out,
+            "nix_cache_mirror_nar_transfers_in_flight",
+            "gauge",
+            "NAR files being sent.",
+            &[("", load(&self.nar_file_in_flight))],
+        );
+        if let Some(cached_nars) = cached_nars {
+            render_value(
+                &mut out,
+                "nix_cache_mirror_cached_nars",
+                "gauge",
+                "Available NARs in the narinfo cache.",
+                &[("", cached_nars as u64)],
+            );
+        }
+        self.nar_info_duration.render(
+            &mut out,
+            "nix_cache_mirror_narinfo_duration_seconds",
+            "Time to answer narinfo requests.",
+        );
+        self.nar_file_duration.render(
+            &mut out,
+            "nix_cache_mirror_nar_transfer_duration_seconds",
+            "Time to completely send NAR files.",
+        );
+        out
+    }
+}
+
+/// A lock-free histogram with fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // Non-cumulative. The last one is for `+Inf`.
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut acc = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            acc += count.load(Ordering::Relaxed);
+            match self.bounds.get(i) {
+                Some(bound) => writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, acc),
+                None => writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, acc),
+            }
+            .unwrap();
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, acc).unwrap();
+    }
+}
@@ -1,50 +1,494 @@
-use crate::database::Database;
+use crate::{
+    database::{
+        model::{Hash, Nar, StorePath, StorePathHash},
+        Database,
+    },
+    logging::AccessEntry,
+    util::Semaphore,
+};
 use async_std;
+use chrono::{DateTime, Utc};
+use failure::Fail;
+use futures::{
+    channel::oneshot,
+    compat::{Future01CompatExt as _, Stream01CompatExt as _},
+    FutureExt as _, StreamExt as _, TryFutureExt as _,
+};
+use futures01::{sync::oneshot as oneshot01, Future as _, Stream as _};
 use hyper::{
     body::{Body, Chunk},
-    header, Method, StatusCode,
+    header,
+    server::conn::AddrIncoming,
+    service::service_fn,
+    Method, StatusCode,
 };
 use log;
-use std::{ops::Range, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::Range,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant},
+};
 
+mod access_log;
+mod idle_timeout;
+mod metrics;
 mod nar_info_cache;
-use self::nar_info_cache::NarInfoCache;
+mod nar_listing;
+mod root_cache;
+mod tls;
+mod upload;
+use self::{
+    access_log::LoggedBody,
+    idle_timeout::IdleTimeout,
+    metrics::{InFlightGuard, Metrics},
+    nar_info_cache::NarInfoCache,
+    root_cache::RootCache,
+    upload::Uploader,
+};
+pub use self::{tls::TlsConfig, upload::UploadConfig};
 
-const SEND_FILE_BUFFER_LEN: usize = 64 << 20; // 64 KiB
+const MAX_BATCH_BODY_LEN: usize = 4 << 20; // 4 MiB
+const PATHS_CHUNK_LEN: usize = 1024;
+const REBUILD_RETRY_AFTER_SECS: u32 = 5;
+// Threads generating `.ls` listings at the same time.
+const MAX_LISTING_THREADS: usize = 4;
 
 type Request = hyper::Request<Body>;
 type Response = hyper::Response<Body>;
 type TryResponse = hyper::Result<Response>;
 
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "{}", 0)]
+    Database(crate::database::Error),
+    #[fail(display = "Invalid NAR file directory '{}': {}", 0, 1)]
+    NarFileDir(String, std::io::Error),
+    #[fail(display = "Invalid nix-cache-info line '{}: {}'", 0, 1)]
+    CacheInfoLine(String, String),
+    #[fail(display = "Cannot bind: {}", 0)]
+    Bind(hyper::Error),
+    #[fail(display = "Invalid TLS certificate or key: {}", 0)]
+    Tls(String),
+}
+
+impl From<crate::database::Error> for Error {
+    fn from(err: crate::database::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// `StoreDir` of served store paths.
+    pub store_dir: String,
+    pub want_mass_query: bool,
+    pub priority: Option<i32>,
+    /// Extra `nix-cache-info` lines appended after generated ones.
+    /// Keys already present are ignored.
+    pub extra_cache_info: Vec<(String, String)>,
+    /// Buffer size for reading each NAR file being sent.
+    pub send_file_buffer_len: usize,
+    /// Render narinfos on demand from a separate connection to the database
+    /// at this path, instead of keeping all of them in memory.
+    pub nar_info_on_demand: Option<PathBuf>,
+    /// Accept narinfos and NAR files uploaded by `PUT`, if set.
+    pub upload: Option<UploadConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            store_dir: StorePath::DEFAULT_STORE_DIR.to_owned(),
+            want_mass_query: false,
+            priority: None,
+            extra_cache_info: vec![],
+            send_file_buffer_len: 128 << 10, // 128 KiB
+            nar_info_on_demand: None,
+            upload: None,
+        }
+    }
+}
+
 pub struct ServerData {
-    nar_info_cache: NarInfoCache,
+    // `None` while rebuilding.
+    nar_info_cache: RwLock<Option<Arc<NarInfoCache>>>,
+    root_cache: RwLock<Option<Arc<RootCache>>>,
     nar_file_dir: PathBuf,
     nix_cache_info: String,
+    store_ping: String,
+    send_file_buffer_len: usize,
+    nar_info_on_demand: Option<PathBuf>,
+    uploader: Option<Uploader>,
+    listing_sem: Semaphore,
+    // Effective configuration, reported at `/api/config`.
+    config: String,
+    metrics: Arc<Metrics>,
 }
 
 impl ServerData {
     pub fn init(
         db: &Database,
         nar_file_dir: PathBuf,
-        want_mass_query: bool,
-        priority: Option<i32>,
-    ) -> Result<Self, crate::database::Error> {
+        config: &ServerConfig,
+    ) -> Result<Self, Error> {
         use std::fmt::Write;
 
-        let mut nix_cache_info = "StoreDir: /nix/store\n".to_owned();
-        if want_mass_query {
+        // Fail early rather than on every NAR request.
+        if let Err(err) = std::fs::read_dir(&nar_file_dir) {
+            return Err(Error::NarFileDir(nar_file_dir.display().to_string(), err));
+        }
+
+        let store_dir = &*config.store_dir;
+        // Nix rejects relative ones, and paths in narinfos are joined with `/`.
+        if !StorePath::is_valid_store_dir(store_dir) || store_dir.contains(&['\n', '\r'][..]) {
+            return Err(Error::CacheInfoLine(
+                "StoreDir".to_owned(),
+                store_dir.to_owned(),
+            ));
+        }
+        let mut nix_cache_info = format!("StoreDir: {}\n", store_dir);
+        let mut store_ping = serde_json::Map::new();
+        store_ping.insert("StoreDir".to_owned(), store_dir.into());
+        if config.want_mass_query {
             write!(&mut nix_cache_info, "WantMassQuery: 1\n").unwrap();
+            store_ping.insert("WantMassQuery".to_owned(), 1.into());
         }
-        if let Some(priority) = priority {
+        if let Some(priority) = config.priority {
             write!(&mut nix_cache_info, "Priority: {}\n", priority).unwrap();
+            store_ping.insert("Priority".to_owned(), priority.into());
+        }
+        for (key, value) in &config.extra_cache_info {
+            if key.is_empty()
+                || key.contains(|c: char| c == ':' || c.is_whitespace())
+                || value.contains(&['\n', '\r'][..])
+            {
+                return Err(Error::CacheInfoLine(key.clone(), value.clone()));
+            }
+            if store_ping.contains_key(key) {
+                log::warn!("Ignored duplicated nix-cache-info key '{}'", key);
+                continue;
+            }
+            writeln!(&mut nix_cache_info, "{}: {}", key, value).unwrap();
+            store_ping.insert(key.clone(), value.clone().into());
         }
 
+        let uploader = match &config.upload {
+            Some(upload) => Some(Uploader::new(upload, store_dir, &nar_file_dir)?),
+            None => None,
+        };
+
         Ok(Self {
-            nar_info_cache: NarInfoCache::init(db)?,
+            nar_info_cache: RwLock::new(Some(Arc::new(NarInfoCache::init(
+                db,
+                config.nar_info_on_demand.as_deref(),
+            )?))),
+            root_cache: RwLock::new(Some(Arc::new(RootCache::init(db)?))),
+            config: serde_json::json!({
+                "store_dir": store_dir,
+                "want_mass_query": config.want_mass_query,
+                "priority": config.priority,
+                "nar_file_dir": nar_file_dir,
+                "upload": uploader.is_some(),
+            })
+            .to_string(),
             nar_file_dir,
             nix_cache_info,
+            store_ping: serde_json::Value::from(store_ping).to_string(),
+            send_file_buffer_len: config.send_file_buffer_len.max(1),
+            nar_info_on_demand: config.nar_info_on_demand.clone(),
+            uploader,
+            listing_sem: Semaphore::new(MAX_LISTING_THREADS),
+            metrics: Default::default(),
+        })
+    }
+
+    /// Rebuild the narinfo and root caches from database and swap them in.
+    /// In-flight requests keep using the old ones.
+    pub fn reload(&self, db: &Database) -> Result<(), crate::database::Error> {
+        let cache = Arc::new(NarInfoCache::init(db, self.nar_info_on_demand.as_deref())?);
+        let roots = Arc::new(RootCache::init(db)?);
+        *self.nar_info_cache.write().unwrap() = Some(cache);
+        *self.root_cache.write().unwrap() = Some(roots);
+        Ok(())
+    }
+
+    /// Pick up NARs which became available or unavailable since the last
+    /// load, without rebuilding caches. Return the number of new NARs.
+    pub fn refresh_from(&self, db: &Database) -> Result<usize, crate::database::Error> {
+        loop {
+            let old = match self.nar_info_cache() {
+                Some(cache) => cache,
+                None => return Ok(0),
+            };
+            let changes = old.select_changes(db)?;
+            if changes.is_empty() {
+                return Ok(0);
+            }
+            let mut new = NarInfoCache::clone(&old);
+            let count = new.apply_changes(changes);
+            if self.swap_nar_info_cache(&old, new) {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Stop serving a NAR. Return whether it was served.
+    pub fn remove_nar(&self, hash: &str) -> bool {
+        loop {
+            let old = match self.nar_info_cache() {
+                Some(cache) if cache.get_file(hash).is_some() => cache,
+                _ => return false,
+            };
+            let mut new = NarInfoCache::clone(&old);
+            new.remove(hash);
+            if self.swap_nar_info_cache(&old, new) {
+                return true;
+            }
+        }
+    }
+
+    /// Start serving an available NAR with row `id`.
+    fn insert_nar(&self, id: i64, nar: &Nar) {
+        while let Some(old) = self.nar_info_cache() {
+            let mut new = NarInfoCache::clone(&old);
+            new.insert_available(id, nar);
+            if self.swap_nar_info_cache(&old, new) {
+                break;
+            }
+        }
+    }
+
+    /// Replace the narinfo cache by `new` if it's still `old`. Callers update
+    /// a copy without the lock, so requests are only blocked by the swap, and
+    /// retry if it's changed meanwhile.
+    fn swap_nar_info_cache(&self, old: &Arc<NarInfoCache>, new: NarInfoCache) -> bool {
+        let mut cache = self.nar_info_cache.write().unwrap();
+        match &*cache {
+            Some(cur) if Arc::ptr_eq(cur, old) => {
+                *cache = Some(Arc::new(new));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop the old caches before rebuilding them, to keep peak memory low.
+    /// Requests needing them get 503 with `Retry-After` until it's done.
+    /// Caches are left empty if it fails, and can be restored by `reload`.
+    pub fn rebuild(&self, db: &Database) -> Result<(), crate::database::Error> {
+        *self.nar_info_cache.write().unwrap() = None;
+        *self.root_cache.write().unwrap() = None;
+        self.reload(db)
+    }
+
+    fn nar_info_cache(&self) -> Option<Arc<NarInfoCache>> {
+        self.nar_info_cache.read().unwrap().clone()
+    }
+
+    fn root_cache(&self) -> Option<Arc<RootCache>> {
+        self.root_cache.read().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    /// Whether to keep HTTP/1 connections alive between requests.
+    pub keep_alive: bool,
+    /// Close connections idle for longer than this. `None` never closes them.
+    pub idle_timeout: Option<Duration>,
+    /// Serve HTTPS instead of plain HTTP if set.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            idle_timeout: None,
+            tls: None,
+        }
+    }
+}
+
+/// URI scheme of the listener which accepted a request, stored in its
+/// extensions. Requests without it are taken as plain HTTP.
+#[derive(Debug, Clone, Copy)]
+struct Scheme(&'static str);
+
+pub struct Server;
+
+impl Server {
+    /// Bind to `addr` and spawn the server onto the current runtime.
+    pub fn bind(
+        addr: &SocketAddr,
+        data: Arc<ServerData>,
+        config: &ListenConfig,
+    ) -> Result<ServerHandle, Error> {
+        let incoming = AddrIncoming::bind(addr).map_err(Error::Bind)?;
+        let local_addr = incoming.local_addr();
+        let idle_timeout = config.idle_timeout;
+        // Idle connections are closed during TLS handshakes as well.
+        let incoming = incoming.map(move |conn| IdleTimeout::new(conn, idle_timeout));
+
+        Ok(match &config.tls {
+            None => Self::spawn(incoming, local_addr, data, config),
+            Some(tls) => {
+                let incoming = tls::accept(incoming, tls.acceptor()?);
+                Self::spawn(incoming, local_addr, data, config)
+            }
         })
     }
+
+    fn spawn<I>(
+        incoming: I,
+        local_addr: SocketAddr,
+        data: Arc<ServerData>,
+        config: &ListenConfig,
+    ) -> ServerHandle
+    where
+        I: futures01::Stream<Error = std::io::Error> + Send + 'static,
+        I::Item: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+    {
+        let (shutdown_tx, shutdown_rx) = oneshot01::channel();
+        let scheme = Scheme(if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        });
+        let server = hyper::Server::builder(incoming)
+            .http1_keepalive(config.keep_alive)
+            .serve(move || {
+                let data = data.clone();
+                service_fn(move |mut req: Request| {
+                    req.extensions_mut().insert(scheme);
+                    serve_logged(data.clone(), req).boxed().compat()
+                })
+            })
+            .with_graceful_shutdown(shutdown_rx.then(|_| Ok::<_, ()>(())));
+
+        let (done_tx, done_rx) = oneshot::channel();
+        crate::spawn(async move {
+            let _ = done_tx.send(server.compat().await);
+        });
+
+        ServerHandle {
+            local_addr,
+            shutdown_tx,
+            done_rx,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: oneshot01::Sender<()>,
+    done_rx: oneshot::Receiver<hyper::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The actual bound address, useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait until the server stops by itself.
+    pub async fn join(self) -> hyper::Result<()> {
+        let Self {
+            shutdown_tx,
+            done_rx,
+            ..
+        } = self;
+        let ret = done_rx.await.expect("Server panicked");
+        drop(shutdown_tx);
+        ret
+    }
+
+    /// Stop accepting new connections and wait for in-flight ones to finish.
+    /// Responses being sent, including NAR files, are completed first.
+    pub async fn shutdown(self) -> hyper::Result<()> {
+        let _ = self.shutdown_tx.send(());
+        self.done_rx.await.expect("Server panicked")
+    }
+
+    /// Run until `signal` resolves, then shut down as `shutdown` does.
+    pub async fn shutdown_on(
+        self,
+        signal: impl std::future::Future<Output = ()>,
+    ) -> hyper::Result<()> {
+        use futures::future::{select, Either};
+
+        let Self {
+            shutdown_tx,
+            mut done_rx,
+            ..
+        } = self;
+        futures::pin_mut!(signal);
+        match select(signal, &mut done_rx).await {
+            Either::Left(((), _)) => {
+                log::info!("Shutting down, waiting for in-flight requests");
+                let _ = shutdown_tx.send(());
+                done_rx.await.expect("Server panicked")
+            }
+            Either::Right((ret, _)) => ret.expect("Server panicked"),
+        }
+    }
+}
+
+/// Dispatch on the request method by arms of `METHOD | .. => handler`.
+/// Other methods get 405 with `Allow` listing exactly the handled ones.
+/// Arms with a guard are only allowed, and listed in `Allow`, if it holds.
+macro_rules! by_method {
+    (match $method:ident { $($($m:ident)|+ $(if $guard:expr)? => $handler:expr),+ $(,)? }) => {
+        match *$method {
+            $($(Method::$m)|+ $(if $guard)? => $handler,)+
+            _ => {
+                let mut allowed = Vec::new();
+                $(
+                    if true $(&& $guard)? {
+                        allowed.extend_from_slice(&[$(Method::$m),+]);
+                    }
+                )+
+                Ok(method_not_allowed(&allowed))
+            }
+        }
+    };
+}
+
+/// Unwrap a cache, or answer 503 if it's being rebuilt.
+macro_rules! get_cache {
+    ($cache:expr) => {
+        match $cache {
+            Some(cache) => cache,
+            None => return Ok(rebuilding_response()),
+        }
+    };
+}
+
+fn rebuilding_response() -> Response {
+    let mut resp = simple_response(StatusCode::SERVICE_UNAVAILABLE, "Rebuilding cache");
+    resp.headers_mut().insert(
+        header::RETRY_AFTER,
+        header::HeaderValue::from(REBUILD_RETRY_AFTER_SECS),
+    );
+    resp
+}
+
+fn method_not_allowed(allowed: &[Method]) -> Response {
+    let allow = allowed
+        .iter()
+        .map(|m| m.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut resp = simple_response(StatusCode::METHOD_NOT_ALLOWED, "");
+    resp.headers_mut().insert(
+        header::ALLOW,
+        header::HeaderValue::from_str(&allow).unwrap(),
+    );
+    resp
 }
 
 fn simple_response(status: StatusCode, body: &'static str) -> Response {
@@ -53,90 +497,609 @@ fn simple_response(status: StatusCode, body: &'static str) -> Response {
     resp
 }
 
-pub fn serve<'a>(data: &ServerData, req: Request) -> TryResponse {
-    let method = req.method();
-    match req.uri().path() {
+/// Log each request after its body is completely sent or aborted.
+async fn serve_logged(
+    data: Arc<ServerData>,
+    req: Request,
+) -> hyper::Result<hyper::Response<LoggedBody>> {
+    let start = Instant::now();
+    let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+    let resp = serve(data, req).await?;
+    let status = resp.status().as_u16();
+    Ok(resp.map(move |body| {
+        LoggedBody::new(body, move |bytes| {
+            AccessEntry {
+                method: method.as_str(),
+                path: &path,
+                status,
+                bytes,
+                duration: start.elapsed(),
+            }
+            .log()
+        })
+    }))
+}
+
+pub async fn serve(data: Arc<ServerData>, req: Request) -> TryResponse {
+    let ret = route(&data, req).await;
+    if let Ok(resp) = &ret {
+        if resp.status() == StatusCode::NOT_FOUND {
+            data.metrics.not_found.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    ret
+}
+
+async fn route(data: &ServerData, req: Request) -> TryResponse {
+    let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+    let method = &method;
+    match &*path {
         "/" => Ok(simple_response(StatusCode::OK, "It works")),
 
-        "/nix-cache-info" => match method {
-            &Method::GET => Ok(Response::new(Body::from(data.nix_cache_info.clone()))),
-            _ => Ok(simple_response(StatusCode::METHOD_NOT_ALLOWED, "")),
-        },
+        "/nix-cache-info" => by_method!(match method {
+            GET => {
+                let mut resp = Response::new(Body::from(data.nix_cache_info.clone()));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/x-nix-cache-info"),
+                );
+                Ok(resp)
+            }
+        }),
 
-        s if s.starts_with("/nar/") => match method {
-            &Method::GET | &Method::HEAD => {
-                let hash = &s["/nar/".len()..];
-                serve_nar_file(data, &req, hash, method == &Method::HEAD)
+        // The same information as `nix-cache-info`, in JSON.
+        "/nix/store/ping" => by_method!(match method {
+            GET => {
+                let mut resp = Response::new(Body::from(data.store_ping.clone()));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("application/json"),
+                );
+                Ok(resp)
             }
-            _ => Ok(simple_response(StatusCode::METHOD_NOT_ALLOWED, "")),
-        },
+        }),
+
+        s if s.starts_with("/nar/") => {
+            let name = &s["/nar/".len()..];
+            let uploads = data.uploader.is_some();
+            by_method!(match method {
+                PUT if uploads => upload::put_nar_file(data, req, name).await,
+                DELETE if uploads => upload::delete_nar(data, req, name).await,
+                GET | HEAD => serve_nar_file(data, &req, name, *method == Method::HEAD),
+            })
+        }
 
-        s if !s[1..].contains('/') && s.ends_with(".narinfo") => match method {
-            &Method::GET => {
-                let hash = &s[1..s.len() - ".narinfo".len()];
-                serve_nar_info(data, &req, hash)
+        "/paths" => by_method!(match method {
+            GET | HEAD => serve_paths(data, &req, *method == Method::HEAD),
+        }),
+
+        "/metrics" => by_method!(match method {
+            GET => {
+                let cached_nars = data.nar_info_cache().map(|cache| cache.len());
+                let mut resp = Response::new(Body::from(data.metrics.render(cached_nars)));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                Ok(resp)
             }
-            _ => Ok(simple_response(StatusCode::METHOD_NOT_ALLOWED, "")),
-        },
+        }),
+
+        "/api/config" => by_method!(match method {
+            GET => {
+                let mut resp = Response::new(Body::from(data.config.clone()));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("application/json"),
+                );
+                Ok(resp)
+            }
+        }),
+
+        s if s.starts_with("/api/roots/") && s.ends_with("/paths") => by_method!(match method {
+            GET => {
+                let id = &s["/api/roots/".len()..s.len() - "/paths".len()];
+                serve_root_paths(data, id)
+            }
+        }),
+
+        s if s.starts_with("/api/nar/") => by_method!(match method {
+            GET => serve_nar_meta(data, &s["/api/nar/".len()..]),
+        }),
+
+        "/api/narinfo-batch" => by_method!(match method {
+            POST => serve_nar_info_batch(data, req).await,
+        }),
+
+        s if s.starts_with("/channels/") => by_method!(match method {
+            GET => serve_channel_file(data, &req, &s["/channels/".len()..]),
+        }),
+
+        s if !s[1..].contains('/') && s.ends_with(".narinfo") => {
+            let hash = &s[1..s.len() - ".narinfo".len()];
+            if !StorePathHash::is_valid(hash) {
+                return Ok(simple_response(StatusCode::NOT_FOUND, "Not found"));
+            }
+            let uploads = data.uploader.is_some();
+            by_method!(match method {
+                PUT if uploads => upload::put_nar_info(data, req, hash).await,
+                DELETE if uploads => upload::delete_nar(data, req, hash).await,
+                GET | HEAD => {
+                    let start = Instant::now();
+                    let ret = serve_nar_info(data, &req, hash, *method == Method::HEAD);
+                    data.metrics.nar_info_duration.observe(start.elapsed());
+                    ret
+                }
+            })
+        }
+
+        s if !s[1..].contains('/') && s.ends_with(".ls") => by_method!(match method {
+            GET => serve_nar_listing(data, &s[1..s.len() - ".ls".len()]).await,
+        }),
 
         _ => Ok(simple_response(StatusCode::NOT_FOUND, "Not found")),
     }
 }
 
-fn serve_nar_info(data: &ServerData, _req: &Request, hash: &str) -> TryResponse {
+/// `HEAD` is used by Nix to check existence. It gets the same headers without body.
+fn serve_nar_info(data: &ServerData, req: &Request, hash: &str, head_only: bool) -> TryResponse {
     log::debug!("Get nar info: {}", hash);
-    Ok(match data.nar_info_cache.get_info(hash) {
+    Ok(match get_cache!(data.nar_info_cache()).get_info(hash) {
         Some(info) => {
-            let mut resp = Response::new(Body::from(info.to_owned()));
+            data.metrics.nar_info_hits.fetch_add(1, Ordering::Relaxed);
+            let etag = nar_info_etag(&info);
+            if if_none_match(req, &etag) {
+                return Ok(not_modified(etag));
+            }
+            let len = info.len() as u64;
+            let body = if head_only {
+                Body::empty()
+            } else {
+                Body::from(info.into_owned())
+            };
+            let mut resp = Response::new(body);
             resp.headers_mut().insert(
                 header::CONTENT_TYPE,
                 header::HeaderValue::from_static("text/x-nix-narinfo"),
             );
+            resp.headers_mut()
+                .insert(header::CONTENT_LENGTH, header::HeaderValue::from(len));
+            resp.headers_mut()
+                .insert(header::ETAG, header::HeaderValue::from_str(&etag).unwrap());
             resp
         }
-        None => simple_response(StatusCode::NOT_FOUND, "Not found"),
+        None => {
+            data.metrics.nar_info_misses.fetch_add(1, Ordering::Relaxed);
+            if head_only {
+                simple_response(StatusCode::NOT_FOUND, "")
+            } else {
+                simple_response(StatusCode::NOT_FOUND, "Not found")
+            }
+        }
     })
 }
 
-fn parse_content_range(req: &Request, file_size: u64) -> Option<Range<u64>> {
-    let s = req.headers().get(header::RANGE)?;
-    let s = s.to_str().ok()?;
-    if !s.starts_with("bytes=") {
-        return None;
+/// Narinfos never change once available, so the hash of the text is stable.
+fn nar_info_etag(info: &str) -> String {
+    use ring::digest::{digest, SHA256};
+
+    let hash = digest(&SHA256, info.as_bytes());
+    let hex: String = hash.as_ref()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// The NAR file is identified by its `NarHash`, which never contains quotes.
+fn nar_file_etag(nar_hash: &Hash) -> String {
+    format!("\"{}\"", nar_hash)
+}
+
+/// Whether `If-None-Match` matches `etag`, by weak comparison.
+fn if_none_match(req: &Request, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| {
+            let tag = tag.trim();
+            tag.strip_prefix("W/").unwrap_or(tag)
+        })
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Format a time as an HTTP-date, in seconds.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether `If-Modified-Since` is missing, invalid, or earlier than `http_date`.
+fn if_modified_since(req: &Request, http_date: &str) -> bool {
+    let parse = |s: &str| DateTime::parse_from_rfc2822(s).ok();
+    let since = req
+        .headers()
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse);
+    match (since, parse(http_date)) {
+        (Some(since), Some(time)) => time > since,
+        _ => true,
     }
-    let s = &s["bytes=".len()..];
+}
 
-    let sep = s.find('-')?;
-    let end = s[sep + 1..].find(',').unwrap_or(s.len());
+fn not_modified(etag: String) -> Response {
+    let mut resp = simple_response(StatusCode::NOT_MODIFIED, "");
+    resp.headers_mut()
+        .insert(header::ETAG, header::HeaderValue::from_str(&etag).unwrap());
+    resp
+}
 
-    let lhs = s[..sep].parse::<u64>().ok()?.checked_sub(1)?;
-    if sep + 1 == s.len() {
-        if lhs < file_size {
-            return Some(lhs..file_size);
-        }
+/// Newline separated store paths of all available NARs. The body is
+/// generated lazily in chunks from the cache snapshot.
+fn serve_paths(data: &ServerData, req: &Request, head_only: bool) -> TryResponse {
+    let gzip = accepts_gzip(req);
+    let body = if head_only {
+        Body::empty()
     } else {
-        let rhs = s[sep + 1..end].parse::<u64>().ok()?;
-        if lhs <= rhs && rhs < file_size {
-            return Some(lhs..rhs);
+        let cache = get_cache!(data.nar_info_cache());
+        let chunks = (0..).step_by(PATHS_CHUNK_LEN).map(move |start| {
+            let mut chunk = String::new();
+            for path in cache.store_paths(start..start + PATHS_CHUNK_LEN) {
+                chunk.push_str(path);
+                chunk.push('\n');
+            }
+            chunk.into_bytes()
+        });
+        let chunks = chunks.take_while(|chunk| !chunk.is_empty());
+        if gzip {
+            Body::wrap_stream(futures01::stream::iter_ok::<_, std::io::Error>(
+                gzip_chunks(chunks),
+            ))
+        } else {
+            Body::wrap_stream(futures01::stream::iter_ok::<_, std::io::Error>(chunks))
         }
+    };
+    let mut resp = Response::new(body);
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain"),
+    );
+    resp.headers_mut().insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept-Encoding"),
+    );
+    if gzip {
+        resp.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static("gzip"),
+        );
+    }
+    Ok(resp)
+}
+
+/// Whether `Accept-Encoding` allows gzip, without `q=0`.
+fn accepts_gzip(req: &Request) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            parts.next() == Some("gzip")
+                && parts.all(|param| {
+                    !param.starts_with("q=") || param[2..].parse::<f32>().is_ok_and(|q| q > 0.0)
+                })
+        })
+}
+
+/// Compress chunks lazily as a single gzip stream.
+fn gzip_chunks(chunks: impl Iterator<Item = Vec<u8>>) -> impl Iterator<Item = Vec<u8>> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut chunks = chunks.fuse();
+    let mut enc = Some(GzEncoder::new(Vec::new(), Compression::default()));
+    std::iter::from_fn(move || loop {
+        let cur = enc.as_mut()?;
+        match chunks.next() {
+            // Writing into `Vec` never fails.
+            Some(chunk) => {
+                cur.write_all(&chunk).unwrap();
+                if !cur.get_ref().is_empty() {
+                    return Some(std::mem::take(cur.get_mut()));
+                }
+            }
+            None => return Some(enc.take().unwrap().finish().unwrap()),
+        }
+    })
+}
+
+/// Files of a Nix channel generated from an available root, so that this
+/// mirror can be used with `nix-channel` directly.
+/// `binary-cache-url` points back to this server, with the scheme it's
+/// listening on.
+fn serve_channel_file(data: &ServerData, req: &Request, path: &str) -> TryResponse {
+    log::debug!("Get channel file: {}", path);
+    let not_found = || Ok(simple_response(StatusCode::NOT_FOUND, "Not found"));
+
+    let sep = match path.find('/') {
+        Some(sep) => sep,
+        None => return not_found(),
+    };
+    let roots = get_cache!(data.root_cache());
+    let channel = match path[..sep]
+        .parse()
+        .ok()
+        .and_then(|id| roots.get_channel(id))
+    {
+        Some(channel) => channel,
+        None => return not_found(),
+    };
+
+    let (body, content_type) = match &path[sep + 1..] {
+        "store-paths.xz" => (
+            Body::from(channel.store_paths_xz.clone().unwrap()),
+            "application/x-xz",
+        ),
+        "git-revision" => match &channel.git_revision {
+            Some(rev) => (Body::from(rev.clone()), "text/plain"),
+            None => return not_found(),
+        },
+        "binary-cache-url" => match req.headers().get(header::HOST) {
+            Some(host) => match host.to_str() {
+                Ok(host) => {
+                    let Scheme(scheme) = req.extensions().get().copied().unwrap_or(Scheme("http"));
+                    (Body::from(format!("{}://{}", scheme, host)), "text/plain")
+                }
+                Err(_) => return Ok(simple_response(StatusCode::BAD_REQUEST, "Invalid Host")),
+            },
+            None => return Ok(simple_response(StatusCode::BAD_REQUEST, "Missing Host")),
+        },
+        _ => return not_found(),
+    };
+    let mut resp = Response::new(body);
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(content_type),
+    );
+    Ok(resp)
+}
+
+/// Non-standard API. Top-level store paths of a root as a JSON array,
+/// without following references.
+fn serve_root_paths(data: &ServerData, id: &str) -> TryResponse {
+    log::debug!("Get root paths: {}", id);
+    let roots = get_cache!(data.root_cache());
+    let root = match id.parse().ok().and_then(|id| roots.get(id)) {
+        Some(root) => root,
+        None => return Ok(simple_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let mut resp = Response::new(Body::from(serde_json::to_string(&root.paths).unwrap()));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    Ok(resp)
+}
+
+/// Non-standard API. Metadata of an available NAR as JSON, with whether its
+/// file is actually present in `nar_file_dir`.
+fn serve_nar_meta(data: &ServerData, hash: &str) -> TryResponse {
+    use crate::database::model::Nar;
+
+    log::debug!("Get nar meta: {}", hash);
+    let cache = get_cache!(data.nar_info_cache());
+    let nar = match cache.get_info(hash).map(|info| Nar::parse_nar_info(&info)) {
+        Some(Ok(nar)) => nar,
+        _ => return Ok(simple_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let file_present = std::fs::metadata(data.nar_file_dir.join(hash))
+        .map(|meta| meta.is_file())
+        .unwrap_or(false);
+    let meta = &nar.meta;
+    let body = serde_json::json!({
+        "store_path": nar.store_path.path(),
+        "url": meta.url,
+        "compression": meta.compression,
+        "file_hash": meta.file_hash,
+        "file_size": meta.file_size,
+        "nar_hash": meta.nar_hash,
+        "nar_size": meta.nar_size,
+        "deriver": meta.deriver,
+        "sig": meta.sig,
+        "ca": meta.ca,
+        "references": nar.references.split_whitespace().collect::<Vec<_>>(),
+        "file_present": file_present,
+    });
+    let mut resp = Response::new(Body::from(body.to_string()));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    Ok(resp)
+}
+
+/// Files inside an available NAR as JSON, generated from its file in
+/// `nar_file_dir` on each request.
+async fn serve_nar_listing(data: &ServerData, hash: &str) -> TryResponse {
+    use crate::database::model::Nar;
+
+    log::debug!("Get nar listing: {}", hash);
+    let not_found = || Ok(simple_response(StatusCode::NOT_FOUND, "Not found"));
+    if !StorePathHash::is_valid(hash) {
+        return not_found();
+    }
+    let cache = get_cache!(data.nar_info_cache());
+    let nar = match cache.get_info(hash).map(|info| Nar::parse_nar_info(&info)) {
+        Some(Ok(nar)) => nar,
+        _ => return not_found(),
+    };
+
+    // Decompressing a large NAR takes a while. Don't block other requests.
+    let _guard = data.listing_sem.acquire().await;
+    let path = data.nar_file_dir.join(hash);
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(nar_listing::list_nar_file(
+            &path,
+            nar.meta.compression.as_deref(),
+        ));
+    });
+    match rx.await.expect("Listing thread panicked") {
+        Ok(Some(listing)) => {
+            let mut resp = Response::new(Body::from(listing.to_string()));
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
+            Ok(resp)
+        }
+        Ok(None) => not_found(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => not_found(),
+        Err(err) => {
+            log::error!("Failed to list NAR file of {}: {}", hash, err);
+            Ok(simple_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Invalid NAR file",
+            ))
+        }
+    }
+}
+
+/// Non-standard API. Request body is a JSON array of hashes,
+/// response is a JSON object mapping each hash to its narinfo, or `null` if not found.
+async fn serve_nar_info_batch(data: &ServerData, req: Request) -> TryResponse {
+    let mut body = req.into_body().compat();
+    let mut buf = vec![];
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > MAX_BATCH_BODY_LEN {
+            return Ok(simple_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body too large",
+            ));
+        }
+    }
+
+    let hashes: Vec<String> = match serde_json::from_slice(&buf) {
+        Ok(hashes) => hashes,
+        Err(_) => {
+            return Ok(simple_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid hash list",
+            ))
+        }
+    };
+    log::debug!("Get nar info batch: {} hashes", hashes.len());
+
+    let cache = get_cache!(data.nar_info_cache());
+    let infos: HashMap<&str, Option<std::borrow::Cow<str>>> = hashes
+        .iter()
+        .map(|hash| (&**hash, cache.get_info(hash)))
+        .collect();
+    let mut resp = Response::new(Body::from(serde_json::to_string(&infos).unwrap()));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    Ok(resp)
+}
+
+/// The `Range` header of a request.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeResult {
+    /// No `Range`, or it's invalid and should be ignored.
+    None,
+    Satisfiable(Range<u64>),
+    Unsatisfiable,
+}
+
+fn parse_content_range(req: &Request, file_size: u64) -> RangeResult {
+    let parse = || -> Option<Option<Range<u64>>> {
+        let s = req.headers().get(header::RANGE)?;
+        let s = s.to_str().ok()?;
+        if !s.starts_with("bytes=") {
+            return None;
+        }
+        // Only the first one of multiple ranges is served.
+        let s = s["bytes=".len()..].split(',').next()?.trim();
+
+        // Positions are 0-indexed and `last` is inclusive.
+        let sep = s.find('-')?;
+        let range = if sep == 0 {
+            // Suffix range of the last `len` bytes, or the whole file if it's shorter.
+            let len = s[1..].parse::<u64>().ok()?;
+            file_size.saturating_sub(len)..file_size
+        } else {
+            let first = s[..sep].parse::<u64>().ok()?;
+            let end = match &s[sep + 1..] {
+                "" => file_size,
+                last => {
+                    let last = last.parse::<u64>().ok()?;
+                    if last < first {
+                        return None;
+                    }
+                    last.saturating_add(1).min(file_size)
+                }
+            };
+            first..end
+        };
+        Some(Some(range).filter(|range| range.start < range.end))
+    };
+    match parse() {
+        None => RangeResult::None,
+        Some(Some(range)) => RangeResult::Satisfiable(range),
+        Some(None) => RangeResult::Unsatisfiable,
     }
-    None
 }
 
 fn serve_nar_file(data: &ServerData, req: &Request, hash: &str, head_only: bool) -> TryResponse {
     use futures::TryFutureExt;
 
     log::debug!("Get nar file: {}", hash);
-    let file_size = match data.nar_info_cache.get_file_size(hash) {
-        Some(file_size) => file_size,
+    let cache = get_cache!(data.nar_info_cache());
+    let (file_size, content_type, etag) = match cache.get_file(hash) {
+        Some((file_size, content_type, nar_hash)) => {
+            (file_size, content_type, nar_file_etag(nar_hash))
+        }
         None => return Ok(simple_response(StatusCode::NOT_FOUND, "Not found")),
     };
+    let path = data.nar_file_dir.join(hash);
+    let last_modified = std::fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .map(|time| http_date(time.into()));
+    // `If-Modified-Since` is ignored when `If-None-Match` is present.
+    let fresh = if req.headers().contains_key(header::IF_NONE_MATCH) {
+        if_none_match(req, &etag)
+    } else {
+        last_modified
+            .as_ref()
+            .is_some_and(|time| !if_modified_since(req, time))
+    };
 
     let (tx, body) = Body::channel();
     let mut resp = Response::new(body);
+    resp.headers_mut()
+        .insert(header::ETAG, header::HeaderValue::from_str(&etag).unwrap());
+    if let Some(time) = last_modified {
+        resp.headers_mut().insert(
+            header::LAST_MODIFIED,
+            header::HeaderValue::from_str(&time).unwrap(),
+        );
+    }
+    // No body, nor range headers.
+    if fresh {
+        *resp.body_mut() = Body::empty();
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(resp);
+    }
     resp.headers_mut().insert(
         header::CONTENT_TYPE,
-        header::HeaderValue::from_static("application/x-nix-nar"),
+        header::HeaderValue::from_static(content_type),
     );
     resp.headers_mut().insert(
         header::ACCEPT_RANGES,
@@ -144,15 +1107,24 @@ fn serve_nar_file(data: &ServerData, req: &Request, hash: &str, head_only: bool)
     );
 
     let range = match parse_content_range(req, file_size) {
-        None => 0..file_size,
-        Some(range) => {
+        RangeResult::None => 0..file_size,
+        RangeResult::Unsatisfiable => {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            resp.headers_mut().insert(
+                header::CONTENT_RANGE,
+                header::HeaderValue::from_str(&format!("bytes */{}", file_size)).unwrap(),
+            );
+            return Ok(resp);
+        }
+        RangeResult::Satisfiable(range) => {
             *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
             resp.headers_mut().insert(
                 header::CONTENT_RANGE,
                 header::HeaderValue::from_str(&format!(
                     "bytes {}-{}/{}",
-                    range.start + 1,
-                    range.end,
+                    range.start,
+                    range.end - 1,
                     file_size,
                 ))
                 .unwrap(),
@@ -161,16 +1133,27 @@ fn serve_nar_file(data: &ServerData, req: &Request, hash: &str, head_only: bool)
         }
     };
 
+    // `From<u64>` formats the full 64-bit decimal, files may exceed 4 GiB.
     resp.headers_mut().insert(
         header::CONTENT_LENGTH,
         header::HeaderValue::from(range.end - range.start),
     );
 
-    let path = data.nar_file_dir.join(hash);
-    if !head_only {
+    // Nothing to send for an empty file. Dropping `tx` ends the body.
+    if !head_only && range.start != range.end {
+        let metrics = data.metrics.clone();
+        let buf_len = data.send_file_buffer_len;
         hyper::rt::spawn(
             Box::pin(async move {
-                send_file(path, tx, range).await;
+                let mut tx = tx;
+                let _guard = InFlightGuard::new(&metrics.nar_file_in_flight);
+                let start = Instant::now();
+                if send_file(path, &mut tx, range, buf_len, &metrics).await {
+                    // Before dropping `tx`, which ends the body.
+                    metrics.nar_file_duration.observe(start.elapsed());
+                } else {
+                    tx.abort();
+                }
                 Ok(())
             })
             .compat(),
@@ -179,11 +1162,19 @@ fn serve_nar_file(data: &ServerData, req: &Request, hash: &str, head_only: bool)
     Ok(resp)
 }
 
-async fn send_file(path: PathBuf, mut tx: hyper::body::Sender, range: Range<u64>) {
+/// Return whether the whole range is sent. The caller should abort `tx` otherwise.
+async fn send_file(
+    path: PathBuf,
+    tx: &mut hyper::body::Sender,
+    range: Range<u64>,
+    buf_len: usize,
+    metrics: &Metrics,
+) -> bool {
     use async_std::{
         fs::File,
         io::{prelude::*, SeekFrom},
     };
+    use bytes::BytesMut;
     use futures01::Async as Async01;
     use std::{
         future::Future,
@@ -205,13 +1196,15 @@ async fn send_file(path: PathBuf, mut tx: hyper::body::Sender, range: Range<u64>
         }
     }
 
-    let mut buf = vec![0u8; SEND_FILE_BUFFER_LEN];
+    let buf_len = (buf_len as u64).min(range.end - range.start) as usize;
+    // Chunks are split off and sent without copying. It reallocates only
+    // when the rest capacity is not enough and sent chunks are still alive.
+    let mut buf = BytesMut::with_capacity(buf_len);
     let mut file = match File::open(&path).await {
         Ok(file) => file,
         Err(err) => {
             log::error!("Failed to open file '{}': {}", path.display(), err);
-            tx.abort();
-            return;
+            return false;
         }
     };
 
@@ -223,43 +1216,1440 @@ async fn send_file(path: PathBuf, mut tx: hyper::body::Sender, range: Range<u64>
                 range.start,
                 err,
             );
-            tx.abort();
-            return;
+            return false;
         }
     }
 
     let mut rest_len = range.end - range.start;
     while rest_len != 0 {
-        if let Err(err) = SenderReadyFuture(&mut tx).await {
+        if let Err(err) = SenderReadyFuture(tx).await {
             log::debug!(
                 "Connection broken when sending file '{}': {}",
                 path.display(),
                 err,
             );
-            tx.abort();
-            return;
+            return false;
         }
 
-        let read_len = rest_len.min(SEND_FILE_BUFFER_LEN as u64) as usize;
-        match file.read(&mut buf[..read_len]).await {
+        let read_len = rest_len.min(buf_len as u64) as usize;
+        buf.resize(read_len, 0);
+        match file.read(&mut buf).await {
             Ok(0) => {
                 log::debug!("File truncated '{}'", path.display());
-                tx.abort();
-                return;
+                return false;
             }
             Ok(got_len) => {
-                if let Err(_) = tx.send_data(Chunk::from(buf[..got_len].to_vec())) {
+                // Reads are capped to the rest of the range.
+                assert!(got_len <= read_len);
+                let chunk = buf.split_to(got_len).freeze();
+                buf.clear();
+                if tx.send_data(Chunk::from(chunk)).is_err() {
                     log::debug!("Failed to send chunk of file '{}'", path.display());
-                    tx.abort();
-                    return;
+                    return false;
                 }
                 rest_len -= got_len as u64;
+                metrics
+                    .nar_file_bytes
+                    .fetch_add(got_len as u64, Ordering::Relaxed);
             }
             Err(err) => {
                 log::error!("Failed to read file '{}' : {}", path.display(), err);
-                tx.abort();
-                return;
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_on,
+        database::model::{Nar, NarMeta, NarStatus, StorePath},
+        tests::test_nar,
+    };
+    use std::{convert::TryFrom, sync::mpsc, thread};
+
+    const HELLO_PATH: &str = "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10";
+    const GLIBC_PATH: &str = "/nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27";
+
+    /// Serve an in-memory database with `nars` available. The database is
+    /// returned for later changes.
+    fn init_data(
+        want_mass_query: bool,
+        priority: Option<i32>,
+        nars: &[Nar],
+    ) -> (Database, Arc<ServerData>) {
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, nars)
+            .unwrap();
+        let config = ServerConfig {
+            want_mass_query,
+            priority,
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, std::env::temp_dir(), &config).unwrap());
+        (db, data)
+    }
+
+    /// Serve a prepared database with the default config.
+    fn init_data_from(db: &Database) -> Arc<ServerData> {
+        Arc::new(ServerData::init(db, std::env::temp_dir(), &Default::default()).unwrap())
+    }
+
+    /// Serve a request to the end, returning the response with the whole body collected.
+    fn request(data: &Arc<ServerData>, req: Request) -> hyper::Response<Vec<u8>> {
+        let data = data.clone();
+        let (tx, rx) = mpsc::channel();
+        block_on(async move {
+            let (parts, body) = serve(data, req).await.unwrap().into_parts();
+            let body = body.concat2().compat().await.unwrap();
+            tx.send(hyper::Response::from_parts(parts, body.to_vec()))
+                .unwrap();
+        });
+        rx.recv().unwrap()
+    }
+
+    fn get(data: &Arc<ServerData>, uri: &str) -> hyper::Response<Vec<u8>> {
+        request(data, hyper::Request::get(uri).body(Body::empty()).unwrap())
+    }
+
+    fn body_to_string(resp: hyper::Response<Vec<u8>>) -> String {
+        String::from_utf8(resp.into_body()).unwrap()
+    }
+
+    #[test]
+    fn test_init_missing_nar_file_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open_in_memory().unwrap();
+        match ServerData::init(&db, dir.path().join("nar"), &Default::default()) {
+            Err(Error::NarFileDir(path, _)) => {
+                assert_eq!(path, dir.path().join("nar").display().to_string())
+            }
+            _ => panic!("Should fail"),
+        }
+    }
+
+    #[test]
+    fn test_nix_cache_info() {
+        let resp = get(&init_data(true, Some(40), &[]).1, "/nix-cache-info");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/x-nix-cache-info"
+        );
+        assert_eq!(
+            body_to_string(resp),
+            "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n",
+        );
+
+        let resp = get(&init_data(false, None, &[]).1, "/nix-cache-info");
+        assert_eq!(body_to_string(resp), "StoreDir: /nix/store\n");
+
+        let db = Database::open_in_memory().unwrap();
+        let extra = vec![
+            ("Trusted".to_owned(), "1".to_owned()),
+            ("Priority".to_owned(), "10".to_owned()),
+        ];
+        let data = ServerData::init(
+            &db,
+            std::env::temp_dir(),
+            &ServerConfig {
+                priority: Some(40),
+                extra_cache_info: extra,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let resp = get(&Arc::new(data), "/nix-cache-info");
+        assert_eq!(
+            body_to_string(resp),
+            "StoreDir: /nix/store\nPriority: 40\nTrusted: 1\n",
+        );
+
+        let data = ServerData::init(
+            &db,
+            std::env::temp_dir(),
+            &ServerConfig {
+                store_dir: "/data/nix/store".to_owned(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let resp = get(&Arc::new(data), "/nix-cache-info");
+        assert_eq!(body_to_string(resp), "StoreDir: /data/nix/store\n");
+
+        for (key, value) in &[("Bad\nKey", "1"), ("Key", "bad\nvalue"), ("", "1")] {
+            let extra = vec![(key.to_string(), value.to_string())];
+            match ServerData::init(
+                &db,
+                std::env::temp_dir(),
+                &ServerConfig {
+                    extra_cache_info: extra,
+                    ..Default::default()
+                },
+            ) {
+                Err(Error::CacheInfoLine(..)) => {}
+                _ => panic!("Should fail"),
+            }
+        }
+        for store_dir in &["nix/store", "/nix/store/", "/nix\n/store"] {
+            let config = ServerConfig {
+                store_dir: store_dir.to_string(),
+                ..Default::default()
+            };
+            match ServerData::init(&db, std::env::temp_dir(), &config) {
+                Err(Error::CacheInfoLine(key, _)) => assert_eq!(key, "StoreDir"),
+                _ => panic!("Should fail"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_store_ping() {
+        let resp = get(&init_data(true, Some(40), &[]).1, "/nix/store/ping");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let info: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "StoreDir": "/nix/store",
+                "WantMassQuery": 1,
+                "Priority": 40,
+            }),
+        );
+
+        let resp = get(&init_data(false, None, &[]).1, "/nix/store/ping");
+        let info: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(info, serde_json::json!({ "StoreDir": "/nix/store" }));
+    }
+
+    #[test]
+    fn test_config() {
+        let db = Database::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let data = Arc::new(
+            ServerData::init(
+                &db,
+                dir.path().to_owned(),
+                &ServerConfig {
+                    priority: Some(10),
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+        );
+        let resp = get(&data, "/api/config");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let config: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "store_dir": "/nix/store",
+                "want_mass_query": false,
+                "priority": 10,
+                "nar_file_dir": dir.path(),
+                "upload": false,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_nar_info_reload() {
+        let (db, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+
+        let data_ = data.clone();
+        let reloader = thread::spawn(move || {
+            for _ in 0..100 {
+                data_.reload(&db).unwrap();
             }
+        });
+        for _ in 0..100 {
+            let resp = get(&data, "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo");
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(body_to_string(resp).starts_with(&format!("StorePath: {}\n", HELLO_PATH)));
+        }
+        reloader.join().unwrap();
+    }
+
+    #[test]
+    fn test_nar_info_refresh() {
+        let (mut db, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+        let hello = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+        let glibc = "/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.narinfo";
+        assert_eq!(get(&data, glibc).status(), StatusCode::NOT_FOUND);
+
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(GLIBC_PATH, 2, "")])
+            .unwrap();
+        let in_flight = data.nar_info_cache().unwrap();
+        assert_eq!(data.refresh_from(&db).unwrap(), 1);
+        assert_eq!(data.refresh_from(&db).unwrap(), 0);
+        // Swapped, rather than changed under requests using it.
+        assert_eq!(in_flight.get_info("xlxiw4rnxx2dksa91fizjzf7jb5nqghc"), None);
+        assert_eq!(get(&data, glibc).status(), StatusCode::OK);
+        assert_eq!(get(&data, hello).status(), StatusCode::OK);
+        assert_eq!(
+            body_to_string(get(&data, "/paths")),
+            format!("{}\n{}\n", HELLO_PATH, GLIBC_PATH),
+        );
+
+        // Available in place, then trashed.
+        const LIBC_PATH: &str = "/nix/store/cccccccccccccccccccccccccccccccc-libc";
+        let libc = "/cccccccccccccccccccccccccccccccc.narinfo";
+        db.insert_or_ignore_nars(NarStatus::Pending, &[test_nar(LIBC_PATH, 3, "")])
+            .unwrap();
+        assert_eq!(data.refresh_from(&db).unwrap(), 0);
+        let libc_hash = StorePath::try_from(LIBC_PATH).unwrap().hash();
+        let libc_id = db.select_nar_id_by_hash(&libc_hash).unwrap().unwrap();
+        db.update_nar_status(libc_id, NarStatus::Available).unwrap();
+        assert_eq!(data.refresh_from(&db).unwrap(), 1);
+        assert_eq!(get(&data, libc).status(), StatusCode::OK);
+        db.trash_nar(&libc_hash).unwrap();
+        assert_eq!(data.refresh_from(&db).unwrap(), 0);
+        assert_eq!(get(&data, libc).status(), StatusCode::NOT_FOUND);
+
+        assert!(data.remove_nar("yhzvzdq82lzk0kvrp3i79yhjnhps6qpk"));
+        assert_eq!(get(&data, hello).status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&data, glibc).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_nar_info_rebuilding() {
+        let (db, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+        let uri = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+
+        // Simulate the window where old caches are dropped but new ones are not ready.
+        *data.nar_info_cache.write().unwrap() = None;
+        *data.root_cache.write().unwrap() = None;
+        for uri in &[uri, "/paths", "/channels/1/git-revision"] {
+            let resp = get(&data, uri);
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+            assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+        }
+        assert_eq!(get(&data, "/nix-cache-info").status(), StatusCode::OK);
+
+        data.rebuild(&db).unwrap();
+        assert_eq!(get(&data, uri).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_nar_info_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut db = Database::open(&db_path).unwrap();
+        db.insert_or_ignore_nars(
+            NarStatus::Available,
+            &[test_nar(HELLO_PATH, 1, ""), test_nar(GLIBC_PATH, 2, "")],
+        )
+        .unwrap();
+        let in_memory = init_data_from(&db);
+        let config = ServerConfig {
+            nar_info_on_demand: Some(db_path),
+            ..Default::default()
+        };
+        let on_demand = Arc::new(ServerData::init(&db, std::env::temp_dir(), &config).unwrap());
+
+        for uri in &[
+            "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo",
+            "/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.narinfo",
+            "/api/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk",
+            "/paths",
+        ] {
+            let expect = get(&in_memory, uri);
+            assert_eq!(expect.status(), StatusCode::OK);
+            assert_eq!(get(&on_demand, uri).body(), expect.body(), "{}", uri);
+        }
+        let uri = "/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo";
+        assert_eq!(get(&on_demand, uri).status(), StatusCode::NOT_FOUND);
+
+        // Reloading keeps rendering on demand.
+        on_demand.reload(&db).unwrap();
+        let uri = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+        assert_eq!(get(&on_demand, uri).body(), get(&in_memory, uri).body());
+    }
+
+    #[test]
+    fn test_nar_info_invalid_hash() {
+        let (_, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+
+        assert_eq!(
+            get(&data, "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo").status(),
+            StatusCode::OK,
+        );
+        for uri in &[
+            "/hello.narinfo",
+            "/.narinfo",
+            "/YHZVZDQ82LZK0KVRP3I79YHJNHPS6QPK.narinfo",
+            "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpe.narinfo",
+            "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpkk.narinfo",
+        ] {
+            assert_eq!(get(&data, uri).status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 1, "")])
+            .unwrap();
+        db.insert_or_ignore_nars(NarStatus::Pending, &[test_nar(GLIBC_PATH, 2, "")])
+            .unwrap();
+        let data = init_data_from(&db);
+
+        let resp = get(&data, "/paths");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_to_string(resp), format!("{}\n", HELLO_PATH));
+
+        let req = hyper::Request::head("/paths").body(Body::empty()).unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn test_paths_gzip() {
+        use std::io::Read;
+
+        let (_, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+
+        let req = hyper::Request::get("/paths")
+            .header(header::ACCEPT_ENCODING, "deflate, gzip;q=0.5")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        let mut paths = String::new();
+        flate2::read::GzDecoder::new(&resp.body()[..])
+            .read_to_string(&mut paths)
+            .unwrap();
+        assert_eq!(paths, format!("{}\n", HELLO_PATH));
+
+        let req = hyper::Request::get("/paths")
+            .header(header::ACCEPT_ENCODING, "gzip;q=0")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body_to_string(resp), format!("{}\n", HELLO_PATH));
+    }
+
+    #[test]
+    fn test_channel_files() {
+        use crate::database::model::{Root, RootStatus};
+        use std::io::Read;
+
+        const REV: &str = "0123456789abcdef0123456789abcdef01234567";
+
+        let mut db = Database::open_in_memory().unwrap();
+        let nars = [test_nar(HELLO_PATH, 1, ""), test_nar(GLIBC_PATH, 2, "")];
+        db.insert_or_ignore_nars(NarStatus::Available, &nars)
+            .unwrap();
+        let root = Root {
+            git_revision: Some(REV.to_owned()),
+            status: RootStatus::Available,
+            ..Default::default()
+        };
+        let hashes = nars.iter().map(|nar| nar.store_path.hash());
+        let root_id = db.insert_root(&root, hashes.clone()).unwrap();
+        let pending_id = db.insert_root(&Root::default(), hashes).unwrap();
+        let data = init_data_from(&db);
+
+        // Served raw even if the client accepts gzip, or `nix-channel` fails to decompress it.
+        let req = hyper::Request::get(format!("/channels/{}/store-paths.xz", root_id))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/x-xz");
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        let mut paths = String::new();
+        xz2::read::XzDecoder::new(&resp.body()[..])
+            .read_to_string(&mut paths)
+            .unwrap();
+        let paths = paths
+            .lines()
+            .map(|line| StorePath::try_from(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0], nars[0].store_path);
+        assert_eq!(paths[1], nars[1].store_path);
+
+        let resp = get(&data, &format!("/channels/{}/git-revision", root_id));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_to_string(resp), REV);
+
+        let req = hyper::Request::get(format!("/channels/{}/binary-cache-url", root_id))
+            .header(header::HOST, "mirror.example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_to_string(resp), "http://mirror.example.com:8080");
+
+        let mut req = hyper::Request::get(format!("/channels/{}/binary-cache-url", root_id))
+            .header(header::HOST, "mirror.example.com")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(Scheme("https"));
+        let resp = request(&data, req);
+        assert_eq!(body_to_string(resp), "https://mirror.example.com");
+
+        for uri in &[
+            format!("/channels/{}/git-revision", pending_id),
+            format!("/channels/{}/nixexprs.tar.xz", root_id),
+            "/channels/foo/git-revision".to_owned(),
+        ] {
+            assert_eq!(get(&data, uri).status(), StatusCode::NOT_FOUND);
         }
     }
+
+    #[test]
+    fn test_empty_nar_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        std::fs::write(dir.path().join(hash), b"").unwrap();
+
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 0, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+
+        let resp = get(&data, &format!("/nar/{}", hash));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "0");
+        assert!(resp.body().is_empty());
+
+        // No range is satisfiable for an empty file.
+        let req = hyper::Request::get(format!("/nar/{}", hash))
+            .header(header::RANGE, "bytes=1-1")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */0");
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn test_nar_file_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let content = (0u8..20).collect::<Vec<u8>>();
+        std::fs::write(dir.path().join(hash), &content).unwrap();
+
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 20, "")])
+            .unwrap();
+        // Files are sent in multiple chunks.
+        let config = ServerConfig {
+            send_file_buffer_len: 3,
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, dir.path().to_owned(), &config).unwrap());
+
+        let resp = get(&data, &format!("/nar/{}", hash));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), &content);
+
+        // Range ends in the middle of the file and the read buffer.
+        let req = hyper::Request::get(format!("/nar/{}", hash))
+            .header(header::RANGE, "bytes=5-9")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 5-9/20");
+        assert_eq!(resp.body(), &content[5..10]);
+
+        let req = hyper::Request::get(format!("/nar/{}", hash))
+            .header(header::RANGE, "bytes=-3")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 17-19/20");
+        assert_eq!(resp.body(), &content[17..]);
+
+        let req = hyper::Request::get(format!("/nar/{}", hash))
+            .header(header::RANGE, "bytes=20-")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */20");
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn test_nar_file_large_content() {
+        const SIZE: usize = 10 << 20; // 10 MiB
+
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let content = (0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(dir.path().join(hash), &content).unwrap();
+
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(
+            NarStatus::Available,
+            &[test_nar(HELLO_PATH, SIZE as u64, "")],
+        )
+        .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+
+        let resp = get(&data, &format!("/nar/{}", hash));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.body() == &content);
+
+        let req = hyper::Request::get(format!("/nar/{}", hash))
+            .header(header::RANGE, "bytes=12345-")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp.body()[..] == content[12345..]);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        let parse = |range: &str, file_size: u64| {
+            let req = hyper::Request::get("/")
+                .header(header::RANGE, range)
+                .body(Body::empty())
+                .unwrap();
+            parse_content_range(&req, file_size)
+        };
+        assert_eq!(parse("bytes=0-0", 200), RangeResult::Satisfiable(0..1));
+        assert_eq!(parse("bytes=0-99", 200), RangeResult::Satisfiable(0..100));
+        assert_eq!(parse("bytes=100-", 200), RangeResult::Satisfiable(100..200));
+        assert_eq!(
+            parse("bytes=199-199", 200),
+            RangeResult::Satisfiable(199..200)
+        );
+        assert_eq!(
+            parse("bytes=100-999", 200),
+            RangeResult::Satisfiable(100..200)
+        );
+        assert_eq!(parse("bytes=0-0, 5-9", 200), RangeResult::Satisfiable(0..1));
+        assert_eq!(parse("bytes=-50", 200), RangeResult::Satisfiable(150..200));
+        assert_eq!(parse("bytes=-200", 200), RangeResult::Satisfiable(0..200));
+        assert_eq!(parse("bytes=-500", 200), RangeResult::Satisfiable(0..200));
+        assert_eq!(parse("bytes=-1", 1), RangeResult::Satisfiable(0..1));
+
+        // Unsatisfiable.
+        assert_eq!(parse("bytes=200-", 200), RangeResult::Unsatisfiable);
+        assert_eq!(parse("bytes=200-300", 200), RangeResult::Unsatisfiable);
+        assert_eq!(parse("bytes=0-0", 0), RangeResult::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 200), RangeResult::Unsatisfiable);
+        assert_eq!(parse("bytes=-500", 0), RangeResult::Unsatisfiable);
+
+        // Invalid ones are ignored.
+        assert_eq!(parse("bytes=-", 200), RangeResult::None);
+        assert_eq!(parse("bytes=9-5", 200), RangeResult::None);
+        assert_eq!(parse("bytes=a-b", 200), RangeResult::None);
+        assert_eq!(parse("items=0-0", 200), RangeResult::None);
+    }
+
+    #[test]
+    fn test_nar_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let mut enc = xz2::write::XzEncoder::new(vec![], 6);
+        std::io::Write::write_all(&mut enc, &nar_listing::test_nar_dir()).unwrap();
+        std::fs::write(dir.path().join(hash), enc.finish().unwrap()).unwrap();
+
+        let mut hello = test_nar(HELLO_PATH, 1, "");
+        hello.meta.compression = Some("xz".to_owned());
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[hello, test_nar(GLIBC_PATH, 1, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+
+        let resp = get(&data, &format!("/{}.ls", hash));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let listing: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            listing,
+            nar_listing::list_nar(&nar_listing::test_nar_dir()[..]).unwrap(),
+        );
+
+        // File missing, not available, or not a hash.
+        for uri in &[
+            "/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.ls",
+            "/00000000000000000000000000000000.ls",
+            "/hello.ls",
+        ] {
+            assert_eq!(get(&data, uri).status(), StatusCode::NOT_FOUND);
+        }
+
+        // Corrupted.
+        std::fs::write(dir.path().join(hash), b"garbage").unwrap();
+        assert_eq!(
+            get(&data, &format!("/{}.ls", hash)).status(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+
+    #[test]
+    fn test_nar_file_content_type() {
+        let compressed = Nar {
+            meta: NarMeta {
+                compression: Some("xz".to_owned()),
+                ..test_nar(HELLO_PATH, 1, "").meta
+            },
+            ..test_nar(HELLO_PATH, 1, "")
+        };
+        let (_, data) = init_data(false, None, &[compressed, test_nar(GLIBC_PATH, 1, "")]);
+
+        for (hash, content_type) in &[
+            ("yhzvzdq82lzk0kvrp3i79yhjnhps6qpk", "application/x-xz"),
+            ("xlxiw4rnxx2dksa91fizjzf7jb5nqghc", "application/x-nix-nar"),
+        ] {
+            let req = hyper::Request::head(format!("/nar/{}", hash))
+                .body(Body::empty())
+                .unwrap();
+            let resp = request(&data, req);
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], *content_type);
+        }
+    }
+
+    #[test]
+    fn test_nar_file_large_size() {
+        const SIZE: u64 = 5 << 30; // 5 GiB
+
+        // Only headers are generated for HEAD, so the file needs not exist.
+        let (_, data) = init_data(false, None, &[test_nar(HELLO_PATH, SIZE, "")]);
+
+        let req = hyper::Request::head("/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], SIZE.to_string());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        std::fs::write(dir.path().join(hash), b"hello").unwrap();
+
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 5, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+
+        for _ in 0..3 {
+            assert_eq!(
+                get(&data, &format!("/{}.narinfo", hash)).status(),
+                StatusCode::OK
+            );
+        }
+        assert_eq!(get(&data, &format!("/nar/{}", hash)).body(), b"hello");
+
+        let resp = get(&data, "/metrics");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_to_string(resp);
+        assert!(body.contains("nix_cache_mirror_narinfo_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(body.contains("nix_cache_mirror_narinfo_duration_seconds_count 3\n"));
+        assert!(
+            body.contains("nix_cache_mirror_nar_transfer_duration_seconds_bucket{le=\"+Inf\"} 1\n")
+        );
+        assert!(body.contains("nix_cache_mirror_narinfo_requests_total{result=\"hit\"} 3\n"));
+        assert!(body.contains("nix_cache_mirror_narinfo_requests_total{result=\"miss\"} 0\n"));
+        assert!(body.contains("nix_cache_mirror_nar_sent_bytes_total 5\n"));
+        assert!(body.contains("nix_cache_mirror_nar_transfers_in_flight 0\n"));
+        assert!(body.contains("nix_cache_mirror_cached_nars 1\n"));
+
+        let missing = "00000000000000000000000000000000";
+        assert_eq!(
+            get(&data, &format!("/{}.narinfo", missing)).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get(&data, "/no/such/file").status(), StatusCode::NOT_FOUND);
+        let body = body_to_string(get(&data, "/metrics"));
+        assert!(body.contains("nix_cache_mirror_narinfo_requests_total{result=\"miss\"} 1\n"));
+        assert!(body.contains("nix_cache_mirror_not_found_total 2\n"));
+
+        let req = hyper::Request::post("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(request(&data, req).status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_root_paths() {
+        use crate::database::model::{Nar, Root};
+
+        let mut db = Database::open_in_memory().unwrap();
+        // `hello` depends on `glibc`, which is not listed.
+        let glibc = test_nar(GLIBC_PATH, 1, "");
+        let hello = Nar {
+            references: "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27".to_owned(),
+            ..test_nar(HELLO_PATH, 1, "")
+        };
+        let openssl = test_nar(
+            "/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz",
+            1,
+            "",
+        );
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&glibc, &hello, &openssl])
+            .unwrap();
+        let root_id = db
+            .insert_root(
+                &Root::default(),
+                vec![hello.store_path.hash(), openssl.store_path.hash()],
+            )
+            .unwrap();
+        let data = init_data_from(&db);
+
+        let resp = get(&data, &format!("/api/roots/{}/paths", root_id));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let paths: Vec<String> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                HELLO_PATH,
+                "/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz"
+            ],
+        );
+
+        let resp = get(&data, &format!("/api/roots/{}/paths", root_id + 1));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_nar_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 5, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+        let uri = "/api/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+
+        // Metadata is known but the file is missing.
+        let resp = get(&data, uri);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let meta: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(meta["store_path"], HELLO_PATH);
+        assert_eq!(meta["file_size"], 5);
+        assert_eq!(meta["file_present"], false);
+
+        std::fs::write(
+            dir.path().join("yhzvzdq82lzk0kvrp3i79yhjnhps6qpk"),
+            b"hello",
+        )
+        .unwrap();
+        let meta: serde_json::Value = serde_json::from_slice(get(&data, uri).body()).unwrap();
+        assert_eq!(meta["file_present"], true);
+
+        let resp = get(&data, "/api/nar/xlxiw4rnxx2dksa91fizjzf7jb5nqghc");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_nar_info_head() {
+        let (_, data) = init_data(false, None, &[test_nar(HELLO_PATH, 1, "")]);
+        let head = |uri: &str| {
+            request(
+                &data,
+                hyper::Request::head(uri).body(Body::empty()).unwrap(),
+            )
+        };
+
+        let uri = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+        let info = get(&data, uri).into_body();
+        let resp = head(uri);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/x-nix-narinfo");
+        assert_eq!(
+            resp.headers()[header::CONTENT_LENGTH],
+            info.len().to_string()
+        );
+        assert!(resp.body().is_empty());
+
+        let resp = head("/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.narinfo");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn test_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        std::fs::write(dir.path().join(hash), b"hello").unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 5, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+        let get_if = |uri: &str, tag: &str| {
+            let req = hyper::Request::get(uri)
+                .header(header::IF_NONE_MATCH, tag)
+                .body(Body::empty())
+                .unwrap();
+            request(&data, req)
+        };
+
+        for uri in &[format!("/{}.narinfo", hash), format!("/nar/{}", hash)] {
+            let resp = get(&data, uri);
+            assert_eq!(resp.status(), StatusCode::OK);
+            let etag = resp.headers()[header::ETAG].to_str().unwrap().to_owned();
+            assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+            // Stable.
+            assert_eq!(get(&data, uri).headers()[header::ETAG], *etag);
+
+            for tag in &[
+                etag.clone(),
+                format!("W/{}", etag),
+                format!("\"other\", {}", etag),
+                "*".to_owned(),
+            ] {
+                let resp = get_if(uri, tag);
+                assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", tag);
+                assert_eq!(resp.headers()[header::ETAG], *etag);
+                assert!(resp.body().is_empty());
+            }
+
+            let resp = get_if(uri, "\"other\"");
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!resp.body().is_empty());
+        }
+        assert_eq!(
+            get(&data, &format!("/nar/{}", hash)).headers()[header::ETAG],
+            "\"sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\"",
+        );
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let file = std::fs::File::create(dir.path().join(hash)).unwrap();
+        file.set_len(5).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1_577_934_245))
+            .unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 5, "")])
+            .unwrap();
+        let data =
+            Arc::new(ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap());
+        let uri = format!("/nar/{}", hash);
+        let get_with = |headers: &[(header::HeaderName, &str)]| {
+            let mut req = hyper::Request::get(&uri);
+            for (name, value) in headers {
+                req.header(name, *value);
+            }
+            request(&data, req.body(Body::empty()).unwrap())
+        };
+
+        const MTIME: &str = "Thu, 02 Jan 2020 03:04:05 GMT";
+        let resp = get(&data, &uri);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::LAST_MODIFIED], MTIME);
+
+        for since in &[MTIME, "Fri, 03 Jan 2020 00:00:00 GMT"] {
+            let resp = get_with(&[(header::IF_MODIFIED_SINCE, since)]);
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", since);
+            assert_eq!(resp.headers()[header::LAST_MODIFIED], MTIME);
+            assert!(resp.body().is_empty());
+        }
+        for since in &["Thu, 02 Jan 2020 03:04:04 GMT", "invalid"] {
+            let resp = get_with(&[(header::IF_MODIFIED_SINCE, since)]);
+            assert_eq!(resp.status(), StatusCode::OK, "{}", since);
+            assert_eq!(resp.body().len(), 5);
+        }
+
+        // No body or range headers for ranges not modified.
+        let resp = get_with(&[
+            (header::IF_MODIFIED_SINCE, MTIME),
+            (header::RANGE, "bytes=1-2"),
+        ]);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(!resp.headers().contains_key(header::CONTENT_RANGE));
+        assert!(resp.body().is_empty());
+
+        // `If-None-Match` takes precedence.
+        let resp = get_with(&[
+            (header::IF_MODIFIED_SINCE, MTIME),
+            (header::IF_NONE_MATCH, "\"other\""),
+        ]);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let (_, data) = init_data(false, None, &[]);
+        for (uri, allow) in &[
+            ("/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk", "GET, HEAD"),
+            ("/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo", "GET, HEAD"),
+            ("/api/narinfo-batch", "POST"),
+        ] {
+            let method = if uri.starts_with("/api/") {
+                Method::PUT
+            } else {
+                Method::POST
+            };
+            let req = hyper::Request::builder()
+                .method(method)
+                .uri(*uri)
+                .body(Body::empty())
+                .unwrap();
+            let resp = request(&data, req);
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(resp.headers()[header::ALLOW], *allow, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn test_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let nar_dir = dir.path().join("nar");
+        std::fs::create_dir(&nar_dir).unwrap();
+        let db = Database::open(&db_path).unwrap();
+        let config = ServerConfig {
+            upload: Some(UploadConfig {
+                token: "secret".to_owned(),
+                db_path: db_path.clone(),
+                max_orphan_size: 1 << 20,
+                staged_ttl: Duration::from_secs(3600),
+            }),
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, nar_dir.clone(), &config).unwrap());
+
+        let put = |uri: &str, auth: Option<&str>, body: Vec<u8>| {
+            let mut req = hyper::Request::put(uri);
+            if let Some(auth) = auth {
+                req.header(header::AUTHORIZATION, auth);
+            }
+            request(&data, req.body(Body::from(body)).unwrap())
+        };
+        let bearer = Some("Bearer secret");
+        let nar_info = |path: &str, url: &str, content: &[u8]| {
+            let mut nar = test_nar(path, content.len() as u64, "");
+            nar.meta.url = url.to_owned();
+            nar.meta.compression = Some("none".to_owned());
+            nar.meta.file_hash = Some(crate::update::sha256_str(content).parse().unwrap());
+            nar.meta.nar_hash = crate::update::sha256_str(content).parse().unwrap();
+            nar.meta.nar_size = content.len() as u64;
+            let info = nar.format_nar_info().to_string();
+            info.into_bytes()
+        };
+        let hello_uri = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+        let hello_nar = b"nix-archive-1 hello".to_vec();
+        let hello_info = nar_info(HELLO_PATH, "nar/4567.nar", &hello_nar);
+
+        let resp = put(hello_uri, None, hello_info.clone());
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+        let resp = put(hello_uri, Some("Bearer wrong"), hello_info.clone());
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // Mismatched store path.
+        let resp = put(
+            "/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.narinfo",
+            bearer,
+            hello_info.clone(),
+        );
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // The narinfo first, then the NAR file at its URL.
+        let resp = put(hello_uri, bearer, hello_info.clone());
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(get(&data, hello_uri).status(), StatusCode::NOT_FOUND);
+        let resp = put("/nar/4567.nar", bearer, b"bad".to_vec());
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = put("/nar/4567.nar", bearer, hello_nar.clone());
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(get(&data, hello_uri).status(), StatusCode::OK);
+        let resp = get(&data, "/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk");
+        assert_eq!(resp.into_body(), hello_nar);
+        assert_eq!(put(hello_uri, bearer, hello_info).status(), StatusCode::OK,);
+
+        // The NAR file first at any name, as `nix copy` does, with basic auth.
+        let basic = format!("Basic {}", base64::encode("nix:secret"));
+        let glibc_uri = "/xlxiw4rnxx2dksa91fizjzf7jb5nqghc.narinfo";
+        let glibc_nar = b"nix-archive-1 glibc".to_vec();
+        let glibc_info = nar_info(GLIBC_PATH, "nar/0123.nar", &glibc_nar);
+        let resp = put("/nar/0123.nar", Some(&basic), glibc_nar.clone());
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(get(&data, glibc_uri).status(), StatusCode::NOT_FOUND);
+        let resp = put(glibc_uri, Some(&basic), glibc_info);
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(get(&data, glibc_uri).status(), StatusCode::OK);
+
+        let mut db = Database::open_exclusive(&db_path).unwrap();
+        assert_eq!(db.count_nars(NarStatus::Available).unwrap(), 2);
+        assert!(!nar_dir.join(".upload/0123.nar").exists());
+        assert!(!nar_dir.join(".upload/4567.nar").exists());
+        assert!(nar_dir.join("xlxiw4rnxx2dksa91fizjzf7jb5nqghc").exists());
+        // Kept by the upload root.
+        assert_eq!(db.gc_unreferenced().unwrap(), vec![]);
+
+        let req = hyper::Request::post(glibc_uri).body(Body::empty()).unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "PUT, DELETE, GET, HEAD");
+
+        // Disabled.
+        let (_, data) = init_data(false, None, &[]);
+        let req = hyper::Request::put(hello_uri)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "GET, HEAD");
+    }
+
+    #[test]
+    fn test_upload_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let db = Database::open(&db_path).unwrap();
+        let config = ServerConfig {
+            upload: Some(UploadConfig {
+                token: "secret".to_owned(),
+                db_path: db_path.clone(),
+                max_orphan_size: 4,
+                staged_ttl: Duration::from_secs(3600),
+            }),
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, dir.path().to_owned(), &config).unwrap());
+        let put = |uri: &str, body: &[u8]| {
+            let req = hyper::Request::put(uri)
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(Body::from(body.to_vec()))
+                .unwrap();
+            request(&data, req).status()
+        };
+
+        let stale = dir.path().join(".upload/stale.nar");
+        let file = std::fs::File::create(&stale).unwrap();
+        file.set_modified(std::time::SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        let fresh = dir.path().join(".upload/fresh.nar");
+        std::fs::write(&fresh, b"x").unwrap();
+
+        // Without a narinfo, the size is limited by `max_orphan_size`.
+        assert_eq!(put("/nar/big.nar", b"12345"), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.path().join(".upload/big.nar").exists());
+        assert_eq!(put("/nar/small.nar", b"1234"), StatusCode::ACCEPTED);
+        assert!(dir.path().join(".upload/small.nar").exists());
+
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut db = Database::open(&db_path).unwrap();
+        db.insert_or_ignore_nars(NarStatus::Available, &[test_nar(HELLO_PATH, 1, "")])
+            .unwrap();
+        let file_path = dir.path().join("yhzvzdq82lzk0kvrp3i79yhjnhps6qpk");
+        std::fs::write(&file_path, b"x").unwrap();
+        let config = ServerConfig {
+            upload: Some(UploadConfig {
+                token: "secret".to_owned(),
+                db_path: db_path.clone(),
+                max_orphan_size: 1 << 20,
+                staged_ttl: Duration::from_secs(3600),
+            }),
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, dir.path().to_owned(), &config).unwrap());
+
+        let delete = |uri: &str, auth: bool| {
+            let mut req = hyper::Request::delete(uri);
+            if auth {
+                req.header(header::AUTHORIZATION, "Bearer secret");
+            }
+            request(&data, req.body(Body::empty()).unwrap()).status()
+        };
+        let uri = "/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+        assert_eq!(delete(uri, false), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&data, uri).status(), StatusCode::OK);
+
+        assert_eq!(delete(uri, true), StatusCode::NO_CONTENT);
+        assert_eq!(get(&data, uri).status(), StatusCode::NOT_FOUND);
+        assert!(!file_path.exists());
+        assert_eq!(db.count_nars(NarStatus::Trashed).unwrap(), 1);
+        assert_eq!(
+            delete("/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk", true),
+            StatusCode::NOT_FOUND,
+        );
+        assert_eq!(delete("/nar/not-a-hash", true), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_nar_info_batch() {
+        let nars = [test_nar(HELLO_PATH, 1, ""), test_nar(GLIBC_PATH, 2, "")];
+        let (_, data) = init_data(false, None, &nars);
+
+        let req = hyper::Request::post("/api/narinfo-batch")
+            .body(Body::from(
+                r#"["yhzvzdq82lzk0kvrp3i79yhjnhps6qpk","xlxiw4rnxx2dksa91fizjzf7jb5nqghc","00000000000000000000000000000000"]"#,
+            ))
+            .unwrap();
+        let resp = request(&data, req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+
+        let infos: HashMap<String, Option<String>> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(infos.len(), 3);
+        assert!(infos["yhzvzdq82lzk0kvrp3i79yhjnhps6qpk"]
+            .as_ref()
+            .unwrap()
+            .starts_with(&format!("StorePath: {}\n", HELLO_PATH)));
+        assert!(infos["xlxiw4rnxx2dksa91fizjzf7jb5nqghc"]
+            .as_ref()
+            .unwrap()
+            .starts_with(&format!("StorePath: {}\n", GLIBC_PATH)));
+        assert_eq!(infos["00000000000000000000000000000000"], None);
+
+        let req = hyper::Request::post("/api/narinfo-batch")
+            .body(Body::from("not json"))
+            .unwrap();
+        assert_eq!(request(&data, req).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_server_handle() {
+        block_on(async {
+            let handle = Server::bind(
+                &([127, 0, 0, 1], 0).into(),
+                init_data(false, None, &[]).1,
+                &ListenConfig::default(),
+            )
+            .unwrap();
+            let uri = format!("http://{}/", handle.local_addr()).parse().unwrap();
+
+            let resp = hyper::Client::new().get(uri).compat().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = resp.into_body().concat2().compat().await.unwrap();
+            assert_eq!(&*body, b"It works");
+
+            handle.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_access_log() {
+        crate::tests::init_logger();
+        block_on(async {
+            let handle = Server::bind(
+                &([127, 0, 0, 1], 0).into(),
+                init_data(false, None, &[]).1,
+                &ListenConfig::default(),
+            )
+            .unwrap();
+            let uri = format!("http://{}/nix-cache-info", handle.local_addr())
+                .parse()
+                .unwrap();
+            let resp = hyper::Client::new().get(uri).compat().await.unwrap();
+            let body = resp.into_body().concat2().compat().await.unwrap();
+            handle.shutdown().await.unwrap();
+
+            let mut logs = crate::tests::access_logs("/nix-cache-info");
+            assert_eq!(logs.len(), 1);
+            let line = logs[0].as_object_mut().unwrap();
+            assert!(line.remove("time").is_some());
+            let duration_ms = line.remove("duration_ms").unwrap().as_u64().unwrap();
+            assert_eq!(
+                logs[0],
+                serde_json::json!({
+                    "level": "INFO",
+                    "target": "access",
+                    "message": format!(
+                        "GET /nix-cache-info 200 {}B {}ms",
+                        body.len(),
+                        duration_ms,
+                    ),
+                    "method": "GET",
+                    "path": "/nix-cache-info",
+                    "status": 200,
+                    "bytes": body.len(),
+                }),
+            );
+        });
+    }
+
+    #[test]
+    fn test_tls() {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509};
+        use std::{
+            io::{Read, Write},
+            net::TcpStream,
+        };
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = x509::X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = ListenConfig {
+            tls: Some(TlsConfig {
+                cert_path: dir.path().join("cert.pem"),
+                key_path: dir.path().join("key.pem"),
+            }),
+            ..Default::default()
+        };
+        let addr = ([127, 0, 0, 1], 0).into();
+        // Missing files.
+        assert!(matches!(
+            Server::bind(&addr, init_data(false, None, &[]).1, &config),
+            Err(Error::Tls(_)),
+        ));
+        std::fs::write(dir.path().join("cert.pem"), cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(
+            dir.path().join("key.pem"),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+
+        block_on(async move {
+            let handle = Server::bind(&addr, init_data(false, None, &[]).1, &config).unwrap();
+            let addr = handle.local_addr();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            thread::spawn(move || {
+                // Clients never sending a ClientHello don't block others.
+                let _silent = (0..100)
+                    .map(|_| TcpStream::connect(addr).unwrap())
+                    .collect::<Vec<_>>();
+
+                // A plain HTTP request fails the handshake, without affecting others.
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+                let _ = stream.read_to_end(&mut vec![]);
+
+                let connector = native_tls::TlsConnector::builder()
+                    .danger_accept_invalid_certs(true)
+                    .build()
+                    .unwrap();
+                let stream = TcpStream::connect(addr).unwrap();
+                let mut stream = connector.connect("localhost", stream).unwrap();
+                stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+                let mut resp = String::new();
+                stream.read_to_string(&mut resp).unwrap();
+                tx.send(resp).unwrap();
+            });
+            let resp = rx.await.unwrap();
+            assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"), "{}", resp);
+            assert!(resp.ends_with("\r\n\r\nIt works"));
+
+            handle.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = "yhzvzdq82lzk0kvrp3i79yhjnhps6qpk";
+        let content: Vec<u8> = (0..4 << 20).map(|i| i as u8).collect();
+        std::fs::write(dir.path().join(hash), &content).unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+        db.insert_or_ignore_nars(
+            NarStatus::Available,
+            &[test_nar(HELLO_PATH, content.len() as u64, "")],
+        )
+        .unwrap();
+        let config = ServerConfig {
+            send_file_buffer_len: 4 << 10,
+            ..Default::default()
+        };
+        let data = Arc::new(ServerData::init(&db, dir.path().to_owned(), &config).unwrap());
+
+        block_on(async move {
+            let handle =
+                Server::bind(&([127, 0, 0, 1], 0).into(), data, &ListenConfig::default()).unwrap();
+            let addr = handle.local_addr();
+            let (signal_tx, signal_rx) = oneshot::channel::<()>();
+            let (done_tx, done_rx) = oneshot::channel();
+            crate::spawn(async move {
+                let ret = handle.shutdown_on(signal_rx.map(|_| ())).await;
+                done_tx.send(ret).unwrap();
+            });
+
+            let uri = format!("http://{}/nar/{}", addr, hash).parse().unwrap();
+            let resp = hyper::Client::new().get(uri).compat().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            // The body is still being sent.
+            signal_tx.send(()).unwrap();
+            let body = resp.into_body().concat2().compat().await.unwrap();
+            assert_eq!(body.len(), content.len());
+            assert!(*body == *content);
+
+            done_rx.await.unwrap().unwrap();
+            // No longer accepting.
+            let uri = format!("http://{}/", addr).parse().unwrap();
+            assert!(hyper::Client::new().get(uri).compat().await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        use std::{io::Read, net::TcpStream};
+
+        let config = ListenConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        block_on(async move {
+            let handle = Server::bind(
+                &([127, 0, 0, 1], 0).into(),
+                init_data(false, None, &[]).1,
+                &config,
+            )
+            .unwrap();
+
+            // Read on another thread to not block the runtime.
+            let addr = handle.local_addr();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                let start = Instant::now();
+                let ret = stream.read(&mut [0u8; 1]).unwrap();
+                tx.send((ret, start.elapsed())).unwrap();
+            });
+            let (ret, elapsed) = rx.await.unwrap();
+            // Closed by server.
+            assert_eq!(ret, 0);
+            assert!(elapsed < Duration::from_secs(10));
+
+            handle.shutdown().await.unwrap();
+        });
+    }
 }
@@ -1,59 +1,416 @@
 use crate::database::{
-    model::{NarStatus, StorePathHash},
+    model::{Hash, Nar, NarStatus, StorePathHash},
     Database, Error as DBError,
 };
-use std::{collections::HashMap, ops::Range};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NarInfoCache {
+    // All narinfos, or only store paths if rendered on demand.
     buf: String,
+    // Bytes in `buf` of removed items.
+    garbage: usize,
     cache: HashMap<StorePathHash, CacheItem>,
+    // Ranges of store paths in `buf`, in order of insertion.
+    paths: Vec<Range<usize>>,
+    // The max change number of NARs loaded from the database.
+    max_seq: i64,
+    // Connections for rendering narinfos on demand.
+    db: Option<Arc<ReadPool>>,
 }
 
+/// Read connections to a database, each used by one request at a time, so
+/// that concurrent requests are not serialized on a single connection.
 #[derive(Debug)]
+struct ReadPool {
+    path: PathBuf,
+    idle: Mutex<Vec<Database>>,
+}
+
+impl ReadPool {
+    fn open(path: &Path) -> Result<Self, DBError> {
+        Ok(Self {
+            path: path.to_owned(),
+            idle: Mutex::new(vec![Database::open(path)?]),
+        })
+    }
+
+    /// Run `f` on an idle connection, opening a new one if there is none.
+    fn with<T>(&self, f: impl FnOnce(&Database) -> Result<T, DBError>) -> Result<T, DBError> {
+        let idle = self.idle.lock().unwrap().pop();
+        let db = match idle {
+            Some(db) => db,
+            None => Database::open(&self.path)?,
+        };
+        let ret = f(&db);
+        self.idle.lock().unwrap().push(db);
+        ret
+    }
+}
+
+/// NARs changed since the last load of a `NarInfoCache`.
+#[derive(Debug)]
+pub struct Changes {
+    max_seq: i64,
+    // `(id, status, nar)` in order of changes.
+    nars: Vec<(i64, NarStatus, Nar)>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.nars.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
 struct CacheItem {
-    info_range: Range<usize>,
+    info: InfoLoc,
+    path: Range<usize>,
     file_size: u64,
+    content_type: &'static str,
+    nar_hash: Hash,
 }
 
-impl NarInfoCache {
-    pub fn init(db: &Database) -> Result<Self, DBError> {
-        use std::fmt::Write;
+#[derive(Debug, Clone)]
+enum InfoLoc {
+    // Range in `buf`.
+    Range(Range<usize>),
+    // Row id in the database.
+    Id(i64),
+}
 
-        let mut buf = String::new();
-        let mut cache = HashMap::new();
-        db.select_all_nar(NarStatus::Available, |_, mut nar| {
-            nar.meta.url = format!("nar/{}", nar.store_path.hash_str());
+/// `Content-Type` of a NAR file by its compression.
+fn content_type(compression: Option<&str>) -> &'static str {
+    match compression {
+        None | Some("none") => "application/x-nix-nar",
+        Some("xz") => "application/x-xz",
+        Some("zstd") => "application/zstd",
+        Some("bzip2") => "application/x-bzip2",
+        Some(_) => "application/octet-stream",
+    }
+}
 
-            let start = buf.len();
-            write!(&mut buf, "{}", nar.format_nar_info()).unwrap();
-            let end = buf.len();
-
-            cache.insert(
-                nar.store_path.hash(),
-                CacheItem {
-                    info_range: start..end,
-                    file_size: nar.meta.file_size.unwrap_or(nar.meta.nar_size),
-                },
-            );
+/// Narinfo as served, with URL pointing to our NAR file.
+fn render_nar_info(nar: &Nar) -> String {
+    let mut nar = nar.clone();
+    nar.meta.url = format!("nar/{}", nar.store_path.hash_str());
+    let info = nar.format_nar_info().to_string();
+    info
+}
+
+impl NarInfoCache {
+    /// Keep all narinfos in memory if `on_demand_db` is `None`. Otherwise
+    /// only keep the index, and render narinfos from read connections to
+    /// the database at `on_demand_db` when requested.
+    pub fn init(db: &Database, on_demand_db: Option<&Path>) -> Result<Self, DBError> {
+        let mut this = Self {
+            buf: String::new(),
+            garbage: 0,
+            cache: HashMap::new(),
+            paths: Vec::new(),
+            max_seq: i64::MIN,
+            db: match on_demand_db {
+                Some(path) => Some(Arc::new(ReadPool::open(path)?)),
+                None => None,
+            },
+        };
+        // Applied while reading, without holding all rows at once.
+        let mut max_seq = this.max_seq;
+        db.select_nar_changed_after(this.max_seq, |seq, id, status, nar| {
+            max_seq = seq;
+            this.apply_change(id, status, &nar);
+        })?;
+        this.max_seq = max_seq;
+        Ok(this)
+    }
+
+    /// Select NARs changed since the last load, without touching the cache.
+    pub fn select_changes(&self, db: &Database) -> Result<Changes, DBError> {
+        let mut changes = Changes {
+            max_seq: self.max_seq,
+            nars: Vec::new(),
+        };
+        db.select_nar_changed_after(self.max_seq, |seq, id, status, nar| {
+            changes.max_seq = seq;
+            changes.nars.push((id, status, nar));
         })?;
+        Ok(changes)
+    }
+
+    /// Load NARs which became available in `changes`, and drop those which
+    /// are no longer available. Return the number of loaded ones.
+    pub fn apply_changes(&mut self, changes: Changes) -> usize {
+        let mut count = 0;
+        for (id, status, nar) in changes.nars {
+            if self.apply_change(id, status, &nar) {
+                count += 1;
+            }
+        }
+        self.max_seq = self.max_seq.max(changes.max_seq);
+        count
+    }
+
+    /// Load or drop a changed NAR by its status. Return whether it's loaded.
+    fn apply_change(&mut self, id: i64, status: NarStatus, nar: &Nar) -> bool {
+        if status == NarStatus::Available {
+            self.insert_available(id, nar);
+            true
+        } else {
+            self.remove(nar.store_path.hash_str());
+            false
+        }
+    }
+
+    /// Add or replace an available NAR with row `id`.
+    pub fn insert_available(&mut self, id: i64, nar: &Nar) {
+        let hash = nar.store_path.hash();
+        self.remove(hash.as_str());
 
-        Ok(Self { buf, cache })
+        let path_len = nar.store_path.path().len();
+        let (info, path_start) = if self.db.is_some() {
+            let start = self.buf.len();
+            self.buf.push_str(nar.store_path.path());
+            (InfoLoc::Id(id), start)
+        } else {
+            let start = self.buf.len();
+            self.buf.push_str(&render_nar_info(nar));
+            (
+                InfoLoc::Range(start..self.buf.len()),
+                start + "StorePath: ".len(),
+            )
+        };
+        let path = path_start..path_start + path_len;
+        debug_assert_eq!(&self.buf[path.clone()], nar.store_path.path());
+        self.paths.push(path.clone());
+        self.cache.insert(
+            hash,
+            CacheItem {
+                info,
+                path,
+                file_size: nar.meta.file_size.unwrap_or(nar.meta.nar_size),
+                content_type: content_type(nar.meta.compression.as_deref()),
+                nar_hash: nar.meta.nar_hash.clone(),
+            },
+        );
     }
 
-    pub fn get_info(&self, hash: &str) -> Option<&str> {
-        if hash.len() != StorePathHash::LEN {
-            return None;
+    /// Remove a NAR. Return whether it was present.
+    pub fn remove(&mut self, hash: &str) -> bool {
+        let hash = match hash.parse::<StorePathHash>() {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        let item = match self.cache.remove(&hash) {
+            Some(item) => item,
+            None => return false,
+        };
+        self.garbage += match &item.info {
+            InfoLoc::Range(range) => range.len(),
+            InfoLoc::Id(_) => item.path.len(),
+        };
+        // Ranges are distinct, and `paths` is sorted by them.
+        if let Ok(idx) = self
+            .paths
+            .binary_search_by_key(&item.path.start, |r| r.start)
+        {
+            self.paths.remove(idx);
+        }
+        if self.garbage * 2 >= self.buf.len() {
+            self.compact();
         }
+        true
+    }
+
+    /// Drop garbage in `buf`, keeping the order of items.
+    fn compact(&mut self) {
+        let mut items: Vec<&mut CacheItem> = self.cache.values_mut().collect();
+        items.sort_by_key(|item| item.path.start);
+        let mut buf = String::with_capacity(self.buf.len() - self.garbage);
+        self.paths.clear();
+        for item in items {
+            let old = match &item.info {
+                InfoLoc::Range(range) => range.clone(),
+                InfoLoc::Id(_) => item.path.clone(),
+            };
+            let start = buf.len();
+            buf.push_str(&self.buf[old.clone()]);
+            let shift = |r: &Range<usize>| r.start - old.start + start..r.end - old.start + start;
+            if let InfoLoc::Range(range) = &mut item.info {
+                *range = shift(range);
+            }
+            item.path = shift(&item.path);
+            self.paths.push(item.path.clone());
+        }
+        self.buf = buf;
+        self.garbage = 0;
+    }
+
+    pub fn get_info(&self, hash: &str) -> Option<Cow<'_, str>> {
+        match &self.cache.get(&hash.parse::<StorePathHash>().ok()?)?.info {
+            // Never panic on a stale range.
+            InfoLoc::Range(range) => self.buf.get(range.clone()).map(Cow::Borrowed),
+            InfoLoc::Id(id) => match self.db.as_ref()?.with(|db| db.select_nar_by_id(*id)) {
+                Ok(nar) => nar.map(|nar| Cow::Owned(render_nar_info(&nar))),
+                Err(err) => {
+                    log::error!("Failed to render narinfo of {}: {}", hash, err);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Number of cached NARs.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Size, `Content-Type` and `NarHash` of a NAR file.
+    pub fn get_file(&self, hash: &str) -> Option<(u64, &'static str, &Hash)> {
         self.cache
-            .get(hash.as_bytes())
-            .map(|item| &self.buf[item.info_range.start..item.info_range.end])
+            .get(&hash.parse::<StorePathHash>().ok()?)
+            .map(|item| (item.file_size, item.content_type, &item.nar_hash))
+    }
+
+    /// Store paths of cached NARs in `range` of indices.
+    pub fn store_paths(&self, range: Range<usize>) -> impl Iterator<Item = &str> {
+        let end = range.end.min(self.paths.len());
+        let start = range.start.min(end);
+        self.paths[start..end]
+            .iter()
+            .map(move |r| &self.buf[r.clone()])
     }
+}
 
-    pub fn get_file_size(&self, hash: &str) -> Option<u64> {
-        if hash.len() != StorePathHash::LEN {
-            return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_nar;
+
+    #[test]
+    fn test_insert_remove() {
+        let db = Database::open_in_memory().unwrap();
+        let mut cache = NarInfoCache::init(&db, None).unwrap();
+        for (id, c) in "abcd".chars().enumerate() {
+            let path = format!("/nix/store/{}-{}", c.to_string().repeat(32), c);
+            cache.insert_available(id as i64, &test_nar(&path, 1, ""));
+        }
+        let paths = |cache: &NarInfoCache| cache.store_paths(0..10).collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            paths(&cache),
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a \
+             /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b \
+             /nix/store/cccccccccccccccccccccccccccccccc-c \
+             /nix/store/dddddddddddddddddddddddddddddddd-d",
+        );
+
+        let b = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+        let d = "dddddddddddddddddddddddddddddddd";
+        let d_info = cache.get_info(d).unwrap().into_owned();
+        assert!(cache.remove(b));
+        assert!(!cache.remove(b));
+        assert_eq!(cache.get_info(b), None);
+        // Compacted after removing a half.
+        assert!(cache.remove("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+        assert_eq!(cache.garbage, 0);
+        assert_eq!(cache.get_info(d).unwrap(), d_info);
+        assert_eq!(
+            paths(&cache),
+            "/nix/store/cccccccccccccccccccccccccccccccc-c \
+             /nix/store/dddddddddddddddddddddddddddddddd-d",
+        );
+
+        // Replaced ones are moved to the end.
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        cache.insert_available(5, &c);
+        assert_eq!(
+            paths(&cache),
+            "/nix/store/dddddddddddddddddddddddddddddddd-d \
+             /nix/store/cccccccccccccccccccccccccccccccc-c",
+        );
+        let (file_size, content_type, nar_hash) = cache.get_file(d).unwrap();
+        assert_eq!((file_size, content_type), (1, "application/x-nix-nar"));
+        assert_eq!(*nar_hash, c.meta.nar_hash);
+    }
+
+    #[test]
+    fn test_on_demand_concurrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut db = Database::open(&db_path).unwrap();
+        let nar = test_nar(
+            "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10",
+            1,
+            "",
+        );
+        db.insert_or_ignore_nars(NarStatus::Available, &[nar.clone()])
+            .unwrap();
+        let cache = Arc::new(NarInfoCache::init(&db, Some(&db_path)).unwrap());
+
+        let threads = (0..4)
+            .map(|_| {
+                let (cache, expect) = (cache.clone(), render_nar_info(&nar));
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let info = cache.get_info("yhzvzdq82lzk0kvrp3i79yhjnhps6qpk");
+                        assert_eq!(info.unwrap(), expect);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let idle = cache.db.as_ref().unwrap().idle.lock().unwrap().len();
+        assert!(1 <= idle && idle <= 4);
+    }
+
+    /// Resident memory of the process in bytes.
+    fn resident_memory() -> usize {
+        let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: usize = statm.split(' ').nth(1).unwrap().parse().unwrap();
+        pages * 4096
+    }
+
+    #[test]
+    #[ignore]
+    fn test_memory_1m() {
+        use crate::update::nix_base32_encode;
+
+        const ROWS: u32 = 1_000_000;
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut db = Database::open(&db_path).unwrap();
+        for chunk in (0..ROWS).collect::<Vec<_>>().chunks(10_000) {
+            let nars = chunk
+                .iter()
+                .map(|i| {
+                    let mut hash = [0u8; 20];
+                    hash[..4].copy_from_slice(&i.to_le_bytes());
+                    let path = format!("/nix/store/{}-pkg-{}", nix_base32_encode(&hash), i);
+                    test_nar(&path, 1, "")
+                })
+                .collect::<Vec<_>>();
+            db.insert_or_ignore_nars(NarStatus::Available, &nars)
+                .unwrap();
+        }
+
+        // Both are kept, so that freed memory is not reused.
+        let mut caches = vec![];
+        for on_demand in [Some(&*db_path), None].iter() {
+            let before = resident_memory();
+            caches.push(NarInfoCache::init(&db, *on_demand).unwrap());
+            assert_eq!(caches.last().unwrap().len(), ROWS as usize);
+            eprintln!(
+                "on_demand: {}, resident memory: {} MiB",
+                on_demand.is_some(),
+                (resident_memory() - before) >> 20,
+            );
         }
-        self.cache.get(hash.as_bytes()).map(|item| item.file_size)
     }
 }
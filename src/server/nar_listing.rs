@@ -0,0 +1,300 @@
+use serde_json::{json, Map, Value};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+// Names and symlink targets longer than this are rejected.
+const MAX_STR_LEN: u64 = 4096;
+// Directories nested deeper than this are rejected, to bound the recursion.
+const MAX_DEPTH: usize = 256;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid NAR: {}", msg))
+}
+
+/// A minimal NAR reader, which skips file contents.
+struct NarReader<R> {
+    inner: R,
+    // Position in the uncompressed NAR.
+    offset: u64,
+}
+
+impl<R: Read> NarReader<R> {
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf)?;
+        self.offset += 8;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // Strings are padded with zeros to multiples of 8 bytes.
+    fn read_padding(&mut self, len: u64) -> io::Result<()> {
+        let mut buf = [0u8; 8];
+        let pad = &mut buf[..((8 - len % 8) % 8) as usize];
+        self.inner.read_exact(pad)?;
+        self.offset += pad.len() as u64;
+        if pad.iter().any(|&b| b != 0) {
+            return Err(invalid("non-zero padding"));
+        }
+        Ok(())
+    }
+
+    fn read_str(&mut self) -> io::Result<String> {
+        let len = self.read_u64()?;
+        if len > MAX_STR_LEN {
+            return Err(invalid("string too long"));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        self.offset += len;
+        self.read_padding(len)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn expect(&mut self, tag: &str) -> io::Result<()> {
+        if self.read_str()? != tag {
+            return Err(invalid(&format!("expecting '{}'", tag)));
+        }
+        Ok(())
+    }
+
+    /// Skip the contents of a regular file. Return its size and offset.
+    fn skip_contents(&mut self) -> io::Result<(u64, u64)> {
+        let len = self.read_u64()?;
+        let offset = self.offset;
+        let skipped = io::copy(&mut (&mut self.inner).take(len), &mut io::sink())?;
+        if skipped != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.offset += len;
+        self.read_padding(len)?;
+        Ok((len, offset))
+    }
+
+    /// Read a node nested in `depth` directories.
+    fn read_node(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("too deeply nested"));
+        }
+        self.expect("(")?;
+        self.expect("type")?;
+        let node = match &*self.read_str()? {
+            "regular" => {
+                let mut tag = self.read_str()?;
+                let executable = tag == "executable";
+                if executable {
+                    self.expect("")?;
+                    tag = self.read_str()?;
+                }
+                if tag != "contents" {
+                    return Err(invalid("expecting 'contents'"));
+                }
+                let (size, offset) = self.skip_contents()?;
+                let mut node = json!({
+                    "type": "regular",
+                    "size": size,
+                    "narOffset": offset,
+                });
+                if executable {
+                    node["executable"] = true.into();
+                }
+                node
+            }
+            "symlink" => {
+                self.expect("target")?;
+                json!({
+                    "type": "symlink",
+                    "target": self.read_str()?,
+                })
+            }
+            "directory" => {
+                let mut entries = Map::new();
+                loop {
+                    match &*self.read_str()? {
+                        ")" => break,
+                        "entry" => {}
+                        _ => return Err(invalid("expecting 'entry'")),
+                    }
+                    self.expect("(")?;
+                    self.expect("name")?;
+                    let name = self.read_str()?;
+                    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                        return Err(invalid("bad entry name"));
+                    }
+                    self.expect("node")?;
+                    let node = self.read_node(depth + 1)?;
+                    self.expect(")")?;
+                    entries.insert(name, node);
+                }
+                // The closing parenthesis is already consumed.
+                return Ok(json!({
+                    "type": "directory",
+                    "entries": entries,
+                }));
+            }
+            _ => return Err(invalid("unknown node type")),
+        };
+        self.expect(")")?;
+        Ok(node)
+    }
+}
+
+/// List files inside an uncompressed NAR, in the JSON format of `<hash>.ls`.
+pub fn list_nar(reader: impl Read) -> io::Result<Value> {
+    let mut reader = NarReader {
+        inner: reader,
+        offset: 0,
+    };
+    reader.expect("nix-archive-1")?;
+    let root = reader.read_node(0)?;
+    Ok(json!({
+        "version": 1,
+        "root": root,
+    }))
+}
+
+/// List files inside the NAR file at `path` compressed by `compression`.
+/// Return `None` for unsupported compressions.
+pub fn list_nar_file(path: &Path, compression: Option<&str>) -> io::Result<Option<Value>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match compression {
+        None | Some("none") => Box::new(file),
+        Some("xz") => Box::new(XzDecoder::new(file)),
+        Some("zstd") => Box::new(ZstdDecoder::with_buffer(file)?),
+        Some(_) => return Ok(None),
+    };
+    list_nar(reader).map(Some)
+}
+
+/// Build a NAR from its strings, for tests.
+#[cfg(test)]
+fn build_nar(strs: &[&[u8]]) -> Vec<u8> {
+    let mut buf = vec![];
+    for s in strs {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s);
+        buf.resize((buf.len() + 7) / 8 * 8, 0);
+    }
+    buf
+}
+
+/// A NAR of a directory with an executable, a symlink and a nested file.
+#[cfg(test)]
+#[rustfmt::skip]
+pub(super) fn test_nar_dir() -> Vec<u8> {
+    build_nar(&[
+        b"nix-archive-1",
+        b"(", b"type", b"directory",
+        b"entry", b"(", b"name", b"bin", b"node",
+            b"(", b"type", b"directory",
+            b"entry", b"(", b"name", b"hello", b"node",
+                b"(", b"type", b"regular", b"executable", b"", b"contents", b"#!/bin/sh\n", b")",
+            b")",
+            b")",
+        b")",
+        b"entry", b"(", b"name", b"lib", b"node",
+            b"(", b"type", b"symlink", b"target", b"/nix/store/foo", b")",
+        b")",
+        b")",
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_nar() {
+        let listing = list_nar(&test_nar_dir()[..]).unwrap();
+        assert_eq!(
+            listing,
+            json!({
+                "version": 1,
+                "root": {
+                    "type": "directory",
+                    "entries": {
+                        "bin": {
+                            "type": "directory",
+                            "entries": {
+                                "hello": {
+                                    "type": "regular",
+                                    "size": 10,
+                                    "executable": true,
+                                    "narOffset": 400,
+                                },
+                            },
+                        },
+                        "lib": {
+                            "type": "symlink",
+                            "target": "/nix/store/foo",
+                        },
+                    },
+                },
+            }),
+        );
+
+        let file = build_nar(&[
+            b"nix-archive-1",
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            b"hello",
+            b")",
+        ]);
+        assert_eq!(
+            list_nar(&file[..]).unwrap()["root"],
+            json!({ "type": "regular", "size": 5, "narOffset": 96 }),
+        );
+        // Truncated, or not a NAR.
+        assert!(list_nar(&file[..file.len() - 8]).is_err());
+        assert!(list_nar(&b"hello"[..]).is_err());
+        let bad_name = build_nar(&[
+            b"nix-archive-1",
+            b"(",
+            b"type",
+            b"directory",
+            b"entry",
+            b"(",
+            b"name",
+            b"..",
+            b"node",
+            b"(",
+            b"type",
+            b"symlink",
+            b"target",
+            b"x",
+            b")",
+            b")",
+            b")",
+        ]);
+        assert!(list_nar(&bad_name[..]).is_err());
+
+        let nested = |depth: usize| {
+            let mut strs: Vec<&[u8]> = vec![b"nix-archive-1"];
+            for _ in 0..depth {
+                strs.extend(&[
+                    &b"("[..],
+                    b"type",
+                    b"directory",
+                    b"entry",
+                    b"(",
+                    b"name",
+                    b"d",
+                    b"node",
+                ]);
+            }
+            strs.extend(&[&b"("[..], b"type", b"directory", b")"]);
+            for _ in 0..depth {
+                strs.extend(&[&b")"[..], b")"]);
+            }
+            build_nar(&strs)
+        };
+        assert!(list_nar(&nested(MAX_DEPTH)[..]).is_ok());
+        assert!(list_nar(&nested(MAX_DEPTH + 1)[..]).is_err());
+    }
+}
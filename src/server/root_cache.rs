@@ -0,0 +1,66 @@
+use crate::database::{
+    model::{RootStatus, StorePath},
+    Database, Error as DBError,
+};
+use std::{collections::HashMap, io::Write};
+use xz2::write::XzEncoder;
+
+/// Top-level paths of all roots, and channel files of available ones
+/// served under `/channels/<root-id>/`.
+#[derive(Debug, Default)]
+pub struct RootCache {
+    roots: HashMap<i64, CachedRoot>,
+}
+
+#[derive(Debug)]
+pub struct CachedRoot {
+    pub git_revision: Option<String>,
+    pub paths: Vec<String>,
+    /// Only for available roots.
+    pub store_paths_xz: Option<Vec<u8>>,
+}
+
+impl RootCache {
+    pub fn init(db: &Database) -> Result<Self, DBError> {
+        let mut rows = vec![];
+        db.select_all_root(|id, root| rows.push((id, root)))?;
+
+        let mut roots = HashMap::new();
+        for (id, root) in rows {
+            let paths = db.select_root_paths(id)?;
+            let store_paths_xz = if root.status == RootStatus::Available {
+                Some(dump_store_paths(&paths))
+            } else {
+                None
+            };
+            roots.insert(
+                id,
+                CachedRoot {
+                    git_revision: root.git_revision,
+                    paths: paths.iter().map(|path| path.path().to_owned()).collect(),
+                    store_paths_xz,
+                },
+            );
+        }
+        Ok(Self { roots })
+    }
+
+    pub fn get(&self, root_id: i64) -> Option<&CachedRoot> {
+        self.roots.get(&root_id)
+    }
+
+    /// Get an available root which can be served as a channel.
+    pub fn get_channel(&self, root_id: i64) -> Option<&CachedRoot> {
+        self.get(root_id)
+            .filter(|root| root.store_paths_xz.is_some())
+    }
+}
+
+/// Newline separated store paths, xz compressed, as `store-paths.xz` of a channel.
+pub fn dump_store_paths(paths: &[StorePath]) -> Vec<u8> {
+    let mut enc = XzEncoder::new(vec![], 6);
+    for path in paths {
+        writeln!(enc, "{}", path).unwrap();
+    }
+    enc.finish().unwrap()
+}
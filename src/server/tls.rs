@@ -0,0 +1,65 @@
+use futures01::{sync::mpsc, Future as _, Stream};
+use log;
+use std::{fs, io, path::PathBuf, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    timer::Timeout,
+};
+use tokio_tls::{TlsAcceptor, TlsStream};
+
+use super::Error;
+
+// Clients never finishing the handshake are dropped after this, whatever the idle timeout is.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM encoded PKCS #8 private key.
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub(super) fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))
+        };
+        let (cert, key) = (read(&self.cert_path)?, read(&self.key_path)?);
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        let acceptor =
+            native_tls::TlsAcceptor::new(identity).map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(acceptor.into())
+    }
+}
+
+/// Handshake on incoming connections, each in its own task so that stalled
+/// ones never block accepting others. Failed ones are dropped.
+/// Must be polled inside the runtime.
+pub(super) fn accept<S>(
+    incoming: S,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = TlsStream<S::Item>, Error = io::Error>
+where
+    S: Stream<Error = io::Error>,
+    S::Item: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded();
+    let spawned = incoming.filter_map(move |conn| {
+        let tx = tx.clone();
+        let handshake = Timeout::new(acceptor.accept(conn), HANDSHAKE_TIMEOUT).then(move |ret| {
+            match ret {
+                Ok(conn) => {
+                    let _ = tx.unbounded_send(conn);
+                }
+                Err(err) => log::debug!("TLS handshake failed: {}", err),
+            }
+            Ok(())
+        });
+        tokio::spawn(handshake);
+        None
+    });
+    // The sender is kept by `spawned`, so `rx` never ends before it.
+    spawned.select(rx.map_err(|()| unreachable!()))
+}
@@ -0,0 +1,410 @@
+use crate::{
+    database::{
+        model::{Nar, NarStatus, StorePathHash},
+        Database, Error as DBError,
+    },
+    update::verify_nar,
+};
+use async_std::{fs, io::prelude::*};
+use futures::{channel::oneshot, compat::Stream01CompatExt as _, StreamExt as _};
+use hyper::{header, Body, StatusCode};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{simple_response, Error, Request, Response, ServerData, TryResponse};
+
+const MAX_NAR_INFO_LEN: usize = 64 << 10; // 64 KiB
+
+// Uploaded NAR files waiting for their narinfos, inside `nar_file_dir`.
+const STAGING_DIR: &str = ".upload";
+
+// How often the staging directory is checked for expired files.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Required as `Authorization: Bearer <token>`, or as the password of
+    /// basic authentication with any user name.
+    pub token: String,
+    /// The database to save uploaded narinfos, through a separate connection.
+    pub db_path: PathBuf,
+    /// Size limit of NAR files uploaded before their narinfos, or whose
+    /// narinfos have no `FileSize`.
+    pub max_orphan_size: u64,
+    /// NAR files waiting for their narinfos longer than this are removed.
+    pub staged_ttl: Duration,
+}
+
+pub(super) struct Uploader {
+    token: String,
+    store_dir: String,
+    staging_dir: PathBuf,
+    max_orphan_size: u64,
+    staged_ttl: Duration,
+    last_expire: Mutex<Option<Instant>>,
+    db: Arc<Mutex<Database>>,
+}
+
+impl Uploader {
+    pub(super) fn new(
+        config: &UploadConfig,
+        store_dir: &str,
+        nar_file_dir: &Path,
+    ) -> Result<Self, Error> {
+        let staging_dir = nar_file_dir.join(STAGING_DIR);
+        if let Err(err) = std::fs::create_dir_all(&staging_dir) {
+            return Err(Error::NarFileDir(staging_dir.display().to_string(), err));
+        }
+        Ok(Self {
+            token: config.token.clone(),
+            store_dir: store_dir.to_owned(),
+            staging_dir,
+            max_orphan_size: config.max_orphan_size,
+            staged_ttl: config.staged_ttl,
+            last_expire: Mutex::new(None),
+            db: Arc::new(Mutex::new(Database::open(&config.db_path)?)),
+        })
+    }
+
+    /// Remove staged files not modified within `staged_ttl`, at most once
+    /// per `EXPIRE_INTERVAL`.
+    async fn expire_staged(&self) {
+        {
+            let mut last = self.last_expire.lock().unwrap();
+            match *last {
+                Some(t) if t.elapsed() < EXPIRE_INTERVAL => return,
+                _ => {}
+            }
+            *last = Some(Instant::now());
+        }
+        let mut entries = match fs::read_dir(&self.staging_dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::error!("Cannot read '{}': {}", self.staging_dir.display(), err);
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        while let Some(entry) = entries.next().await {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    log::error!("Cannot read '{}': {}", self.staging_dir.display(), err);
+                    return;
+                }
+            };
+            let expired = match fs::metadata(&path).await.and_then(|meta| meta.modified()) {
+                Ok(mtime) => now.duration_since(mtime).unwrap_or_default() >= self.staged_ttl,
+                // Finished or removed meanwhile.
+                Err(_) => false,
+            };
+            if expired {
+                match fs::remove_file(&path).await {
+                    Ok(()) => log::info!("Removed expired upload '{}'", path.display()),
+                    Err(err) => log::error!("Cannot remove '{}': {}", path.display(), err),
+                }
+            }
+        }
+    }
+
+    fn authorized(&self, req: &Request) -> bool {
+        use ring::constant_time::verify_slices_are_equal;
+
+        let auth = match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(auth) => auth,
+            None => return false,
+        };
+        let token = if let Some(token) = auth.strip_prefix("Bearer ") {
+            token.trim().as_bytes().to_vec()
+        } else if let Some(cred) = auth.strip_prefix("Basic ") {
+            match base64::decode(cred.trim()) {
+                Ok(cred) => match cred.iter().position(|&b| b == b':') {
+                    Some(pos) => cred[pos + 1..].to_vec(),
+                    None => return false,
+                },
+                Err(_) => return false,
+            }
+        } else {
+            return false;
+        };
+        verify_slices_are_equal(&token, self.token.as_bytes()).is_ok()
+    }
+}
+
+/// Run `f` on its own thread, since it may block waiting for a busy database.
+async fn with_db<T: Send + 'static>(
+    up: &Uploader,
+    f: impl FnOnce(&mut Database) -> Result<T, DBError> + Send + 'static,
+) -> Result<T, DBError> {
+    let (tx, rx) = oneshot::channel();
+    let db = up.db.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(f(&mut db.lock().unwrap()));
+    });
+    rx.await.expect("Database thread panicked")
+}
+
+fn db_error(err: DBError) -> Response {
+    log::error!("Database error: {}", err);
+    simple_response(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+}
+
+fn text_response(status: StatusCode, body: String) -> Response {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Check that uploads are enabled and the request is authorized.
+fn uploader<'a>(data: &'a ServerData, req: &Request) -> Result<&'a Uploader, Box<Response>> {
+    let up = match &data.uploader {
+        Some(up) => up,
+        None => {
+            let allowed = [hyper::Method::GET, hyper::Method::HEAD];
+            return Err(Box::new(super::method_not_allowed(&allowed)));
+        }
+    };
+    if !up.authorized(req) {
+        let mut resp = simple_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+        resp.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static("Basic realm=\"nix-cache-mirror\""),
+        );
+        return Err(Box::new(resp));
+    }
+    Ok(up)
+}
+
+/// Save an uploaded narinfo, linked to the upload root. The NAR becomes
+/// available if its file is already uploaded to its URL, or later when it is.
+pub(super) async fn put_nar_info(data: &ServerData, req: Request, hash: &str) -> TryResponse {
+    let up = match uploader(data, &req) {
+        Ok(up) => up,
+        Err(resp) => return Ok(*resp),
+    };
+
+    let mut body = req.into_body().compat();
+    let mut buf = vec![];
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > MAX_NAR_INFO_LEN {
+            return Ok(simple_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body too large",
+            ));
+        }
+    }
+    let nar = match std::str::from_utf8(&buf)
+        .map_err(|err| err.to_string())
+        .and_then(|info| Nar::parse_nar_info(info).map_err(|err| err.to_string()))
+    {
+        Ok(nar) => nar,
+        Err(err) => return Ok(text_response(StatusCode::BAD_REQUEST, err)),
+    };
+    if nar.store_path.hash_str() != hash || nar.store_path.root() != up.store_dir {
+        return Ok(simple_response(
+            StatusCode::BAD_REQUEST,
+            "Store path mismatches the URL or StoreDir",
+        ));
+    }
+    let staged_name = match nar.meta.url.strip_prefix("nar/") {
+        Some(name) if is_valid_file_name(name) => name.to_owned(),
+        _ => return Ok(simple_response(StatusCode::BAD_REQUEST, "Invalid URL")),
+    };
+
+    if is_served(data, hash) {
+        return Ok(simple_response(StatusCode::OK, "Already available"));
+    }
+    let nar = Arc::new(nar);
+    let id = {
+        let nar = nar.clone();
+        let ret = with_db(up, move |db| {
+            let hash = nar.store_path.hash();
+            let id = match db.select_nar_id_by_hash(&hash)? {
+                Some(id) => Some(id),
+                None => {
+                    db.insert_or_ignore_nars_from(NarStatus::Pending, vec![(&*nar, None)])?;
+                    db.select_nar_id_by_hash(&hash)?
+                }
+            };
+            match id {
+                Some(id) => db.link_uploaded_nar(id).map(|()| Some(id)),
+                None => Ok(None),
+            }
+        })
+        .await;
+        match ret {
+            Ok(Some(id)) => id,
+            // Trashed but not yet collected.
+            Ok(None) => return Ok(simple_response(StatusCode::CONFLICT, "NAR is trashed")),
+            Err(err) => return Ok(db_error(err)),
+        }
+    };
+
+    let staged = up.staging_dir.join(&staged_name);
+    if fs::metadata(&staged).await.is_err() {
+        return Ok(simple_response(
+            StatusCode::ACCEPTED,
+            "Waiting for NAR file",
+        ));
+    }
+    Ok(finish(data, up, id, nar, staged).await)
+}
+
+fn is_served(data: &ServerData, hash: &str) -> bool {
+    data.nar_info_cache()
+        .is_some_and(|cache| cache.get_file(hash).is_some())
+}
+
+/// The pending NAR whose narinfo has URL `nar/<name>`, if any.
+async fn pending_nar(up: &Uploader, name: &str) -> Result<Option<(i64, Nar)>, DBError> {
+    let url = format!("nar/{}", name);
+    with_db(up, move |db| db.select_pending_nar_by_url(&url)).await
+}
+
+/// Save an uploaded NAR file. It's checked and made available if a narinfo
+/// with URL `nar/<name>` is already uploaded. Otherwise it waits for one.
+pub(super) async fn put_nar_file(data: &ServerData, req: Request, name: &str) -> TryResponse {
+    let up = match uploader(data, &req) {
+        Ok(up) => up,
+        Err(resp) => return Ok(*resp),
+    };
+    if !is_valid_file_name(name) {
+        return Ok(simple_response(
+            StatusCode::BAD_REQUEST,
+            "Invalid file name",
+        ));
+    }
+    up.expire_staged().await;
+
+    let pending = match pending_nar(up, name).await {
+        Ok(pending) => pending,
+        Err(err) => return Ok(db_error(err)),
+    };
+    let max_size = pending
+        .as_ref()
+        .and_then(|(_, nar)| nar.meta.file_size)
+        .unwrap_or(up.max_orphan_size);
+
+    let staged = up.staging_dir.join(name);
+    let tmp = up.staging_dir.join(format!("{}.tmp", name));
+    if let Err(resp) = receive_file(req.into_body(), &tmp, max_size).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Ok(resp);
+    }
+    if let Err(err) = fs::rename(&tmp, &staged).await {
+        log::error!("Cannot rename '{}': {}", tmp.display(), err);
+        return Ok(simple_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Cannot save file",
+        ));
+    }
+
+    match pending {
+        Some((id, nar)) => Ok(finish(data, up, id, Arc::new(nar), staged).await),
+        None => Ok(simple_response(StatusCode::ACCEPTED, "Waiting for narinfo")),
+    }
+}
+
+/// Trash a NAR by its store path hash, stop serving it and remove its file.
+/// It's deleted from the database by the next garbage collection.
+pub(super) async fn delete_nar(data: &ServerData, req: Request, hash: &str) -> TryResponse {
+    let up = match uploader(data, &req) {
+        Ok(up) => up,
+        Err(resp) => return Ok(*resp),
+    };
+    let parsed = match hash.parse::<StorePathHash>() {
+        Ok(hash) => hash,
+        Err(_) => return Ok(simple_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    match with_db(up, move |db| db.trash_nar(&parsed)).await {
+        Ok(()) => {}
+        Err(DBError::NotFound) => {
+            return Ok(simple_response(StatusCode::NOT_FOUND, "Not found"));
+        }
+        Err(err) => return Ok(db_error(err)),
+    }
+    data.remove_nar(hash);
+
+    let path = data.nar_file_dir.join(hash);
+    match fs::remove_file(&path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => log::error!("Cannot remove '{}': {}", path.display(), err),
+    }
+    log::info!("Trashed {}", hash);
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+    Ok(resp)
+}
+
+fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+/// Write a request body to `path`, failing if it's larger than `max_size`.
+async fn receive_file(body: Body, path: &Path, max_size: u64) -> Result<(), Response> {
+    let internal_error = |err: std::io::Error| {
+        log::error!("Cannot write '{}': {}", path.display(), err);
+        simple_response(StatusCode::INTERNAL_SERVER_ERROR, "Cannot save file")
+    };
+    let mut file = fs::File::create(path).await.map_err(internal_error)?;
+    let mut body = body.compat();
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|_| simple_response(StatusCode::BAD_REQUEST, "Broken body"))?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(simple_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body too large",
+            ));
+        }
+        file.write_all(&chunk).await.map_err(internal_error)?;
+    }
+    file.sync_all().await.map_err(internal_error)?;
+    Ok(())
+}
+
+/// Check a staged NAR file against its narinfo, move it into `nar_file_dir`
+/// and make it available.
+async fn finish(
+    data: &ServerData,
+    up: &Uploader,
+    id: i64,
+    nar: Arc<Nar>,
+    staged: PathBuf,
+) -> Response {
+    // Hashing a large NAR takes a while. Don't block other requests.
+    let (tx, rx) = oneshot::channel();
+    {
+        let (staged, nar) = (staged.clone(), nar.clone());
+        std::thread::spawn(move || {
+            let _ = tx.send(verify_nar(&staged, &nar.meta).map_err(|err| err.to_string()));
+        });
+    }
+    if let Err(err) = rx.await.expect("Verifying thread panicked") {
+        let _ = fs::remove_file(&staged).await;
+        return text_response(StatusCode::BAD_REQUEST, err);
+    }
+
+    let path = data.nar_file_dir.join(nar.store_path.hash_str());
+    if let Err(err) = fs::rename(&staged, &path).await {
+        log::error!("Cannot move NAR file to '{}': {}", path.display(), err);
+        return simple_response(StatusCode::INTERNAL_SERVER_ERROR, "Cannot save file");
+    }
+    let ret = with_db(up, move |db| db.update_nar_status(id, NarStatus::Available)).await;
+    if let Err(err) = ret {
+        return db_error(err);
+    }
+    data.insert_nar(id, &nar);
+    log::info!("Uploaded {}", nar.store_path);
+    simple_response(StatusCode::CREATED, "Created")
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// A graph where an edge `a -> b` means `a` depends on `b`, or the reverse
+/// when built with swapped arguments.
+#[derive(Debug)]
+pub struct DepGraph<V> {
+    edges: HashMap<V, Vec<V>>,
+    inds: HashMap<V, usize>,
+}
+
+impl<V: Hash + Eq> Default for DepGraph<V> {
+    fn default() -> Self {
+        Self {
+            edges: Default::default(),
+            inds: Default::default(),
+        }
+    }
+}
+
+impl<V: Hash + Ord + Copy> DepGraph<V> {
+    pub fn add_node(&mut self, a: V) {
+        assert!(
+            self.edges.insert(a, Default::default()).is_none(),
+            "Add duplicated nodes",
+        );
+        self.inds.insert(a, 0);
+    }
+
+    pub fn add_dep(&mut self, a: V, b: V) {
+        self.edges.get_mut(&a).expect("No source vertex").push(b);
+        *self.inds.get_mut(&b).expect("No dest vertex") += 1;
+    }
+
+    /// Nodes without incoming edges, in ascending order.
+    pub fn sources(&self) -> Vec<V> {
+        let mut ret: Vec<V> = self
+            .inds
+            .iter()
+            .filter(|(_, &ind)| ind == 0)
+            .map(|(k, _)| *k)
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    /// Remove edges from a source node `a`, and return nodes which become
+    /// sources after that, in ascending order.
+    pub fn remove_source(&mut self, a: V) -> Vec<V> {
+        assert_eq!(self.inds[&a], 0, "Not a source vertex");
+        let mut ret = Vec::new();
+        for nxt in std::mem::take(self.edges.get_mut(&a).expect("No vertex")) {
+            let p = self.inds.get_mut(&nxt).unwrap();
+            *p -= 1;
+            if *p == 0 {
+                ret.push(nxt);
+            }
+        }
+        ret.sort();
+        ret
+    }
+
+    /// Sort nodes so that all edges go forward.
+    /// The result only depends on the graph, not the insertion order.
+    /// Fail with nodes in or behind cycles, in ascending order.
+    pub fn topo_sort(mut self) -> Result<Vec<V>, Vec<V>> {
+        let mut q = self.sources();
+        let mut lpos = 0usize;
+        while lpos != q.len() {
+            let c = q[lpos];
+            lpos += 1;
+            let nxts = self.remove_source(c);
+            q.extend(nxts);
+        }
+        if q.len() == self.inds.len() {
+            return Ok(q);
+        }
+        let mut rest: Vec<V> = self
+            .inds
+            .into_iter()
+            .filter(|(_, ind)| *ind != 0)
+            .map(|(k, _)| k)
+            .collect();
+        rest.sort();
+        Err(rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topo_sort_deterministic() {
+        let edges = [(5, 3), (5, 1), (4, 1), (3, 2), (1, 2), (6, 2)];
+        let build = |rev: bool| {
+            let mut g = DepGraph::default();
+            let mut nodes = vec![1, 2, 3, 4, 5, 6];
+            let mut edges = edges.to_vec();
+            if rev {
+                nodes.reverse();
+                edges.reverse();
+            }
+            for v in nodes {
+                g.add_node(v);
+            }
+            for (a, b) in edges {
+                g.add_dep(a, b);
+            }
+            g
+        };
+        let ord = build(false).topo_sort().unwrap();
+        assert_eq!(ord, vec![4, 5, 6, 1, 3, 2]);
+        for _ in 0..10 {
+            assert_eq!(build(true).topo_sort().unwrap(), ord);
+        }
+    }
+
+    #[test]
+    fn test_topo_sort_cycle() {
+        let mut g = DepGraph::default();
+        for v in 1..=4 {
+            g.add_node(v);
+        }
+        // 4 -> 1 <-> 2 -> 3
+        g.add_dep(4, 1);
+        g.add_dep(1, 2);
+        g.add_dep(2, 1);
+        g.add_dep(2, 3);
+        assert_eq!(g.topo_sort(), Err(vec![1, 2, 3]));
+    }
+}
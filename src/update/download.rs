@@ -0,0 +1,598 @@
+use crate::{
+    database::{model::*, Database},
+    util::Semaphore,
+};
+use async_std::{
+    fs,
+    io::{prelude::*, BufWriter},
+};
+use failure::{bail, ensure, format_err, ResultExt as _};
+use futures::{
+    channel::oneshot,
+    compat::{Future01CompatExt as _, Stream01CompatExt as _},
+    future::join_all,
+    stream::FuturesUnordered,
+    StreamExt as _,
+};
+use reqwest::{
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use super::{dep_graph::DepGraph, verify::verify_nar, Result, CLIENT};
+
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Maximum number of files downloading at the same time. This is separated
+    /// from the narinfo fetcher's, since NAR files are much larger.
+    pub concurrency: usize,
+    /// Buffer size for writing each file. The server reads files with its own
+    /// buffer, and only sees a file after it's completely downloaded.
+    pub write_buffer_len: usize,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            write_buffer_len: 1 << 20, // 1 MiB
+        }
+    }
+}
+
+/// A file to download by `download_files`.
+#[derive(Debug, Clone)]
+pub struct DownloadItem {
+    pub url: String,
+    pub path: PathBuf,
+    pub expected_size: Option<u64>,
+}
+
+/// Download files as `download_file` does, with at most `config.concurrency`
+/// ones at the same time. Return results in the same order as `items`.
+pub async fn download_files(
+    items: impl IntoIterator<Item = DownloadItem>,
+    config: &DownloadConfig,
+) -> Vec<Result<u64>> {
+    let sem = Semaphore::new(config.concurrency);
+    let sem = &sem;
+    join_all(items.into_iter().map(|item| async move {
+        let _guard = sem.acquire().await;
+        download_file_with(
+            &item.url,
+            &item.path,
+            item.expected_size,
+            config.write_buffer_len,
+        )
+        .await
+    }))
+    .await
+}
+
+/// Download all Pending NARs into `nar_file_dir` and mark them Available,
+/// dependencies first. Files are checked by `verify_nar`, and corrupted ones
+/// are removed and left Pending. Files already present with the expected size are not
+/// downloaded again. Return the number of NARs becoming Available.
+pub async fn download_nars(
+    db: &mut Database,
+    cache_url: &str,
+    nar_file_dir: &Path,
+    config: &DownloadConfig,
+) -> Result<usize> {
+    let mut nars = HashMap::new();
+    db.select_all_nar(NarStatus::Pending, |id, nar| {
+        nars.insert(id, nar);
+    })?;
+    let mut refs = Vec::new();
+    db.select_nar_refs(|nar_id, ref_id| refs.push((nar_id, ref_id)))?;
+    download_nar_set(db, nars, refs, cache_url, nar_file_dir, config).await
+}
+
+/// Download Pending NARs in the closure of a root into `nar_file_dir`.
+/// A NAR is only downloaded after all its dependencies are Available, and
+/// is marked Available right after its file is downloaded and verified, so an
+/// interrupted download still leaves a usable partial closure.
+/// The root is `Downloading` meanwhile, and becomes `Available` after all NARs
+/// in its closure are.
+/// Return the number of NARs downloaded.
+pub async fn download_closure(
+    db: &mut Database,
+    root_id: i64,
+    cache_url: &str,
+    nar_file_dir: &Path,
+    config: &DownloadConfig,
+) -> Result<usize> {
+    let mut nars = HashMap::new();
+    db.select_root_closure(root_id, NarStatus::Pending, |id, nar| {
+        nars.insert(id, nar);
+    })?;
+    let mut refs = Vec::new();
+    db.select_root_closure_refs(root_id, |nar_id, ref_id| refs.push((nar_id, ref_id)))?;
+    if !nars.is_empty() {
+        db.with_busy_retry_async(|db| db.set_root_status(root_id, RootStatus::Downloading))
+            .await?;
+    }
+    // Fails unless all of them are downloaded.
+    let downloaded = download_nar_set(db, nars, refs, cache_url, nar_file_dir, config).await?;
+    db.with_busy_retry_async(|db| db.set_root_status(root_id, RootStatus::Available))
+        .await?;
+    Ok(downloaded)
+}
+
+/// Download `nars` in the order of references `(nar_id, ref_id)`.
+/// References to NARs not in `nars` are ignored. NARs fetched from a fallback
+/// cache are downloaded from there instead of `cache_url`.
+async fn download_nar_set(
+    db: &mut Database,
+    nars: HashMap<i64, Nar>,
+    refs: Vec<(i64, i64)>,
+    cache_url: &str,
+    nar_file_dir: &Path,
+    config: &DownloadConfig,
+) -> Result<usize> {
+    // Edges go from dependencies to dependents, so sources are ready to download.
+    let mut graph = DepGraph::default();
+    for &id in nars.keys() {
+        graph.add_node(id);
+    }
+    for (nar_id, ref_id) in refs {
+        if nar_id != ref_id && nars.contains_key(&nar_id) && nars.contains_key(&ref_id) {
+            graph.add_dep(ref_id, nar_id);
+        }
+    }
+
+    log::info!(count = nars.len(); "Downloading {} NAR files", nars.len());
+    let mut ready = graph.sources();
+    let mut running = FuturesUnordered::new();
+    let (mut downloaded, mut failed) = (0usize, 0usize);
+    loop {
+        while running.len() < config.concurrency {
+            let id = match ready.pop() {
+                Some(id) => id,
+                None => break,
+            };
+            let nar: &Nar = &nars[&id];
+            // From the same cache as its narinfo.
+            let origin = db.select_nar_cache_url(id)?;
+            let url = format!(
+                "{}/{}",
+                origin.as_deref().unwrap_or(cache_url),
+                nar.meta.url,
+            );
+            let path = nar_file_dir.join(nar.store_path.hash_str());
+            let expected_size = nar.meta.file_size;
+            let buf_len = config.write_buffer_len;
+            let meta = nar.meta.clone();
+            running.push(async move {
+                let ret = match fs::metadata(&path).await {
+                    Ok(meta)
+                        if meta.is_file() && expected_size.map_or(true, |s| s == meta.len()) =>
+                    {
+                        log::debug!("File '{}' is already present", path.display());
+                        Ok(meta.len())
+                    }
+                    _ => download_file_with(&url, &path, expected_size, buf_len).await,
+                };
+                // Never make a corrupted file Available.
+                let ret = match ret {
+                    Ok(_) => match verify_nar_in_thread(path.clone(), meta).await {
+                        Ok(()) => Ok(()),
+                        Err(err) => {
+                            let _ = fs::remove_file(&path).await;
+                            Err(err)
+                        }
+                    },
+                    Err(err) => Err(err),
+                };
+                (id, url, ret)
+            });
+        }
+
+        let (id, url, ret) = match running.next().await {
+            Some(x) => x,
+            None => break,
+        };
+        match ret {
+            Ok(_) => {
+                db.with_busy_retry_async(|db| db.update_nar_status(id, NarStatus::Available))
+                    .await?;
+                downloaded += 1;
+                ready.extend(graph.remove_source(id));
+            }
+            Err(err) => {
+                // Dependents of it are never ready.
+                log::warn!(url:% = url, error:% = err; "Cannot download {}: {}", url, err);
+                failed += 1;
+            }
+        }
+    }
+
+    ensure!(
+        downloaded == nars.len(),
+        "{} NAR files failed to download, {} skipped",
+        failed,
+        nars.len() - downloaded - failed,
+    );
+    Ok(downloaded)
+}
+
+/// Run `verify_nar` on its own thread. Hashing a large NAR takes a while, and
+/// should not block other downloads.
+async fn verify_nar_in_thread(path: PathBuf, meta: NarMeta) -> Result<()> {
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(verify_nar(&path, &meta));
+    });
+    rx.await.expect("Verifying thread panicked")
+}
+
+/// Path of the temporary file while downloading to `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".tmp");
+    s.into()
+}
+
+/// Download `url` to `path` through a temporary file, so that `path` never
+/// exists with partial content. If `expected_size` is given, the body must
+/// have exactly that many bytes.
+/// The temporary file is removed on failure, and one left by an interrupted
+/// run is resumed if the server supports range requests.
+pub async fn download_file(url: &str, path: &Path, expected_size: Option<u64>) -> Result<u64> {
+    let buf_len = DownloadConfig::default().write_buffer_len;
+    download_file_with(url, path, expected_size, buf_len).await
+}
+
+async fn download_file_with(
+    url: &str,
+    path: &Path,
+    expected_size: Option<u64>,
+    buf_len: usize,
+) -> Result<u64> {
+    let tmp_path = tmp_path(path);
+    match download_to(url, &tmp_path, expected_size, buf_len).await {
+        Ok(size) => {
+            fs::rename(&tmp_path, path).await.with_context(|err| {
+                format_err!("Cannot rename to '{}': {}", path.display(), err)
+            })?;
+            Ok(size)
+        }
+        Err(err) => {
+            if let Err(err) = fs::remove_file(&tmp_path).await {
+                log::warn!("Cannot remove '{}': {}", tmp_path.display(), err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// GET `url` from byte `offset`, or from the start if `offset` is 0.
+async fn get_from(url: &str, offset: u64) -> Result<reqwest::r#async::Response> {
+    let mut req = CLIENT.get(url);
+    if offset != 0 {
+        req = req.header(RANGE, format!("bytes={}-", offset));
+    }
+    Ok(req.send().compat().await?)
+}
+
+/// The first byte position in `Content-Range` of a 206 response.
+fn content_range_start(resp: &reqwest::r#async::Response) -> Option<u64> {
+    let range = resp.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Download into `tmp_path`. A partial file left by an interrupted run is
+/// continued with a range request, or overwritten if the server ignores it.
+async fn download_to(
+    url: &str,
+    tmp_path: &Path,
+    expected_size: Option<u64>,
+    buf_len: usize,
+) -> Result<u64> {
+    let offset = match fs::metadata(tmp_path).await {
+        Ok(meta) if meta.is_file() && expected_size.map_or(true, |s| meta.len() < s) => meta.len(),
+        _ => 0,
+    };
+    let mut resp = get_from(url, offset).await?;
+    if offset != 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        resp = get_from(url, 0).await?;
+    }
+    let resp = resp.error_for_status()?;
+    let start = if resp.status() == StatusCode::PARTIAL_CONTENT {
+        let start = content_range_start(&resp);
+        ensure!(
+            start == Some(offset),
+            "Unexpected Content-Range from {}, requested from byte {}",
+            url,
+            offset,
+        );
+        log::info!(url:% = url, offset; "Resuming {} from byte {}", url, offset);
+        offset
+    } else {
+        0
+    };
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(start != 0)
+        .truncate(start == 0)
+        .open(tmp_path)
+        .await
+        .with_context(|err| format_err!("Cannot create '{}': {}", tmp_path.display(), err))?;
+    let mut file = BufWriter::with_capacity(buf_len, file);
+
+    let mut stream = resp.into_body().compat();
+    let mut size = start;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if let Some(expected) = expected_size {
+            if size > expected {
+                bail!("Body of {} is larger than expected {} bytes", url, expected);
+            }
+        }
+        file.write_all(&chunk).await?;
+    }
+    if let Some(expected) = expected_size {
+        ensure!(
+            size == expected,
+            "Body of {} is truncated, expect {} bytes, got {}",
+            url,
+            expected,
+            size,
+        );
+    }
+
+    file.flush().await?;
+    file.get_ref().sync_all().await?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_on,
+        tests::{test_nar, MockServer},
+        update::verify::sha256_str,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_download_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            let mut files = HashMap::new();
+            files.insert("/nar/hello".to_owned(), b"hello".to_vec());
+            let server = MockServer::start(files);
+            let url = format!("{}/nar/hello", server.url);
+
+            let path = dir_path.join("ok");
+            assert_eq!(download_file(&url, &path, Some(5)).await.unwrap(), 5);
+            assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+            for (name, size) in &[("short", Some(10)), ("long", Some(3)), ("missing", None)] {
+                let path = dir_path.join(name);
+                let url = if *name == "missing" {
+                    format!("{}/nar/missing", server.url)
+                } else {
+                    url.clone()
+                };
+                assert!(download_file(&url, &path, *size).await.is_err());
+                assert!(!path.exists());
+                assert!(!tmp_path(&path).exists());
+            }
+        });
+    }
+
+    #[test]
+    fn test_download_file_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            let mut files = HashMap::new();
+            files.insert("/nar/hello".to_owned(), b"hello world".to_vec());
+            let server = MockServer::start(files);
+            let url = format!("{}/nar/hello", server.url);
+            // The partial content differs from the remote one, to tell whether
+            // it is kept.
+            let download = |name: &str, partial: &[u8]| {
+                let path = dir_path.join(name);
+                std::fs::write(tmp_path(&path), partial).unwrap();
+                let url = url.clone();
+                async move {
+                    assert_eq!(download_file(&url, &path, Some(11)).await.unwrap(), 11);
+                    assert!(!tmp_path(&path).exists());
+                    std::fs::read(&path).unwrap()
+                }
+            };
+
+            // Ranges ignored.
+            assert_eq!(download("ignored", b"HELLO").await, b"hello world");
+
+            server.accept_ranges();
+            assert_eq!(download("resumed", b"HELLO").await, b"HELLO world");
+            // Not shorter than the expected size, or empty.
+            assert_eq!(download("full", b"HELLO WORLD").await, b"hello world");
+            assert_eq!(download("empty", b"").await, b"hello world");
+            // Unknown size and beyond the end, which is not satisfiable.
+            let path = dir_path.join("unsatisfiable");
+            std::fs::write(tmp_path(&path), b"HELLO WORLD!").unwrap();
+            assert_eq!(download_file(&url, &path, None).await.unwrap(), 11);
+            assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+            assert_eq!(server.hits("/nar/hello"), 6);
+        });
+    }
+
+    #[test]
+    fn test_download_files_concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            let files = (0..6)
+                .map(|i| (format!("/nar/{}", i), vec![i as u8; i + 1]))
+                .collect::<HashMap<_, _>>();
+            let server = MockServer::start_with_delay(files, Duration::from_millis(50));
+
+            let config = DownloadConfig {
+                concurrency: 2,
+                write_buffer_len: 4,
+            };
+            let items = (0..6).map(|i| DownloadItem {
+                url: format!("{}/nar/{}", server.url, i),
+                path: dir_path.join(i.to_string()),
+                expected_size: Some(i as u64 + 1),
+            });
+            let rets = download_files(items, &config).await;
+            for (i, ret) in rets.into_iter().enumerate() {
+                assert_eq!(ret.unwrap(), i as u64 + 1);
+                assert_eq!(
+                    std::fs::read(dir_path.join(i.to_string())).unwrap(),
+                    vec![i as u8; i + 1],
+                );
+            }
+            assert_eq!(server.max_in_flight(), 2);
+        });
+    }
+
+    /// A NAR served uncompressed at `nar/<hash>.nar`.
+    fn content_nar(path: &str, content: &[u8], references: &str) -> Nar {
+        let mut nar = test_nar(path, content.len() as u64, references);
+        nar.meta.url = format!("nar/{}.nar", nar.store_path.hash_str());
+        nar.meta.nar_hash = sha256_str(content).parse().unwrap();
+        nar.meta.nar_size = content.len() as u64;
+        nar
+    }
+
+    #[test]
+    fn test_download_closure() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            // a -> b -> c
+            let c = content_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", b"c", "");
+            let b = content_nar(
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+                b"bb",
+                "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b cccccccccccccccccccccccccccccccc-c",
+            );
+            let a = content_nar(
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+                b"aaa",
+                "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+            );
+            let mut db = Database::open_in_memory().unwrap();
+            db.insert_or_ignore_nars(NarStatus::Pending, vec![&c, &b, &a])
+                .unwrap();
+            let root_id = db
+                .insert_root(&Root::default(), vec![a.store_path.hash()])
+                .unwrap();
+            let available = |db: &Database| {
+                let mut v = vec![];
+                db.select_all_nar(NarStatus::Available, |_, nar| {
+                    v.push(nar.store_path.name().to_owned())
+                })
+                .unwrap();
+                v.sort();
+                v
+            };
+
+            // `b` is missing, so `a` must not be touched.
+            let mut files = HashMap::new();
+            files.insert(format!("/{}", c.meta.url), b"c".to_vec());
+            files.insert(format!("/{}", a.meta.url), b"aaa".to_vec());
+            let server = MockServer::start(files);
+            let config = DownloadConfig::default();
+            assert!(
+                download_closure(&mut db, root_id, &server.url, &dir_path, &config)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(available(&db), vec!["c"]);
+            assert_eq!(server.hits(&format!("/{}", a.meta.url)), 0);
+            let downloading = db.select_roots_by_status(RootStatus::Downloading).unwrap();
+            assert_eq!(downloading.len(), 1);
+            assert_eq!(downloading[0].id, root_id);
+            assert!(!dir_path.join(a.store_path.hash_str()).exists());
+
+            let mut files = HashMap::new();
+            files.insert(format!("/{}", c.meta.url), b"c".to_vec());
+            files.insert(format!("/{}", b.meta.url), b"bb".to_vec());
+            files.insert(format!("/{}", a.meta.url), b"aaa".to_vec());
+            let server = MockServer::start(files);
+            assert_eq!(
+                download_closure(&mut db, root_id, &server.url, &dir_path, &config)
+                    .await
+                    .unwrap(),
+                2,
+            );
+            assert_eq!(available(&db), vec!["a", "b", "c"]);
+            assert_eq!(server.hits(&format!("/{}", c.meta.url)), 0);
+            assert_eq!(
+                db.select_root(root_id).unwrap().root.status,
+                RootStatus::Available,
+            );
+            // Nothing left to download.
+            assert_eq!(
+                download_closure(&mut db, root_id, &server.url, &dir_path, &config)
+                    .await
+                    .unwrap(),
+                0,
+            );
+            assert!(db
+                .select_roots_by_status(RootStatus::Downloading)
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                std::fs::read(dir_path.join(a.store_path.hash_str())).unwrap(),
+                b"aaa",
+            );
+        });
+    }
+
+    #[test]
+    fn test_download_nars_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            let d = content_nar("/nix/store/dddddddddddddddddddddddddddddddd-d", b"ddd", "");
+            let mut db = Database::open_in_memory().unwrap();
+            db.insert_or_ignore_nars(NarStatus::Pending, vec![&d])
+                .unwrap();
+            let path = dir_path.join(d.store_path.hash_str());
+
+            let mut files = HashMap::new();
+            files.insert(format!("/{}", d.meta.url), b"ddx".to_vec());
+            let server = MockServer::start(files);
+            assert!(
+                download_nars(&mut db, &server.url, &dir_path, &DownloadConfig::default())
+                    .await
+                    .is_err()
+            );
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 1);
+            assert!(!path.exists());
+
+            // A good file already present is not downloaded again.
+            std::fs::write(&path, b"ddd").unwrap();
+            assert_eq!(
+                download_nars(&mut db, &server.url, &dir_path, &DownloadConfig::default())
+                    .await
+                    .unwrap(),
+                1,
+            );
+            assert_eq!(db.count_nars(NarStatus::Available).unwrap(), 1);
+            assert_eq!(server.hits(&format!("/{}", d.meta.url)), 1);
+        });
+    }
+}
@@ -1,30 +1,49 @@
 use crate::{
     database::{model::*, Database},
+    keys::TrustedKeys,
     spawn,
 };
 use failure::{ensure, format_err, ResultExt as _};
 use futures::{
     channel::{mpsc, oneshot},
     compat::Future01CompatExt as _,
+    future::{BoxFuture, Shared},
     prelude::*,
 };
 use log;
 use std::{
     collections::HashMap,
-    hash::Hash,
+    fmt,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
 use tokio::timer;
 
-use super::{get_all_to_string, Result};
+use super::{dep_graph::DepGraph, get_optional_string, Result, RetryConfig};
+
+/// A callback receiving `(finished, total)` narinfo counts while fetching.
+#[derive(Clone)]
+pub struct ProgressFn(Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl ProgressFn {
+    pub fn new(f: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for ProgressFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProgressFn")
+    }
+}
 
 #[derive(Debug)]
 struct Progress {
     state: Arc<ProgressState>,
+    on_progress: Option<ProgressFn>,
     // Drop it to stop.
     stopper_tx: Option<oneshot::Sender<()>>,
 }
@@ -33,38 +52,68 @@ struct Progress {
 struct ProgressState {
     finished: AtomicU64,
     total: AtomicU64,
+    // Held while reporting, so none is reported after the final one.
+    stopped: Mutex<bool>,
 }
 
 impl Progress {
-    const PROGRESS_TIMER_DELAY: Duration = Duration::from_secs(1);
-
-    async fn logger(state: Arc<ProgressState>, mut stopper_rx: oneshot::Receiver<()>) {
+    async fn reporter(
+        state: Arc<ProgressState>,
+        mut stopper_rx: oneshot::Receiver<()>,
+        interval: Duration,
+        report: impl Fn(u64, u64),
+    ) {
         // While timeout
-        while timer::Timeout::new((&mut stopper_rx).compat(), Self::PROGRESS_TIMER_DELAY)
+        while timer::Timeout::new((&mut stopper_rx).compat(), interval)
             .compat()
             .await
             .err()
             .map_or(false, |e| e.is_elapsed())
         {
-            log::info!(
-                "Fetching meta [{}/{}]",
+            let stopped = state.stopped.lock().unwrap();
+            if *stopped {
+                break;
+            }
+            report(
                 state.finished.load(Ordering::Relaxed),
                 state.total.load(Ordering::Relaxed),
             );
         }
     }
 
-    fn new() -> Self {
+    /// Report to `on_progress` if set, or log otherwise, every `interval`.
+    fn new(interval: Duration, on_progress: Option<ProgressFn>) -> Self {
         let state = Arc::new(ProgressState {
             finished: 0.into(),
             total: 0.into(),
+            stopped: Mutex::new(false),
         });
         let (stopper_tx, stopper_rx) = oneshot::channel();
-        if log::log_enabled!(log::Level::Info) {
-            spawn(Self::logger(state.clone(), stopper_rx));
+        match &on_progress {
+            Some(f) => {
+                let f = f.clone();
+                spawn(Self::reporter(
+                    state.clone(),
+                    stopper_rx,
+                    interval,
+                    move |finished, total| (f.0)(finished, total),
+                ));
+            }
+            None if log::log_enabled!(log::Level::Info) => {
+                spawn(Self::reporter(
+                    state.clone(),
+                    stopper_rx,
+                    interval,
+                    |finished, total| {
+                        log::info!(finished, total; "Fetching meta [{}/{}]", finished, total);
+                    },
+                ));
+            }
+            None => {}
         }
         Self {
             state,
+            on_progress,
             stopper_tx: Some(stopper_tx),
         }
     }
@@ -77,18 +126,105 @@ impl Progress {
         &self.state.finished
     }
 
+    /// Stop reporting. The callback is called once more with final counts.
     fn stop(&mut self) {
         self.stopper_tx = None;
+        *self.state.stopped.lock().unwrap() = true;
+        if let Some(f) = &self.on_progress {
+            (f.0)(
+                self.finished().load(Ordering::Relaxed),
+                self.total().load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+type SharedFetch = Shared<BoxFuture<'static, std::result::Result<Option<String>, String>>>;
+
+/// Narinfo fetches shared by concurrent `fetch_meta_rec` in one process, so
+/// that identical requests are sent only once. Successful results are kept
+/// until the last clone is dropped, since they may not be saved yet.
+#[derive(Clone, Default)]
+pub struct NarInfoShare {
+    fetches: Arc<Mutex<HashMap<String, SharedFetch>>>,
+}
+
+impl fmt::Debug for NarInfoShare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NarInfoShare")
+            .field("len", &self.fetches.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl NarInfoShare {
+    async fn fetch(&self, url: String, retry: RetryConfig) -> Result<Option<String>> {
+        let fut = self
+            .fetches
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_insert_with(|| {
+                let url = url.clone();
+                async move {
+                    get_optional_string(&url, &retry)
+                        .await
+                        .map_err(|err| err.to_string())
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+        match fut.await {
+            Ok(info) => Ok(info),
+            Err(err) => {
+                // Let later ones retry.
+                self.fetches.lock().unwrap().remove(&url);
+                Err(format_err!("{}", err))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    /// Maximum number of narinfos fetching at the same time.
+    pub concurrency: usize,
+    /// Reject narinfos not signed by any of them, if set.
+    pub keys: Option<TrustedKeys>,
+    pub retry: RetryConfig,
+    /// Caches tried in order when a narinfo is not found, or cannot be fetched
+    /// from the main one.
+    pub fallback_cache_urls: Vec<String>,
+    /// Interval between progress reports.
+    pub progress_interval: Duration,
+    /// Receive progress reports instead of logging them.
+    pub on_progress: Option<ProgressFn>,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 128,
+            keys: None,
+            retry: RetryConfig::default(),
+            fallback_cache_urls: vec![],
+            progress_interval: Duration::from_secs(1),
+            on_progress: None,
+        }
     }
 }
 
 struct Fetcher<'db> {
     db: &'db mut Database,
-    cache_url: Arc<str>,
+    // The main cache goes first.
+    cache_urls: Arc<[String]>,
+    share: Option<NarInfoShare>,
+    // Reject narinfos not signed by any of them, if set.
+    keys: Option<TrustedKeys>,
+    retry: RetryConfig,
     progress: Progress,
-    // None:      Fetching or present in database
-    // Some(nar): Fetched
-    nars: HashMap<StorePathHash, Option<Nar>>,
+    nars: HashMap<StorePathHash, NarState>,
     dep_graph: DepGraph<StorePathHash>,
 
     done_tx: Option<mpsc::Sender<QueueData>>,
@@ -98,23 +234,46 @@ struct Fetcher<'db> {
 }
 
 #[derive(Debug)]
-struct QueueData(StorePathHash, Result<String>, mpsc::Sender<QueueData>);
+enum NarState {
+    /// Fetching, or present in the database.
+    Pending,
+    /// With the index of the cache it's fetched from.
+    Fetched(Box<Nar>, usize),
+    /// Not found in the cache.
+    Missing,
+}
 
-impl<'db> Fetcher<'db> {
-    const MAX_CONCURRENT_FETCH: usize = 128;
+// The cache index and the narinfo, or `None` if not found in any cache.
+#[derive(Debug)]
+struct QueueData(
+    StorePathHash,
+    Result<Option<(usize, String)>>,
+    mpsc::Sender<QueueData>,
+);
 
-    fn new(db: &'db mut Database, cache_url: Arc<str>) -> Result<Self> {
-        let (done_tx, done_rx) = mpsc::channel(Self::MAX_CONCURRENT_FETCH);
+impl<'db> Fetcher<'db> {
+    fn new(
+        db: &'db mut Database,
+        cache_urls: Arc<[String]>,
+        share: Option<NarInfoShare>,
+        config: &FetchConfig,
+    ) -> Result<Self> {
+        ensure!(!cache_urls.is_empty(), "No cache to fetch from");
+        let concurrency = config.concurrency.max(1);
+        let (done_tx, done_rx) = mpsc::channel(concurrency);
         Ok(Self {
             db,
-            cache_url,
-            progress: Progress::new(),
+            cache_urls,
+            share,
+            keys: config.keys.clone(),
+            retry: config.retry,
+            progress: Progress::new(config.progress_interval, config.on_progress.clone()),
             nars: Default::default(),
             dep_graph: Default::default(),
             done_tx: Some(done_tx),
             done_rx,
             todo: vec![],
-            permits: Self::MAX_CONCURRENT_FETCH,
+            permits: concurrency,
         })
     }
 
@@ -124,7 +283,7 @@ impl<'db> Fetcher<'db> {
             return Ok(());
         }
         self.dep_graph.add_node(hash);
-        self.nars.insert(hash, None);
+        self.nars.insert(hash, NarState::Pending);
         if self.db.select_nar_id_by_hash(&hash)?.is_some() {
             // Already in database.
             return Ok(());
@@ -142,10 +301,11 @@ impl<'db> Fetcher<'db> {
             };
             self.permits -= 1;
 
-            let info_url = format!("{}/{}.narinfo", self.cache_url, hash);
             let done_tx = done_tx.clone();
+            let (cache_urls, share, retry) =
+                (self.cache_urls.clone(), self.share.clone(), self.retry);
             spawn(async move {
-                let ret = get_all_to_string(&info_url).await;
+                let ret = Self::fetch_one(hash, &cache_urls, share, retry).await;
                 // Channel only fails when main future done with errors.
                 // So just them ignore to suppress more errors.
                 let _ = done_tx.clone().send(QueueData(hash, ret, done_tx)).await;
@@ -153,9 +313,64 @@ impl<'db> Fetcher<'db> {
         }
     }
 
-    fn parse_one(&mut self, ret: Result<String>) -> Result<()> {
-        let nar = Nar::parse_nar_info(&ret?)?;
+    /// Try caches in order until one has the narinfo. Fail if none has it
+    /// and any of them fails.
+    async fn fetch_one(
+        hash: StorePathHash,
+        cache_urls: &[String],
+        share: Option<NarInfoShare>,
+        retry: RetryConfig,
+    ) -> Result<Option<(usize, String)>> {
+        let mut last_err = None;
+        for (i, cache_url) in cache_urls.iter().enumerate() {
+            let info_url = format!("{}/{}.narinfo", cache_url, hash);
+            let ret = match &share {
+                Some(share) => share.fetch(info_url.clone(), retry).await,
+                None => get_optional_string(&info_url, &retry).await,
+            };
+            match ret {
+                Ok(Some(info)) => return Ok(Some((i, info))),
+                Ok(None) => log::debug!("{} is not found", info_url),
+                Err(err) => {
+                    if i + 1 < cache_urls.len() {
+                        log::warn!(
+                            url:% = info_url, error:% = err;
+                            "Cannot fetch {}: {}, trying the next cache", info_url, err
+                        );
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        last_err.map_or(Ok(None), Err)
+    }
+
+    fn parse_one(
+        &mut self,
+        hash: StorePathHash,
+        ret: Result<Option<(usize, String)>>,
+    ) -> Result<()> {
+        let (origin, info) = match ret? {
+            Some(x) => x,
+            None => {
+                log::warn!(hash = hash.as_str(); "Narinfo of {} is not found, skipped", hash);
+                *self.nars.get_mut(&hash).expect("Already inserted") = NarState::Missing;
+                return Ok(());
+            }
+        };
+        let nar = Nar::parse_nar_info(&info)?;
+        if let Some(keys) = &self.keys {
+            ensure!(
+                nar.verify_signature(keys),
+                "No valid signature by trusted keys",
+            );
+        }
         let cur_hash = nar.store_path.hash();
+        ensure!(
+            cur_hash == hash,
+            "Got narinfo of another path {}",
+            nar.store_path,
+        );
         for hash in nar.ref_hashes() {
             let hash = hash?;
             if hash != cur_hash {
@@ -163,7 +378,8 @@ impl<'db> Fetcher<'db> {
                 self.dep_graph.add_dep(cur_hash, hash);
             }
         }
-        *self.nars.get_mut(&cur_hash).expect("Already inserted") = Some(nar);
+        *self.nars.get_mut(&cur_hash).expect("Already inserted") =
+            NarState::Fetched(Box::new(nar), origin);
         Ok(())
     }
 
@@ -184,7 +400,7 @@ impl<'db> Fetcher<'db> {
         while let Some(QueueData(hash, ret, done_tx)) = self.done_rx.next().await {
             self.permits += 1;
 
-            self.parse_one(ret)
+            self.parse_one(hash, ret)
                 .with_context(|err| format_err!("Failed to get {}: {}", hash, err))?;
             self.progress.finished().fetch_add(1, Ordering::Relaxed);
 
@@ -199,94 +415,334 @@ impl<'db> Fetcher<'db> {
         Ok(total)
     }
 
-    fn save_all(self) -> Result<()> {
+    /// Fetched and present NARs, with references before referrers.
+    fn topo_sort(&mut self) -> Result<Vec<StorePathHash>> {
+        std::mem::take(&mut self.dep_graph)
+            .topo_sort()
+            .map(|mut ord| {
+                ord.reverse();
+                ord
+            })
+            .map_err(|rest| {
+                let first = match &self.nars[&rest[0]] {
+                    NarState::Fetched(nar, _) => nar.store_path.to_string(),
+                    _ => rest[0].to_string(),
+                };
+                // Not all of them are in a cycle. Some are only referenced by one.
+                format_err!(
+                    "{} narinfos blocked by a reference cycle, including {}",
+                    rest.len(),
+                    first,
+                )
+            })
+    }
+
+    async fn save_all(self, topo_ord: Vec<StorePathHash>) -> Result<InsertSummary> {
         log::info!("Saving narinfos...");
         // Avoid over-capturing `self`
-        let nars = self.nars;
-        let topo_ord = self.dep_graph.topo_sort();
-        self.db.insert_or_ignore_nars(
-            NarStatus::Pending,
-            topo_ord
-                .iter()
-                .rev()
-                .filter_map(|&hash| nars[&hash].as_ref()),
-        )?;
-        Ok(())
+        let (nars, cache_urls) = (self.nars, self.cache_urls);
+        let summary = self
+            .db
+            .with_busy_retry_async(|db| {
+                db.insert_or_ignore_nars_from(
+                    NarStatus::Pending,
+                    topo_ord.iter().filter_map(|hash| match &nars[hash] {
+                        // Only record fallback ones. The main cache is the root's.
+                        NarState::Fetched(nar, origin) => {
+                            Some((&**nar, Some(&*cache_urls[*origin]).filter(|_| *origin != 0)))
+                        }
+                        _ => None,
+                    }),
+                )
+            })
+            .await?;
+        log::info!(
+            inserted = summary.inserted, skipped = summary.skipped;
+            "{} narinfos inserted, {} already present",
+            summary.inserted,
+            summary.skipped,
+        );
+        Ok(summary)
     }
 }
 
+/// NARs in the closure visited by `fetch_meta_rec`.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct FetchSummary {
+    /// Not in the database before. In dry run, the number of fetched ones.
+    pub new_nars: u64,
+    /// Total `FileSize` of `new_nars`.
+    pub new_file_size: u64,
+    pub existing_nars: u64,
+    /// Not found in the cache, in ascending order. References to them are
+    /// kept in narinfos, but not followed.
+    pub missing_nars: Vec<StorePathHash>,
+}
+
+/// Fetch narinfos of the closure of `root_hashes` and save new ones,
+/// or only fetch them if `dry_run` is set.
+/// If `config.keys` is set, fail on any narinfo without a valid signature by them.
+/// Narinfos not in `cache_url` are looked up in `config.fallback_cache_urls`.
 pub async fn fetch_meta_rec(
     db: &mut Database,
     cache_url: &str,
     root_hashes: Vec<StorePathHash>,
-) -> Result<()> {
+    dry_run: bool,
+    share: Option<&NarInfoShare>,
+    config: &FetchConfig,
+) -> Result<FetchSummary> {
     log::info!("Recursively fetching {} narinfo", root_hashes.len());
-    let mut fetcher = Fetcher::new(db, cache_url.into())?;
-    fetcher.fetch_all(root_hashes).await?;
-    fetcher.save_all()?;
-    log::info!("All paths saved");
-    Ok(())
+    let cache_urls: Vec<String> = std::iter::once(cache_url.to_owned())
+        .chain(config.fallback_cache_urls.iter().cloned())
+        .collect();
+    let mut fetcher = Fetcher::new(db, cache_urls.into(), share.cloned(), config)?;
+    let fetched = fetcher.fetch_all(root_hashes).await?;
+    // Also reject cycles in dry run, which would fail when saving.
+    let topo_ord = fetcher.topo_sort()?;
+    let mut missing_nars: Vec<StorePathHash> = fetcher
+        .nars
+        .iter()
+        .filter(|(_, state)| matches!(state, NarState::Missing))
+        .map(|(hash, _)| *hash)
+        .collect();
+    missing_nars.sort();
+    if !missing_nars.is_empty() {
+        log::warn!(
+            missing = missing_nars.len();
+            "{} paths are missing in the cache", missing_nars.len()
+        );
+    }
+    let visited = (fetcher.nars.len() - missing_nars.len()) as u64;
+    let mut new_nars = fetched - missing_nars.len() as u64;
+    let mut new_file_size = fetcher
+        .nars
+        .values()
+        .filter_map(|state| match state {
+            NarState::Fetched(nar, _) => nar.meta.file_size,
+            _ => None,
+        })
+        .sum();
+    if !dry_run {
+        // Others may insert some of them concurrently.
+        let summary = fetcher.save_all(topo_ord).await?;
+        new_nars = summary.inserted as u64;
+        new_file_size = summary.inserted_file_size;
+        log::info!("All paths saved");
+    }
+    Ok(FetchSummary {
+        new_nars,
+        new_file_size,
+        existing_nars: visited - new_nars,
+        missing_nars,
+    })
 }
 
-struct DepGraph<V> {
-    edges: HashMap<V, Vec<V>>,
-    inds: HashMap<V, usize>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, tests::MockServer};
+    use insta::assert_debug_snapshot;
+    use std::convert::TryFrom;
 
-impl<V: Hash + Eq> Default for DepGraph<V> {
-    fn default() -> Self {
-        Self {
-            edges: Default::default(),
-            inds: Default::default(),
+    #[test]
+    fn test_fetch_meta_rec_concurrency() {
+        let nar_info = |i: usize, refs: &str| {
+            format!(
+                "StorePath: /nix/store/{:0>32}-p{}\nURL: nar/{}.nar\nCompression: none\n\
+                 NarHash: sha256:{}\nNarSize: 1\nReferences: {}\n",
+                i,
+                i,
+                i,
+                "0".repeat(52),
+                refs,
+            )
+        };
+        // Path 0 refers to all others.
+        const N: usize = 20;
+        let refs: Vec<String> = (1..N).map(|i| format!("{:0>32}-p{}", i, i)).collect();
+        let mut files = HashMap::new();
+        for i in 0..N {
+            let refs = if i == 0 {
+                refs.join(" ")
+            } else {
+                String::new()
+            };
+            files.insert(
+                format!("/cache/{:0>32}.narinfo", i),
+                nar_info(i, &refs).into_bytes(),
+            );
         }
+
+        block_on(async move {
+            let server = MockServer::start_with_delay(files, Duration::from_millis(50));
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let config = FetchConfig {
+                concurrency: 4,
+                ..Default::default()
+            };
+            let mut fetcher = Fetcher::new(&mut db, vec![cache_url].into(), None, &config).unwrap();
+            assert_eq!(fetcher.permits, 4);
+            let root = StorePath::try_from(format!("/nix/store/{:0>32}-p0", 0))
+                .unwrap()
+                .hash();
+            fetcher.fetch_all(vec![root]).await.unwrap();
+            // All permits are returned.
+            assert_eq!(fetcher.permits, 4);
+            assert_eq!(fetcher.nars.len(), N);
+            assert_eq!(server.total_hits(), N);
+            assert_eq!(server.max_in_flight(), 4);
+        });
     }
-}
 
-impl<V: Hash + Eq + Copy> DepGraph<V> {
-    fn add_node(&mut self, a: V) {
-        assert!(
-            self.edges.insert(a, Default::default()).is_none(),
-            "Add duplicated nodes",
+    #[test]
+    fn test_save_all_inserted_concurrently() {
+        let nar_info = |i: usize, file_size: u64, refs: &str| {
+            format!(
+                "StorePath: /nix/store/{:0>32}-p{}\nURL: nar/{}.nar\nCompression: none\n\
+                 FileSize: {}\nNarHash: sha256:{}\nNarSize: 1\nReferences: {}\n",
+                i,
+                i,
+                i,
+                file_size,
+                "0".repeat(52),
+                refs,
+            )
+        };
+        let mut files = HashMap::new();
+        files.insert(
+            format!("/cache/{:0>32}.narinfo", 0),
+            nar_info(0, 10, &format!("{:0>32}-p1", 1)).into_bytes(),
+        );
+        files.insert(
+            format!("/cache/{:0>32}.narinfo", 1),
+            nar_info(1, 100, "").into_bytes(),
         );
-        self.inds.insert(a, 0);
-    }
 
-    fn add_dep(&mut self, a: V, b: V) {
-        self.edges.get_mut(&a).expect("No source vertex").push(b);
-        *self.inds.get_mut(&b).expect("No dest vertex") += 1;
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let file = tempfile::NamedTempFile::new().unwrap();
+            let mut db = Database::open(file.path()).unwrap();
+            let mut other = Database::open(file.path()).unwrap();
+            let config = FetchConfig::default();
+            let mut fetcher = Fetcher::new(&mut db, vec![cache_url].into(), None, &config).unwrap();
+            let root = StorePath::try_from(format!("/nix/store/{:0>32}-p0", 0))
+                .unwrap()
+                .hash();
+            fetcher.fetch_all(vec![root]).await.unwrap();
+            let topo_ord = fetcher.topo_sort().unwrap();
+
+            // Inserted by others meanwhile.
+            let nar = Nar::parse_nar_info(&nar_info(1, 100, "")).unwrap();
+            other
+                .insert_or_ignore_nars(NarStatus::Pending, &[nar])
+                .unwrap();
+            let summary = fetcher.save_all(topo_ord).await.unwrap();
+            assert_eq!(summary.inserted, 1);
+            assert_eq!(summary.inserted_file_size, 10);
+        });
     }
 
-    fn topo_sort(mut self) -> Vec<V> {
-        let mut q = Vec::with_capacity(self.edges.len());
-        for (k, &ind) in &self.inds {
-            if ind == 0 {
-                q.push(*k);
-            }
-        }
+    #[test]
+    fn test_fetch_meta_rec_progress() {
+        let nar_info = |i: usize| {
+            format!(
+                "StorePath: /nix/store/{:0>32}-p{}\nURL: nar/{}.nar\nCompression: none\n\
+                 NarHash: sha256:{}\nNarSize: 1\nReferences: \n",
+                i,
+                i,
+                i,
+                "0".repeat(52),
+            )
+        };
+        const N: usize = 4;
+        let files = (0..N)
+            .map(|i| {
+                (
+                    format!("/cache/{:0>32}.narinfo", i),
+                    nar_info(i).into_bytes(),
+                )
+            })
+            .collect();
 
-        let mut lpos = 0usize;
-        while lpos != q.len() {
-            let c = q[lpos];
-            lpos += 1;
-            for nxt in &self.edges[&c] {
-                let p = self.inds.get_mut(nxt).unwrap();
-                *p -= 1;
-                if *p == 0 {
-                    q.push(*nxt);
-                }
-            }
-        }
+        block_on(async move {
+            let server = MockServer::start_with_delay(files, Duration::from_millis(50));
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let reports = Arc::new(Mutex::new(vec![]));
+            let reports_ = reports.clone();
+            let config = FetchConfig {
+                concurrency: 1,
+                progress_interval: Duration::from_millis(10),
+                on_progress: Some(ProgressFn::new(move |finished, total| {
+                    reports_.lock().unwrap().push((finished, total));
+                })),
+                ..Default::default()
+            };
+            let roots = (0..N)
+                .map(|i| {
+                    StorePath::try_from(format!("/nix/store/{:0>32}-p{}", i, i))
+                        .unwrap()
+                        .hash()
+                })
+                .collect();
+            fetch_meta_rec(&mut db, &cache_url, roots, true, None, &config)
+                .await
+                .unwrap();
 
-        q
+            let reports = reports.lock().unwrap().clone();
+            // Periodic ones, and the final one.
+            assert!(reports.len() > 2, "{:?}", reports);
+            assert_eq!(*reports.last().unwrap(), (N as u64, N as u64));
+            assert!(
+                reports.windows(2).all(|w| w[0].0 <= w[1].0),
+                "{:?}",
+                reports
+            );
+            assert!(reports.iter().all(|&(finished, total)| finished <= total));
+        });
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::block_on;
-    use insta::assert_debug_snapshot;
-    use std::convert::TryFrom;
+    #[test]
+    fn test_fetch_meta_rec_path_mismatch() {
+        let mut files = HashMap::new();
+        files.insert(
+            format!("/cache/{:0>32}.narinfo", 0),
+            format!(
+                "StorePath: /nix/store/{:0>32}-p1\nURL: nar/1.nar\nCompression: none\n\
+                 NarHash: sha256:{}\nNarSize: 1\nReferences: \n",
+                1,
+                "0".repeat(52),
+            )
+            .into_bytes(),
+        );
+
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let root = StorePath::try_from(format!("/nix/store/{:0>32}-p0", 0))
+                .unwrap()
+                .hash();
+            let err = fetch_meta_rec(
+                &mut db,
+                &cache_url,
+                vec![root],
+                false,
+                None,
+                &Default::default(),
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                err.to_string().contains("Got narinfo of another path"),
+                "{}",
+                err
+            );
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 0);
+        });
+    }
 
     #[test]
     #[ignore]
@@ -308,19 +764,27 @@ mod tests {
             ];
 
             let mut db = Database::open_in_memory().unwrap();
-            fetch_meta_rec(&mut db, cache_url, root_paths)
-                .await
-                .unwrap();
+            fetch_meta_rec(
+                &mut db,
+                cache_url,
+                root_paths,
+                false,
+                None,
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
 
             let mut nars = vec![];
             db.select_all_nar(NarStatus::Pending, |_, nar| nars.push(nar))
                 .unwrap();
-            nars.sort_by(|a, b| a.store_path.path().cmp(&b.store_path.path()));
+            nars.sort_by(|a, b| a.store_path.cmp(&b.store_path));
             assert_debug_snapshot!(&nars, @r###"
             [
                 Nar {
                     store_path: StorePath {
                         path: "/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz",
+                        root_len: 10,
                     },
                     meta: NarMeta {
                         url: "nar/0zxydma1vh0gnncnkw3cxfpsl4y1rl5zsw0bprqyvb5zsklck6k5.nar.xz",
@@ -338,9 +802,9 @@ mod tests {
                         deriver: Some(
                             "0g93706i7g54hwilxkd9lhyfmmwy4jr6-openssl-1.1.1d.tar.gz.drv",
                         ),
-                        sig: Some(
+                        sig: [
                             "cache.nixos.org-1:+t+LMZdteGZ6dasXA1yZqv61RpQBfp5C5gXSktiUun/A7REwDO1Zo/u388sTGF8Vg8GX2VdggWTG2WCi03ceCg==",
-                        ),
+                        ],
                         ca: None,
                     },
                     references: "",
@@ -348,6 +812,7 @@ mod tests {
                 Nar {
                     store_path: StorePath {
                         path: "/nix/store/xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27",
+                        root_len: 10,
                     },
                     meta: NarMeta {
                         url: "nar/0jh2rlji5ypmksarb1l48980dlizpw2i0a0cwa1wvmznclbf39r2.nar.xz",
@@ -365,9 +830,9 @@ mod tests {
                         deriver: Some(
                             "3v2v8gcgyrz0n1rkrm7qpr8x855fdc84-glibc-2.27.drv",
                         ),
-                        sig: Some(
+                        sig: [
                             "cache.nixos.org-1:kpeoCBW1+6FDfUEGPZVgyNQ4/CvenOpLGa6MmJWAAKESZeti5VHFSSKjqQd2NeFyCIrBvO5D2SpGi2om/0brCg==",
-                        ),
+                        ],
                         ca: None,
                     },
                     references: "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27",
@@ -375,6 +840,7 @@ mod tests {
                 Nar {
                     store_path: StorePath {
                         path: "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10",
+                        root_len: 10,
                     },
                     meta: NarMeta {
                         url: "nar/1xbx6mir1krb81rb6g2paz2mxgpjkxqc0v9i2pyl90zmjdxjv0ld.nar.xz",
@@ -392,9 +858,9 @@ mod tests {
                         deriver: Some(
                             "dsjl0sbwpcrxfg85bq75y1j1hbwrxjy9-hello-2.10.drv",
                         ),
-                        sig: Some(
+                        sig: [
                             "cache.nixos.org-1:ek9X+mtn4eOMwIfDIq4gyzO/pFOjOvTracg5+SPMAMcSRrNravyRPVyaOgmjy3vTXKC6AavAxfILAg7mpVnDDg==",
-                        ),
+                        ],
                         ca: None,
                     },
                     references: "xlxiw4rnxx2dksa91fizjzf7jb5nqghc-glibc-2.27 yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10",
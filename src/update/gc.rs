@@ -0,0 +1,89 @@
+use crate::database::{model::StorePathHash, Database};
+use failure::ResultExt as _;
+use std::{fs, io, path::Path};
+
+use super::Result;
+
+/// Trash NARs not reachable from any root, delete their files in
+/// `nar_file_dir`, then purge their rows. Rows whose file cannot be deleted
+/// are kept trashed for the next run. Return hashes of purged NARs.
+/// `db` must be the single writer from `Database::open_exclusive`.
+pub fn collect_garbage(db: &mut Database, nar_file_dir: &Path) -> Result<Vec<StorePathHash>> {
+    let trashed = db.gc_unreferenced()?;
+    log::info!(count = trashed.len(); "{} NARs to collect", trashed.len());
+
+    let mut removed = Vec::with_capacity(trashed.len());
+    for hash in trashed {
+        let path = nar_file_dir.join(hash.as_str());
+        match fs::remove_file(&path) {
+            // Pending ones may be never downloaded.
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                log::error!(
+                    path:% = path.display(), error:% = err;
+                    "Cannot remove {}: {}", path.display(), err
+                );
+                continue;
+            }
+        }
+        removed.push(hash);
+    }
+
+    let deleted = db
+        .delete_trashed_nars(&removed)
+        .context("Cannot purge trashed NARs")?;
+    log::info!(count = deleted; "{} NARs collected", deleted);
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::model::*, tests::test_nar};
+
+    #[test]
+    fn test_collect_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+        // a -> [a, c], b -> [c], d -> [a]
+        let a = test_nar(
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+            1,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a cccccccccccccccccccccccccccccccc-c",
+        );
+        let b = test_nar(
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b",
+            1,
+            "cccccccccccccccccccccccccccccccc-c",
+        );
+        let c = test_nar("/nix/store/cccccccccccccccccccccccccccccccc-c", 1, "");
+        let d = test_nar(
+            "/nix/store/dddddddddddddddddddddddddddddddd-d",
+            1,
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a",
+        );
+        db.insert_or_ignore_nars(NarStatus::Pending, vec![&c, &a, &b, &d])
+            .unwrap();
+        for nar in &[&a, &b, &c, &d] {
+            fs::write(dir.path().join(nar.store_path.hash_str()), b"nar").unwrap();
+        }
+        db.insert_root(&Root::default(), vec![a.store_path.hash()])
+            .unwrap();
+        db.insert_root(&Root::default(), vec![b.store_path.hash()])
+            .unwrap();
+
+        // Only `d` is unreachable. `c` is shared by both roots.
+        let removed = collect_garbage(&mut db, dir.path()).unwrap();
+        assert_eq!(removed, vec![d.store_path.hash()]);
+        assert!(!dir.path().join(d.store_path.hash_str()).exists());
+        for nar in &[&a, &b, &c] {
+            assert!(dir.path().join(nar.store_path.hash_str()).exists());
+        }
+        assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 3);
+        assert_eq!(db.count_nars(NarStatus::Trashed).unwrap(), 0);
+
+        // Nothing more to collect.
+        assert_eq!(collect_garbage(&mut db, dir.path()).unwrap(), vec![]);
+    }
+}
@@ -9,32 +9,173 @@ use lazy_static::lazy_static;
 use log;
 use reqwest::{
     r#async::{Client, ClientBuilder},
-    Proxy,
+    Proxy, StatusCode, Url,
 };
-use std::{convert::TryFrom, env};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    env,
+    time::{Duration, Instant},
+};
+use tokio::timer;
 use xz2;
 
+mod dep_graph;
+mod download;
 mod fetch_meta_rec;
+mod gc;
+mod verify;
+
+pub use self::download::{
+    download_closure, download_file, download_files, download_nars, DownloadConfig, DownloadItem,
+};
+pub use self::fetch_meta_rec::{FetchConfig, NarInfoShare, ProgressFn};
+pub use self::gc::collect_garbage;
+#[cfg(test)]
+pub(crate) use self::verify::sha256_str;
+pub use self::verify::verify_nar;
+pub(crate) use self::verify::{decode_digest, nix_base32_encode};
 
 type Result<T> = std::result::Result<T, Error>;
 
 lazy_static! {
     static ref CLIENT: Client = {
         let mut b = ClientBuilder::new();
-        if let Ok(proxy) = env::var("https_proxy").or(env::var("HTTPS_PROXY")) {
-            b = b.proxy(Proxy::https(&proxy).expect("Invalid https_proxy"));
-        }
-        if let Ok(proxy) = env::var("http_proxy").or(env::var("HTTP_PROXY")) {
-            b = b.proxy(Proxy::https(&proxy).expect("Invalid http_proxy"));
-        }
-        if let Ok(proxy) = env::var("all_proxy").or(env::var("ALL_PROXY")) {
-            b = b.proxy(Proxy::all(&proxy).expect("Invalid all_proxy"));
+        let proxy = EnvProxy::from_env(|name| env::var(name).ok()).expect("Invalid proxy");
+        if !proxy.is_empty() {
+            b = b.proxy(Proxy::custom(move |url| {
+                proxy
+                    .proxy_for(url.scheme(), url.host_str().unwrap_or(""))
+                    .cloned()
+            }));
         }
         b.build().expect("Cannot build reqwest client")
     };
 }
 
-async fn get_all_to_vec(url: &str) -> Result<Vec<u8>> {
+/// Proxies from `http_proxy`, `https_proxy`, `all_proxy` and `no_proxy`,
+/// or their uppercase variants.
+#[derive(Debug, Default)]
+struct EnvProxy {
+    http: Option<Url>,
+    https: Option<Url>,
+    all: Option<Url>,
+    // Domain suffixes, or `*` for all hosts.
+    no_proxy: Vec<String>,
+}
+
+impl EnvProxy {
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let get = |name: &str| {
+            var(name)
+                .or_else(|| var(&name.to_uppercase()))
+                .filter(|s| !s.is_empty())
+        };
+        let get_url = |name: &str| {
+            get(name)
+                .map(|s| {
+                    let url = Url::parse(&s).with_context(|_| format!("Invalid {}", name))?;
+                    ensure!(
+                        url.scheme() == "http" || url.scheme() == "https",
+                        "Unsupported {} scheme: {}",
+                        name,
+                        url.scheme(),
+                    );
+                    Ok(url)
+                })
+                .transpose()
+        };
+        Ok(Self {
+            http: get_url("http_proxy")?,
+            https: get_url("https_proxy")?,
+            all: get_url("all_proxy")?,
+            no_proxy: get("no_proxy")
+                .iter()
+                .flat_map(|s| s.split(','))
+                .map(|s| s.trim().trim_start_matches('.').to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.http.is_none() && self.https.is_none() && self.all.is_none()
+    }
+
+    fn proxy_for(&self, scheme: &str, host: &str) -> Option<&Url> {
+        let host = host.to_lowercase();
+        let bypass = self.no_proxy.iter().any(|suffix| {
+            suffix == "*"
+                || host == *suffix
+                || (host.ends_with(&**suffix) && host[..host.len() - suffix.len()].ends_with('.'))
+        });
+        if bypass {
+            return None;
+        }
+        match scheme {
+            "http" => self.http.as_ref(),
+            "https" => self.https.as_ref(),
+            _ => None,
+        }
+        .or(self.all.as_ref())
+    }
+}
+
+/// Retry policy of metadata requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Maximum number of retries after the first failure.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled before each following one.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Connection errors, 5xx and 429 are worth retrying. Other 4xx are not.
+fn is_transient(err: &Error) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        None => false,
+        Some(err) => match err.status() {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => err.is_http() || err.is_timeout(),
+        },
+    }
+}
+
+async fn get_all_to_vec(url: &str, retry: &RetryConfig) -> Result<Vec<u8>> {
+    let mut retries = 0;
+    loop {
+        let err = match get_all_to_vec_once(url).await {
+            Ok(buf) => return Ok(buf),
+            Err(err) => err,
+        };
+        if retries == retry.max_retries || !is_transient(&err) {
+            return Err(err);
+        }
+        let delay = retry.base_delay * 2u32.pow(retries);
+        retries += 1;
+        log::warn!(
+            url:% = url, retry = retries, error:% = err;
+            "Failed to get {}, retry {}/{} in {:?}: {}",
+            url,
+            retries,
+            retry.max_retries,
+            delay,
+            err,
+        );
+        timer::Delay::new(Instant::now() + delay).compat().await?;
+    }
+}
+
+async fn get_all_to_vec_once(url: &str) -> Result<Vec<u8>> {
     let resp = CLIENT.get(url).send().compat().await?.error_for_status()?;
     let mut stream = resp.into_body().compat();
     let mut buf: Vec<u8> = vec![];
@@ -44,15 +185,33 @@ async fn get_all_to_vec(url: &str) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
-async fn get_all_to_string(uri: &str) -> Result<String> {
-    Ok(String::from_utf8(get_all_to_vec(uri).await?)?)
+async fn get_all_to_string(uri: &str, retry: &RetryConfig) -> Result<String> {
+    Ok(String::from_utf8(get_all_to_vec(uri, retry).await?)?)
+}
+
+/// Like `get_all_to_string`, but return `None` on 404.
+async fn get_optional_string(uri: &str, retry: &RetryConfig) -> Result<Option<String>> {
+    match get_all_to_string(uri, retry).await {
+        Ok(s) => Ok(Some(s)),
+        Err(err) => match err.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            _ => Err(err),
+        },
+    }
 }
 
 async fn get_git_revision(uri: &str) -> Result<String> {
-    let mut rev = get_all_to_string(uri).await?;
+    parse_git_revision(get_all_to_string(uri, &RetryConfig::default()).await?)
+}
+
+/// Accept hex revisions from shortened ones up to SHA-256 ones.
+fn parse_git_revision(mut rev: String) -> Result<String> {
+    const MIN_LEN: usize = 7;
+    const MAX_LEN: usize = 64;
     ensure!(
-        rev.len() == 40 && rev.chars().all(|c| c.is_ascii_alphanumeric()),
-        "Invalid git revision",
+        MIN_LEN <= rev.len() && rev.len() <= MAX_LEN && rev.chars().all(|c| c.is_ascii_hexdigit()),
+        "Invalid git revision '{}'",
+        rev.escape_debug(),
     );
     rev.make_ascii_lowercase();
     Ok(rev)
@@ -67,33 +226,66 @@ pub struct NixChannelInfo {
     pub root_paths: Vec<StorePath>,
 }
 
+/// Check a binary cache URL is http(s), and strip trailing slashes.
+fn normalize_cache_url(url: &str) -> Result<String> {
+    let url = url.trim().trim_end_matches('/');
+    ensure!(!url.is_empty(), "Empty binary cache url");
+    ensure!(
+        (url.starts_with("http://") || url.starts_with("https://"))
+            && !url.contains(char::is_whitespace),
+        "Invalid binary cache url '{}'",
+        url,
+    );
+    Ok(url.to_owned())
+}
+
+/// The binary cache URL is `cache_url` if given, which takes precedence over
+/// the one advertised by the channel. Otherwise the advertised one is used.
 pub async fn get_nix_channel(channel_url: &str, cache_url: Option<&str>) -> Result<NixChannelInfo> {
-    let revision_url = format!("{}/git-revision", channel_url);
-    let store_path_url = format!("{}/store-paths.xz", channel_url);
-    let cache_url_url = format!("{}/binary-cache-url", channel_url);
+    let git_revision = get_channel_revision(channel_url).await?;
+    get_nix_channel_at(channel_url, cache_url, git_revision).await
+}
 
+async fn get_channel_revision(channel_url: &str) -> Result<String> {
     log::info!("Fetching metadata");
-    let git_revision1 = get_git_revision(&revision_url)
+    Ok(get_git_revision(&format!("{}/git-revision", channel_url))
         .await
-        .context("Cannot get git revision")?;
+        .context("Cannot get git revision")?)
+}
+
+/// Fetch the rest of a channel whose revision is just fetched as `git_revision1`.
+async fn get_nix_channel_at(
+    channel_url: &str,
+    cache_url: Option<&str>,
+    git_revision1: String,
+) -> Result<NixChannelInfo> {
+    let revision_url = format!("{}/git-revision", channel_url);
+    let store_path_url = format!("{}/store-paths.xz", channel_url);
+    let cache_url_url = format!("{}/binary-cache-url", channel_url);
+    let cache_url = cache_url.map(normalize_cache_url).transpose()?;
 
     let fetch_time = Utc::now();
 
     let cache_url = match cache_url {
-        Some(url) => url.to_owned(),
-        None => get_all_to_string(&cache_url_url)
-            .await
-            .context("Cannot get binary cache url")?,
+        Some(url) => url,
+        None => {
+            let url = get_all_to_string(&cache_url_url, &RetryConfig::default())
+                .await
+                .context("Cannot get binary cache url")?;
+            normalize_cache_url(&url).with_context(|err| {
+                format_err!("Channel {} has bad binary-cache-url: {}", channel_url, err)
+            })?
+        }
     };
 
     log::info!("Fetching root store paths");
-    let root_paths = get_store_paths(&store_path_url)
+    let (root_paths, _) = get_store_paths(&store_path_url, false)
         .await
         .context("Cannot get root store paths")?;
 
     log::info!("Checking git revision");
     let git_revision2 = get_git_revision(&revision_url).await?;
-    log::info!("rev = {}", git_revision1);
+    log::info!(git_revision = git_revision1.as_str(); "rev = {}", git_revision1);
 
     ensure!(
         git_revision1 == git_revision2,
@@ -111,19 +303,31 @@ pub async fn get_nix_channel(channel_url: &str, cache_url: Option<&str>) -> Resu
     })
 }
 
-async fn get_store_paths(url: &str) -> Result<Vec<StorePath>> {
+/// Fetch and parse an xz compressed store path listing.
+/// In `lenient` mode, invalid lines are logged and skipped instead of failing
+/// the whole listing. Return valid paths and the number of skipped lines.
+pub async fn get_store_paths(url: &str, lenient: bool) -> Result<(Vec<StorePath>, usize)> {
     use std::io::{BufRead, BufReader, Cursor};
     use xz2::read::XzDecoder;
 
-    let resp = get_all_to_vec(&url).await?;
-    BufReader::new(XzDecoder::new(Cursor::new(resp)))
-        .lines()
-        .map(|line| -> Result<StorePath> {
-            let line = line?;
-            Ok(StorePath::try_from(&*line)
-                .with_context(|err| format_err!("Invalid store path '{}': {}", line, err))?)
-        })
-        .collect()
+    let resp = get_all_to_vec(url, &RetryConfig::default()).await?;
+    let mut paths = Vec::new();
+    let mut skipped = 0;
+    for line in BufReader::new(XzDecoder::new(Cursor::new(resp))).lines() {
+        let line = line?;
+        match StorePath::try_from(&*line) {
+            Ok(path) => paths.push(path),
+            Err(err) if lenient => {
+                log::warn!("Skipped invalid store path '{}': {}", line, err);
+                skipped += 1;
+            }
+            Err(err) => return Err(format_err!("Invalid store path '{}': {}", line, err)),
+        }
+    }
+    if skipped != 0 {
+        log::warn!("{} invalid store paths skipped", skipped);
+    }
+    Ok((paths, skipped))
 }
 
 pub async fn add_root_rec(
@@ -131,21 +335,167 @@ pub async fn add_root_rec(
     root: &Root,
     cache_url: &str,
     root_paths: impl IntoIterator<Item = StorePath>,
+    config: &FetchConfig,
 ) -> Result<i64> {
-    let root_hashes: Vec<StorePathHash> = root_paths.into_iter().map(|path| path.hash()).collect();
-    fetch_meta_rec::fetch_meta_rec(db, cache_url, root_hashes.clone()).await?;
-    log::info!("Saving root with {} root paths", root_hashes.len());
-    let id = db.insert_root(root, root_hashes)?;
-    log::info!("New root {} added", id);
-    Ok(id)
+    let outcome = add_root_rec_with(db, root, cache_url, root_paths, false, None, config).await?;
+    Ok(outcome.root_id.expect("Not dry run"))
 }
 
+async fn add_root_rec_with(
+    db: &mut Database,
+    root: &Root,
+    cache_url: &str,
+    root_paths: impl IntoIterator<Item = StorePath>,
+    dry_run: bool,
+    share: Option<&NarInfoShare>,
+    config: &FetchConfig,
+) -> Result<AddChannelOutcome> {
+    // Channels may list a path more than once. Keep the first occurrence.
+    let mut visited = HashSet::new();
+    let root_hashes: Vec<StorePathHash> = root_paths
+        .into_iter()
+        .map(|path| path.hash())
+        .filter(|hash| visited.insert(*hash))
+        .collect();
+    let fetched =
+        fetch_meta_rec::fetch_meta_rec(db, cache_url, root_hashes.clone(), dry_run, share, config)
+            .await?;
+    let mut outcome = AddChannelOutcome {
+        root_id: None,
+        skipped: false,
+        root_paths: root_hashes.len(),
+        new_nars: fetched.new_nars,
+        new_file_size: fetched.new_file_size,
+        existing_nars: fetched.existing_nars,
+        missing_nars: fetched.missing_nars,
+        closure_size: None,
+    };
+    if dry_run {
+        return Ok(outcome);
+    }
+    log::info!(
+        root_paths = root_hashes.len();
+        "Saving root with {} root paths", root_hashes.len()
+    );
+    let id = db
+        .with_busy_retry_async(|db| db.insert_root(root, root_hashes.clone()))
+        .await?;
+    log::info!(root_id = id; "New root {} added", id);
+    outcome.root_id = Some(id);
+    outcome.closure_size = Some(db.root_closure_size(id)?);
+    Ok(outcome)
+}
+
+/// Parse a plain list of store paths, one per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_store_path_list(content: &str) -> Result<Vec<StorePath>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(lineno, line)| {
+            StorePath::try_from(line)
+                .map_err(|err| format_err!("Invalid store path at line {}: {}", lineno, err))
+        })
+        .collect()
+}
+
+/// Add the closure of `paths` in the cache at `cache_url` as a new root
+/// without channel information, and return its id.
+pub async fn add_paths_rec(
+    db: &mut Database,
+    cache_url: &str,
+    paths: impl IntoIterator<Item = StorePath>,
+    config: &FetchConfig,
+) -> Result<i64> {
+    let root = Root {
+        channel_url: None,
+        cache_url: Some(cache_url.to_owned()),
+        git_revision: None,
+        fetch_time: Some(Utc::now()),
+        status: RootStatus::Pending,
+    };
+    add_root_rec(db, &root, cache_url, paths, config).await
+}
+
+/// Options of `add_nix_channel_rec_outcome`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AddChannelOptions<'a> {
+    /// Add the channel again even if its revision is already added.
+    pub force: bool,
+    /// Only fetch metadata, without writing to the database.
+    pub dry_run: bool,
+    /// Concurrent calls with the same `share` fetch each narinfo only once.
+    pub share: Option<&'a NarInfoShare>,
+}
+
+/// What adding a channel did, or would do in dry run.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct AddChannelOutcome {
+    /// The new or existing root. `None` if a new root would be added in dry run.
+    pub root_id: Option<i64>,
+    /// Whether the revision is already added, and nothing is fetched.
+    pub skipped: bool,
+    /// Number of distinct root paths of the channel.
+    pub root_paths: usize,
+    /// Number of NARs in the closure which were not in the database.
+    pub new_nars: u64,
+    /// Total file size of these new NARs.
+    pub new_file_size: u64,
+    /// Number of NARs in the closure which were already in the database.
+    pub existing_nars: u64,
+    /// Paths in the closure whose narinfo is not found in the cache.
+    pub missing_nars: Vec<StorePathHash>,
+    /// Total file size of the closure of the root, if it exists.
+    pub closure_size: Option<u64>,
+}
+
+/// Add a channel and return the root id. See `add_nix_channel_rec_outcome`.
 pub async fn add_nix_channel_rec(
     db: &mut Database,
     channel_url: &str,
     cache_url: Option<&str>,
+    force: bool,
+    config: &FetchConfig,
 ) -> Result<i64> {
-    let info = get_nix_channel(channel_url, cache_url).await?;
+    let options = AddChannelOptions {
+        force,
+        ..Default::default()
+    };
+    let outcome = add_nix_channel_rec_outcome(db, channel_url, cache_url, options, config).await?;
+    Ok(outcome.root_id.expect("Not dry run"))
+}
+
+/// Skip and return the existing root id if the same revision of the channel
+/// is already added, unless `options.force` is set.
+/// If `options.dry_run` is set, only metadata are fetched and nothing is
+/// written to the database.
+/// If `config.keys` is set, fail on any path without a valid signature by them.
+pub async fn add_nix_channel_rec_outcome(
+    db: &mut Database,
+    channel_url: &str,
+    cache_url: Option<&str>,
+    options: AddChannelOptions<'_>,
+    config: &FetchConfig,
+) -> Result<AddChannelOutcome> {
+    let git_revision = get_channel_revision(channel_url).await?;
+    if !options.force {
+        if let Some((id, _)) = db.select_channel_root_by_revision(channel_url, &git_revision)? {
+            log::info!(
+                git_revision = git_revision.as_str(), root_id = id;
+                "Revision {} is already added as root {}", git_revision, id
+            );
+            return Ok(AddChannelOutcome {
+                root_id: Some(id),
+                skipped: true,
+                root_paths: db.select_root_paths(id)?.len(),
+                closure_size: Some(db.root_closure_size(id)?),
+                ..Default::default()
+            });
+        }
+    }
+    let info = get_nix_channel_at(channel_url, cache_url, git_revision).await?;
     let root = Root {
         channel_url: Some(info.channel_url),
         cache_url: Some(info.cache_url),
@@ -153,13 +503,60 @@ pub async fn add_nix_channel_rec(
         fetch_time: Some(info.fetch_time),
         status: RootStatus::Pending,
     };
-    add_root_rec(db, &root, root.cache_url.as_ref().unwrap(), info.root_paths).await
+    let cache_url = root.cache_url.as_ref().unwrap();
+    add_root_rec_with(
+        db,
+        &root,
+        cache_url,
+        info.root_paths,
+        options.dry_run,
+        options.share,
+        config,
+    )
+    .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::block_on;
+    use crate::{block_on, keys::TrustedKeys, tests::MockServer};
+    use std::{collections::HashMap, io::Write as _, time::Duration};
+
+    const HELLO_PATH: &str = "/nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10";
+    const HELLO_NAR_INFO: &str = "\
+StorePath: /nix/store/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+URL: nar/1xbx6mir1krb81rb6g2paz2mxgpjkxqc0v9i2pyl90zmjdxjv0ld.nar.xz
+Compression: xz
+FileHash: sha256:1xbx6mir1krb81rb6g2paz2mxgpjkxqc0v9i2pyl90zmjdxjv0ld
+FileSize: 41204
+NarHash: sha256:0v1pkm7xg0gp5avnd0qbnmmhcw97rwwwyfxf467imwcvvpyl54hz
+NarSize: 205920
+References: yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+";
+
+    fn xz_compress(data: &[u8]) -> Vec<u8> {
+        let mut enc = xz2::write::XzEncoder::new(vec![], 6);
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    /// Files of a channel at `/channel` with binary cache at `/cache`, on the same server.
+    fn mock_channel_files(store_paths: &[&str]) -> HashMap<String, Vec<u8>> {
+        let mut files = HashMap::new();
+        files.insert(
+            "/channel/git-revision".to_owned(),
+            b"0123456789abcdef0123456789abcdef01234567".to_vec(),
+        );
+        files.insert(
+            "/channel/store-paths.xz".to_owned(),
+            xz_compress(store_paths.join("\n").as_bytes()),
+        );
+        files.insert(
+            "/cache/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo".to_owned(),
+            HELLO_NAR_INFO.as_bytes().to_vec(),
+        );
+        files
+    }
 
     #[test]
     fn test_parse_nix_path() {
@@ -197,4 +594,734 @@ mod tests {
             eprintln!("{:?} store paths", channel_info);
         });
     }
+
+    #[test]
+    fn test_add_nix_channel_skip_known_revision() {
+        block_on(async {
+            let server = MockServer::start(mock_channel_files(&[HELLO_PATH]));
+            let channel_url = format!("{}/channel", server.url);
+            let cache_url = format!("{}/cache", server.url);
+            let narinfo_path = "/cache/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+
+            let mut db = Database::open_in_memory().unwrap();
+            let id1 = add_nix_channel_rec(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                false,
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(server.hits(narinfo_path), 1);
+
+            let id2 = add_nix_channel_rec(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                false,
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(id1, id2);
+            assert_eq!(server.hits(narinfo_path), 1);
+            assert_eq!(server.hits("/channel/store-paths.xz"), 1);
+
+            let id3 = add_nix_channel_rec(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                true,
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_ne!(id1, id3);
+        });
+    }
+
+    #[test]
+    fn test_add_nix_channel_outcome() {
+        block_on(async {
+            let server = MockServer::start(mock_channel_files(&[HELLO_PATH]));
+            let channel_url = format!("{}/channel", server.url);
+            let cache_url = format!("{}/cache", server.url);
+
+            let mut db = Database::open_in_memory().unwrap();
+            let fresh = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions::default(),
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert!(fresh.root_id.is_some());
+            assert_eq!(
+                fresh,
+                AddChannelOutcome {
+                    root_id: fresh.root_id,
+                    skipped: false,
+                    root_paths: 1,
+                    new_nars: 1,
+                    new_file_size: 41204,
+                    existing_nars: 0,
+                    missing_nars: vec![],
+                    closure_size: Some(41204),
+                },
+            );
+
+            let repeat = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions::default(),
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                repeat,
+                AddChannelOutcome {
+                    root_id: fresh.root_id,
+                    skipped: true,
+                    root_paths: 1,
+                    closure_size: Some(41204),
+                    ..Default::default()
+                },
+            );
+
+            let forced = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions {
+                    force: true,
+                    ..Default::default()
+                },
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_ne!(forced.root_id, fresh.root_id);
+            assert_eq!((forced.new_nars, forced.existing_nars), (0, 1));
+        });
+    }
+
+    #[test]
+    fn test_add_nix_channel_dry_run() {
+        block_on(async {
+            let server = MockServer::start(mock_channel_files(&[HELLO_PATH]));
+            let channel_url = format!("{}/channel", server.url);
+            let cache_url = format!("{}/cache", server.url);
+
+            let mut db = Database::open_in_memory().unwrap();
+            let outcome = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions {
+                    dry_run: true,
+                    ..Default::default()
+                },
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                outcome,
+                AddChannelOutcome {
+                    root_id: None,
+                    root_paths: 1,
+                    new_nars: 1,
+                    new_file_size: 41204,
+                    ..Default::default()
+                },
+            );
+
+            let mut count = 0;
+            db.select_all_root(|_, _| count += 1).unwrap();
+            db.select_all_nar(NarStatus::Pending, |_, _| count += 1)
+                .unwrap();
+            assert_eq!(count, 0);
+        });
+    }
+
+    #[test]
+    fn test_get_store_paths_lenient() {
+        block_on(async {
+            let listing = [HELLO_PATH, "/nix/store/invalid", ""].join("\n");
+            let mut files = HashMap::new();
+            files.insert(
+                "/store-paths.xz".to_owned(),
+                xz_compress(listing.as_bytes()),
+            );
+            let server = MockServer::start(files);
+            let url = format!("{}/store-paths.xz", server.url);
+
+            assert!(get_store_paths(&url, false).await.is_err());
+            let (paths, skipped) = get_store_paths(&url, true).await.unwrap();
+            assert_eq!(paths, vec![StorePath::try_from(HELLO_PATH).unwrap()]);
+            assert_eq!(skipped, 1);
+        });
+    }
+
+    #[test]
+    fn test_get_nix_channel_forced_cache_url() {
+        block_on(async {
+            let mut files = mock_channel_files(&[HELLO_PATH]);
+            files.insert(
+                "/channel/binary-cache-url".to_owned(),
+                b"https://advertised.example.com".to_vec(),
+            );
+            let server = MockServer::start(files);
+            let channel_url = format!("{}/channel", server.url);
+            let cache_url = format!("{}/cache/", server.url);
+
+            let info = get_nix_channel(&channel_url, Some(&cache_url))
+                .await
+                .unwrap();
+            assert_eq!(info.cache_url, format!("{}/cache", server.url));
+            assert_eq!(server.hits("/channel/binary-cache-url"), 0);
+
+            assert!(get_nix_channel(&channel_url, Some("ftp://example.com"))
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_get_nix_channel_empty_cache_url() {
+        block_on(async {
+            let mut files = mock_channel_files(&[HELLO_PATH]);
+            files.insert("/channel/binary-cache-url".to_owned(), b" \n".to_vec());
+            let server = MockServer::start(files);
+            let channel_url = format!("{}/channel", server.url);
+
+            let err = get_nix_channel(&channel_url, None).await.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Channel {} has bad binary-cache-url: Empty binary cache url",
+                    channel_url,
+                ),
+            );
+        });
+    }
+
+    #[test]
+    fn test_add_nix_channel_then_download() {
+        use crate::server::{serve, ServerData};
+        use futures::compat::Future01CompatExt as _;
+        use futures01::Stream as _;
+
+        let nar_data = xz_compress(b"nix-archive-1 hello");
+        let nar_info = format!(
+            "\
+StorePath: {}
+URL: nar/hello.nar.xz
+Compression: xz
+FileHash: {}
+FileSize: {}
+NarHash: {}
+NarSize: 19
+References: yhzvzdq82lzk0kvrp3i79yhjnhps6qpk-hello-2.10
+",
+            HELLO_PATH,
+            verify::sha256_str(&nar_data),
+            nar_data.len(),
+            verify::sha256_str(b"nix-archive-1 hello"),
+        );
+        let mut files = mock_channel_files(&[HELLO_PATH]);
+        files.insert(
+            "/cache/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo".to_owned(),
+            nar_info.into_bytes(),
+        );
+        files.insert("/cache/nar/hello.nar.xz".to_owned(), nar_data.clone());
+        block_on(async move {
+            let server = MockServer::start(files);
+            let channel_url = format!("{}/channel", server.url);
+            let cache_url = format!("{}/cache", server.url);
+            let dir = tempfile::tempdir().unwrap();
+
+            let mut db = Database::open_in_memory().unwrap();
+            add_nix_channel_rec(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                false,
+                &FetchConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 1);
+            assert_eq!(
+                download_nars(&mut db, &cache_url, dir.path(), &DownloadConfig::default())
+                    .await
+                    .unwrap(),
+                1
+            );
+            assert_eq!(db.count_nars(NarStatus::Available).unwrap(), 1);
+
+            let data = ServerData::init(&db, dir.path().to_owned(), &Default::default()).unwrap();
+            let req = hyper::Request::get("/nar/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk")
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = serve(std::sync::Arc::new(data), req).await.unwrap();
+            assert_eq!(resp.status(), hyper::StatusCode::OK);
+            let body = resp.into_body().concat2().compat().await.unwrap();
+            assert_eq!(&body[..], &nar_data[..]);
+        });
+    }
+
+    #[test]
+    fn test_add_nix_channel_trusted_keys() {
+        const OPENSSL_PATH: &str =
+            "/nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz";
+        const OPENSSL_NAR_INFO: &str = "\
+StorePath: /nix/store/fv8g2yczna9d78d150km0h73fkijw021-openssl-1.1.1d.tar.gz
+URL: nar/0rv8nbz4dwg9ipm3xl42m8wfihqbq9rdr9gxsqw5p3rjnw2f8mzi.nar.xz
+Compression: xz
+NarHash: sha256:0i6abchw6pa0p313ahhz0myrr2sbk1npxkkprbbw1qmz6javbc6x
+NarSize: 8845976
+References: 
+Sig: cache.nixos.org-1:+t+LMZdteGZ6dasXA1yZqv61RpQBfp5C5gXSktiUun/A7REwDO1Zo/u388sTGF8Vg8GX2VdggWTG2WCi03ceCg==
+";
+
+        let keys =
+            TrustedKeys::parse("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=")
+                .unwrap();
+        let mut files = mock_channel_files(&[HELLO_PATH]);
+        files.insert(
+            "/channel2/git-revision".to_owned(),
+            b"89abcdef0123456789abcdef0123456789abcdef".to_vec(),
+        );
+        files.insert(
+            "/channel2/store-paths.xz".to_owned(),
+            xz_compress(OPENSSL_PATH.as_bytes()),
+        );
+        files.insert(
+            "/cache/fv8g2yczna9d78d150km0h73fkijw021.narinfo".to_owned(),
+            OPENSSL_NAR_INFO.as_bytes().to_vec(),
+        );
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+
+            // `hello` is not signed.
+            let channel_url = format!("{}/channel", server.url);
+            let ret = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions::default(),
+                &FetchConfig {
+                    keys: Some(keys.clone()),
+                    ..Default::default()
+                },
+            )
+            .await;
+            assert!(ret.is_err());
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 0);
+
+            let channel_url = format!("{}/channel2", server.url);
+            let outcome = add_nix_channel_rec_outcome(
+                &mut db,
+                &channel_url,
+                Some(&cache_url),
+                AddChannelOptions::default(),
+                &FetchConfig {
+                    keys: Some(keys.clone()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(outcome.new_nars, 1);
+        });
+    }
+
+    #[test]
+    fn test_parse_store_path_list() {
+        let list = format!("# Comment\n\n{}\n  {}  \n", HELLO_PATH, HELLO_PATH);
+        let paths = parse_store_path_list(&list).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].path(), HELLO_PATH);
+        assert!(parse_store_path_list("").unwrap().is_empty());
+
+        let err = parse_store_path_list(&format!("{}\nhello\n", HELLO_PATH)).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid store path at line 2: "),
+            "{}",
+            err,
+        );
+    }
+
+    #[test]
+    fn test_add_paths() {
+        let files = mock_channel_files(&[]);
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let paths = parse_store_path_list(HELLO_PATH).unwrap();
+            let id = add_paths_rec(&mut db, &cache_url, paths, &FetchConfig::default())
+                .await
+                .unwrap();
+
+            let record = db.select_root(id).unwrap();
+            assert_eq!(record.root.channel_url, None);
+            assert_eq!(record.root.cache_url, Some(cache_url));
+            assert_eq!(record.root.git_revision, None);
+            assert!(record.root.fetch_time.is_some());
+            assert_eq!(record.root.status, RootStatus::Pending);
+            assert_eq!(record.nar_count, 1);
+            assert_eq!(server.hits("/channel/git-revision"), 0);
+        });
+    }
+
+    #[test]
+    fn test_add_root_reference_cycle() {
+        let nar_info = |hash: &str, name: &str, refs: &str| {
+            format!(
+                "StorePath: /nix/store/{}-{}\nURL: nar/{}.nar\nCompression: none\n\
+                 NarHash: sha256:{}\nNarSize: 1\nReferences: {}\n",
+                hash,
+                name,
+                hash,
+                "0".repeat(52),
+                refs,
+            )
+        };
+        // c <- a <-> b
+        let (a, b, c) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        let mut files = HashMap::new();
+        files.insert(
+            format!("/cache/{}.narinfo", c),
+            nar_info(&c, "c", "").into_bytes(),
+        );
+        files.insert(
+            format!("/cache/{}.narinfo", a),
+            nar_info(&a, "a", &format!("{}-b {}-c", b, c)).into_bytes(),
+        );
+        files.insert(
+            format!("/cache/{}.narinfo", b),
+            nar_info(&b, "b", &format!("{}-a", a)).into_bytes(),
+        );
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let root_path = StorePath::try_from(format!("/nix/store/{}-a", a)).unwrap();
+
+            for &dry_run in &[true, false] {
+                let err = add_root_rec_with(
+                    &mut db,
+                    &Root::default(),
+                    &cache_url,
+                    vec![root_path.clone()],
+                    dry_run,
+                    None,
+                    &FetchConfig::default(),
+                )
+                .await
+                .unwrap_err();
+                assert!(
+                    err.to_string()
+                        .starts_with("3 narinfos blocked by a reference cycle"),
+                    "{}",
+                    err,
+                );
+            }
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_add_root_missing_reference() {
+        let (a, b) = ("a".repeat(32), "b".repeat(32));
+        let nar_info = format!(
+            "StorePath: /nix/store/{}-a\nURL: nar/a.nar\nCompression: none\n\
+             NarHash: sha256:{}\nNarSize: 1\nFileSize: 1\nReferences: {}-b\n",
+            a,
+            "0".repeat(52),
+            b,
+        );
+        let mut files = HashMap::new();
+        files.insert(format!("/cache/{}.narinfo", a), nar_info.into_bytes());
+        block_on(async move {
+            let server = MockServer::start(files);
+            let cache_url = format!("{}/cache", server.url);
+            let mut db = Database::open_in_memory().unwrap();
+            let root_path = StorePath::try_from(format!("/nix/store/{}-a", a)).unwrap();
+            let missing = StorePath::try_from(format!("/nix/store/{}-b", b))
+                .unwrap()
+                .hash();
+
+            for &dry_run in &[true, false] {
+                let outcome = add_root_rec_with(
+                    &mut db,
+                    &Root::default(),
+                    &cache_url,
+                    vec![root_path.clone()],
+                    dry_run,
+                    None,
+                    &FetchConfig::default(),
+                )
+                .await
+                .unwrap();
+                assert_eq!(outcome.new_nars, 1);
+                assert_eq!(outcome.existing_nars, 0);
+                assert_eq!(outcome.new_file_size, 1);
+                assert_eq!(outcome.missing_nars, vec![missing]);
+                assert_eq!(outcome.root_id.is_some(), !dry_run);
+            }
+            assert_eq!(server.hits(&format!("/cache/{}.narinfo", b)), 2);
+            assert_eq!(db.count_nars(NarStatus::Pending).unwrap(), 1);
+            // Still listed, so that the signature stays valid.
+            let nar = db.select_nar_by_hash(&root_path.hash()).unwrap().unwrap();
+            assert_eq!(nar.references, format!("{}-b", b));
+        });
+    }
+
+    #[test]
+    fn test_add_root_fallback_cache() {
+        let (hello, glibc) = ("a".repeat(32), "b".repeat(32));
+        let nar_info = |hash: &str, name: &str, content: &[u8], refs: &str| {
+            format!(
+                "StorePath: /nix/store/{}-{}\nURL: nar/{}.nar\nCompression: none\n\
+                 NarHash: {}\nNarSize: {}\nReferences: {}\n",
+                hash,
+                name,
+                name,
+                verify::sha256_str(content),
+                content.len(),
+                refs,
+            )
+            .into_bytes()
+        };
+        let mut files = HashMap::new();
+        files.insert(
+            format!("/cache1/{}.narinfo", hello),
+            nar_info(&hello, "hello", b"hello", &format!("{}-glibc", glibc)),
+        );
+        files.insert("/cache1/nar/hello.nar".to_owned(), b"hello".to_vec());
+        files.insert(
+            format!("/cache2/{}.narinfo", glibc),
+            nar_info(&glibc, "glibc", b"glibc", ""),
+        );
+        files.insert("/cache2/nar/glibc.nar".to_owned(), b"glibc".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        block_on(async move {
+            let server = MockServer::start(files);
+            let (cache1, cache2) = (
+                format!("{}/cache1", server.url),
+                format!("{}/cache2", server.url),
+            );
+            let mut db = Database::open_in_memory().unwrap();
+            let config = FetchConfig {
+                fallback_cache_urls: vec![cache2.clone()],
+                ..Default::default()
+            };
+            let root_path = StorePath::try_from(format!("/nix/store/{}-hello", hello)).unwrap();
+            let outcome = add_root_rec_with(
+                &mut db,
+                &Root::default(),
+                &cache1,
+                vec![root_path],
+                false,
+                None,
+                &config,
+            )
+            .await
+            .unwrap();
+            assert_eq!(outcome.new_nars, 2);
+            assert!(outcome.missing_nars.is_empty());
+            assert_eq!(server.hits(&format!("/cache1/{}.narinfo", glibc)), 1);
+            assert_eq!(server.hits(&format!("/cache2/{}.narinfo", hello)), 0);
+
+            let id_of = |db: &Database, path: String| {
+                let hash = StorePath::try_from(path).unwrap().hash();
+                db.select_nar_id_by_hash(&hash).unwrap().unwrap()
+            };
+            let hello_id = id_of(&db, format!("/nix/store/{}-hello", hello));
+            let glibc_id = id_of(&db, format!("/nix/store/{}-glibc", glibc));
+            assert_eq!(db.select_nar_cache_url(hello_id).unwrap(), None);
+            assert_eq!(db.select_nar_cache_url(glibc_id).unwrap(), Some(cache2));
+
+            // NAR files come from the same caches.
+            let root_id = outcome.root_id.unwrap();
+            let config = DownloadConfig::default();
+            assert_eq!(
+                download_closure(&mut db, root_id, &cache1, &dir_path, &config)
+                    .await
+                    .unwrap(),
+                2,
+            );
+            assert_eq!(server.hits("/cache1/nar/hello.nar"), 1);
+            assert_eq!(server.hits("/cache2/nar/glibc.nar"), 1);
+            assert_eq!(server.hits("/cache1/nar/glibc.nar"), 0);
+        });
+    }
+
+    #[test]
+    fn test_env_proxy() {
+        let from_env = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            EnvProxy::from_env(|name| vars.get(name).cloned())
+        };
+        let proxy_for = |proxy: &EnvProxy, url: &str| {
+            let url = Url::parse(url).unwrap();
+            proxy
+                .proxy_for(url.scheme(), url.host_str().unwrap())
+                .map(|u| u.as_str().to_owned())
+        };
+        let (http, https, all) = ("http://h:1/", "http://s:2/", "http://a:3/");
+
+        let proxy = from_env(&[("http_proxy", http)]).unwrap();
+        assert_eq!(
+            proxy_for(&proxy, "http://example.com").as_deref(),
+            Some(http)
+        );
+        assert_eq!(proxy_for(&proxy, "https://example.com"), None);
+
+        let proxy = from_env(&[("HTTPS_PROXY", https)]).unwrap();
+        assert_eq!(proxy_for(&proxy, "http://example.com"), None);
+        assert_eq!(
+            proxy_for(&proxy, "https://example.com").as_deref(),
+            Some(https)
+        );
+
+        let proxy = from_env(&[("all_proxy", all), ("http_proxy", http)]).unwrap();
+        assert_eq!(
+            proxy_for(&proxy, "http://example.com").as_deref(),
+            Some(http)
+        );
+        assert_eq!(
+            proxy_for(&proxy, "https://example.com").as_deref(),
+            Some(all)
+        );
+
+        let proxy =
+            from_env(&[("all_proxy", all), ("no_proxy", "localhost, .example.com")]).unwrap();
+        assert_eq!(proxy_for(&proxy, "https://localhost"), None);
+        assert_eq!(proxy_for(&proxy, "https://example.com"), None);
+        assert_eq!(proxy_for(&proxy, "https://cache.Example.com"), None);
+        assert_eq!(
+            proxy_for(&proxy, "https://badexample.com").as_deref(),
+            Some(all)
+        );
+
+        let proxy = from_env(&[("all_proxy", all), ("no_proxy", "*")]).unwrap();
+        assert_eq!(proxy_for(&proxy, "https://example.com"), None);
+
+        assert!(from_env(&[]).unwrap().is_empty());
+        assert!(from_env(&[("http_proxy", "not a url")]).is_err());
+        assert!(from_env(&[("http_proxy", "ftp://h:1")]).is_err());
+    }
+
+    #[test]
+    fn test_get_all_retry() {
+        let mut files = HashMap::new();
+        files.insert("/file".to_owned(), b"content".to_vec());
+        block_on(async move {
+            let server = MockServer::start(files);
+            let url = format!("{}/file", server.url);
+            let retry = RetryConfig {
+                max_retries: 2,
+                base_delay: Duration::from_millis(10),
+            };
+
+            server.fail_next("/file", 2);
+            assert_eq!(get_all_to_vec(&url, &retry).await.unwrap(), b"content");
+            assert_eq!(server.hits("/file"), 3);
+
+            server.fail_next("/file", 3);
+            assert!(get_all_to_vec(&url, &retry).await.is_err());
+            assert_eq!(server.hits("/file"), 6);
+
+            // Not found is not retried.
+            let missing = format!("{}/missing", server.url);
+            assert!(get_all_to_vec(&missing, &retry).await.is_err());
+            assert_eq!(server.hits("/missing"), 1);
+        });
+    }
+
+    #[test]
+    fn test_parse_git_revision() {
+        let p = |s: &str| parse_git_revision(s.to_owned());
+
+        let sha1 = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(p(sha1).unwrap(), sha1);
+        assert_eq!(p(&sha1.to_uppercase()).unwrap(), sha1);
+        let sha256 = "0123456789abcdef".repeat(4);
+        assert_eq!(p(&sha256).unwrap(), sha256);
+        assert_eq!(p("0123abc").unwrap(), "0123abc");
+
+        for s in &[
+            "",
+            "0123ab",
+            "not a revision",
+            "0123456789abcdeg",
+            &"0".repeat(65),
+        ] {
+            assert!(p(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_add_nix_channels_shared() {
+        block_on(async {
+            let mut files = mock_channel_files(&[HELLO_PATH]);
+            for file in &["git-revision", "store-paths.xz"] {
+                let content = files[&format!("/channel/{}", file)].clone();
+                files.insert(format!("/channel2/{}", file), content);
+            }
+            let server = MockServer::start_with_delay(files, Duration::from_millis(50));
+            let cache_url = format!("{}/cache", server.url);
+            let narinfo_path = "/cache/yhzvzdq82lzk0kvrp3i79yhjnhps6qpk.narinfo";
+
+            let share = NarInfoShare::default();
+            let (mut db1, mut db2) = (
+                Database::open_in_memory().unwrap(),
+                Database::open_in_memory().unwrap(),
+            );
+            let channel1 = format!("{}/channel", server.url);
+            let channel2 = format!("{}/channel2", server.url);
+            let (ret1, ret2) = future::join(
+                add_nix_channel_rec_outcome(
+                    &mut db1,
+                    &channel1,
+                    Some(&cache_url),
+                    AddChannelOptions {
+                        share: Some(&share),
+                        ..Default::default()
+                    },
+                    &FetchConfig::default(),
+                ),
+                add_nix_channel_rec_outcome(
+                    &mut db2,
+                    &channel2,
+                    Some(&cache_url),
+                    AddChannelOptions {
+                        share: Some(&share),
+                        ..Default::default()
+                    },
+                    &FetchConfig::default(),
+                ),
+            )
+            .await;
+            assert_eq!(ret1.unwrap().new_nars, 1);
+            assert_eq!(ret2.unwrap().new_nars, 1);
+            assert_eq!(server.hits(narinfo_path), 1);
+        });
+    }
 }
@@ -0,0 +1,311 @@
+use crate::database::model::{Hash, HashAlgo, NarMeta, NIX_BASE32_CHARS};
+use failure::{ensure, ResultExt as _};
+use ring::digest::{self, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA512};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use super::Result;
+
+fn nix_base32_len(bytes_len: usize) -> usize {
+    (bytes_len * 8 - 1) / 5 + 1
+}
+
+/// Encode bytes in Nix's base32, which starts from the last 5-bit group.
+pub(crate) fn nix_base32_encode(bytes: &[u8]) -> String {
+    let len = nix_base32_len(bytes.len());
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let mut c = bytes[i] >> j;
+            if i + 1 < bytes.len() && j > 3 {
+                c |= bytes[i + 1] << (8 - j);
+            }
+            NIX_BASE32_CHARS[(c & 31) as usize] as char
+        })
+        .collect()
+}
+
+/// Decode Nix's base32 into exactly `out.len()` bytes.
+pub(crate) fn nix_base32_decode(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != nix_base32_len(out.len()) {
+        return None;
+    }
+    out.iter_mut().for_each(|b| *b = 0);
+    for (k, c) in s.bytes().enumerate() {
+        let digit = NIX_BASE32_CHARS.iter().position(|&x| x == c)? as u8;
+        let n = s.len() - 1 - k;
+        let (i, j) = (n * 5 / 8, n * 5 % 8);
+        out[i] |= digit << j;
+        let carry = ((digit as u16) << j >> 8) as u8;
+        if i + 1 < out.len() {
+            out[i + 1] |= carry;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+    Some(())
+}
+
+/// Decode the digest of `hash`, in either Nix base32 or base16.
+pub(crate) fn decode_digest(hash: &Hash) -> Result<Vec<u8>> {
+    let digest = hash.digest();
+    let mut out = vec![0u8; hash.algo().digest_len()];
+    let ok = if digest.len() == out.len() * 2 {
+        out.iter_mut().enumerate().all(|(i, b)| {
+            match u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16) {
+                Ok(x) => {
+                    *b = x;
+                    true
+                }
+                Err(_) => false,
+            }
+        })
+    } else {
+        nix_base32_decode(digest, &mut out).is_some()
+    };
+    ensure!(ok, "Invalid hash: {}", hash);
+    Ok(out)
+}
+
+fn ring_algorithm(algo: HashAlgo) -> &'static digest::Algorithm {
+    match algo {
+        HashAlgo::Sha1 => &SHA1_FOR_LEGACY_USE_ONLY,
+        HashAlgo::Sha256 => &SHA256,
+        HashAlgo::Sha512 => &SHA512,
+    }
+}
+
+/// A reader which hashes and counts everything read through it.
+struct HashReader<R> {
+    inner: R,
+    algo: HashAlgo,
+    ctx: digest::Context,
+    len: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R, algo: HashAlgo) -> Self {
+        Self {
+            inner,
+            algo,
+            ctx: digest::Context::new(ring_algorithm(algo)),
+            len: 0,
+        }
+    }
+
+    /// Return the digest, the hash in `<algo>:<base32>` form, and the length.
+    fn finish(self) -> (Vec<u8>, String, u64) {
+        let digest = self.ctx.finish().as_ref().to_vec();
+        let hash = format!("{}:{}", self.algo.name(), nix_base32_encode(&digest));
+        (digest, hash, self.len)
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.ctx.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Check the (compressed) NAR file at `path` against `file_hash`, `file_size`,
+/// and the decompressed content against `nar_hash` and `nar_size` in `meta`.
+/// The NAR hash is not checked for unsupported compressions.
+pub fn verify_nar(path: &Path, meta: &NarMeta) -> Result<()> {
+    let expected_file_hash = meta.file_hash.as_ref().map(decode_digest).transpose()?;
+    let expected_nar_hash = decode_digest(&meta.nar_hash)?;
+    let file_algo = meta.file_hash.as_ref().map_or(HashAlgo::Sha256, Hash::algo);
+
+    let file = File::open(path).with_context(|_| format!("Cannot open {}", path.display()))?;
+    let mut file_reader = HashReader::new(BufReader::new(file), file_algo);
+    let hash_nar = |decoder: &mut dyn Read| -> Result<_> {
+        let mut nar_reader = HashReader::new(decoder, meta.nar_hash.algo());
+        io::copy(&mut nar_reader, &mut io::sink())
+            .with_context(|_| format!("Cannot decompress {}", path.display()))?;
+        Ok(Some(nar_reader.finish()))
+    };
+    let nar = match meta.compression.as_deref() {
+        None | Some("none") => hash_nar(&mut file_reader)?,
+        Some("xz") => hash_nar(&mut XzDecoder::new(&mut file_reader))?,
+        Some("zstd") => hash_nar(&mut ZstdDecoder::new(&mut file_reader)?)?,
+        Some(compression) => {
+            log::debug!("Skip checking NarHash of {} compression", compression);
+            io::copy(&mut file_reader, &mut io::sink())?;
+            None
+        }
+    };
+
+    let (file_digest, file_hash, file_size) = file_reader.finish();
+    if let Some(expected) = meta.file_size {
+        ensure!(
+            file_size == expected,
+            "FileSize mismatch: expected {}, got {}",
+            expected,
+            file_size,
+        );
+    }
+    if let Some(expected) = expected_file_hash {
+        ensure!(
+            file_digest == expected,
+            "FileHash mismatch: expected {}, got {}",
+            meta.file_hash.as_ref().unwrap(),
+            file_hash,
+        );
+    }
+    if let Some((nar_digest, nar_hash, nar_size)) = nar {
+        ensure!(
+            nar_size == meta.nar_size,
+            "NarSize mismatch: expected {}, got {}",
+            meta.nar_size,
+            nar_size,
+        );
+        ensure!(
+            nar_digest == expected_nar_hash,
+            "NarHash mismatch: expected {}, got {}",
+            meta.nar_hash,
+            nar_hash,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn sha256_str(data: &[u8]) -> String {
+    let hash = nix_base32_encode(digest::digest(&SHA256, data).as_ref());
+    format!("sha256:{}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+
+    const EMPTY_SHA256: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+
+    #[test]
+    fn test_nix_base32() {
+        assert_eq!(sha256_str(b""), format!("sha256:{}", EMPTY_SHA256));
+        assert_eq!(
+            sha256_str(b"hello"),
+            "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic",
+        );
+
+        let mut out = [0u8; 32];
+        nix_base32_decode(EMPTY_SHA256, &mut out).unwrap();
+        assert_eq!(nix_base32_encode(&out), EMPTY_SHA256);
+        // Bad length, bad char, or overflowing the last byte.
+        assert!(nix_base32_decode(&EMPTY_SHA256[1..], &mut out).is_none());
+        assert!(nix_base32_decode(&EMPTY_SHA256.replace('m', "e"), &mut out).is_none());
+        assert!(nix_base32_decode(&EMPTY_SHA256.replacen('0', "z", 1), &mut out).is_none());
+    }
+
+    #[test]
+    fn test_decode_digest() {
+        let empty = digest::digest(&SHA256, b"").as_ref().to_vec();
+        let hex = empty
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        for s in &[
+            EMPTY_SHA256.to_owned(),
+            format!("sha256:{}", EMPTY_SHA256),
+            format!("sha256:{}", hex),
+            format!("sha256:{}", hex.to_uppercase()),
+        ] {
+            assert_eq!(decode_digest(&s.parse().unwrap()).unwrap(), empty, "{}", s);
+        }
+        // Overflowing the last byte passes the character check of `Hash`.
+        let overflow = format!("sha256:{}", EMPTY_SHA256.replacen('0', "z", 1));
+        assert!(decode_digest(&overflow.parse().unwrap()).is_err());
+    }
+
+    fn test_meta(compression: Option<&str>, file: &[u8], nar: &[u8], bare: bool) -> NarMeta {
+        let hash = |data: &[u8]| {
+            let s = sha256_str(data);
+            let s = if bare { &s["sha256:".len()..] } else { &s };
+            s.parse().unwrap()
+        };
+        NarMeta {
+            url: "nar/test".to_owned(),
+            compression: compression.map(|s| s.to_owned()),
+            file_hash: Some(hash(file)),
+            file_size: Some(file.len() as u64),
+            nar_hash: hash(nar),
+            nar_size: nar.len() as u64,
+            deriver: None,
+            sig: vec![],
+            ca: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_nar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nar");
+        let nar = b"nix-archive-1 content".to_vec();
+        let mut enc = xz2::write::XzEncoder::new(vec![], 6);
+        enc.write_all(&nar).unwrap();
+        let xz = enc.finish().unwrap();
+
+        std::fs::write(&path, &xz).unwrap();
+        verify_nar(&path, &test_meta(Some("xz"), &xz, &nar, false)).unwrap();
+        verify_nar(&path, &test_meta(Some("xz"), &xz, &nar, true)).unwrap();
+        let mut meta = test_meta(Some("xz"), &xz, &nar, false);
+        meta.file_hash = None;
+        verify_nar(&path, &meta).unwrap();
+        meta.nar_hash = sha256_str(b"other").parse().unwrap();
+        assert!(verify_nar(&path, &meta).is_err());
+
+        // Truncated.
+        std::fs::write(&path, &xz[..xz.len() - 1]).unwrap();
+        let mut meta = test_meta(Some("xz"), &xz, &nar, false);
+        assert!(verify_nar(&path, &meta).is_err());
+        meta.file_hash = None;
+        meta.file_size = None;
+        assert!(verify_nar(&path, &meta).is_err());
+
+        let zstd = zstd::stream::encode_all(&nar[..], 3).unwrap();
+        std::fs::write(&path, &zstd).unwrap();
+        verify_nar(&path, &test_meta(Some("zstd"), &zstd, &nar, false)).unwrap();
+        let mut meta = test_meta(Some("zstd"), &zstd, &nar, false);
+        meta.file_hash = None;
+        meta.nar_hash = sha256_str(b"other").parse().unwrap();
+        assert!(verify_nar(&path, &meta).is_err());
+        // Not zstd at all.
+        assert!(verify_nar(&path, &test_meta(Some("zstd"), &xz, &nar, false)).is_err());
+
+        std::fs::write(&path, &nar).unwrap();
+        verify_nar(&path, &test_meta(None, &nar, &nar, false)).unwrap();
+        // Other algorithms, in base32 or base16.
+        for &(algo, name) in &[(&SHA1_FOR_LEGACY_USE_ONLY, "sha1"), (&SHA512, "sha512")] {
+            let digest = digest::digest(algo, &nar);
+            let base32 = format!("{}:{}", name, nix_base32_encode(digest.as_ref()));
+            let base16 = digest
+                .as_ref()
+                .iter()
+                .fold(format!("{}:", name), |s, b| s + &format!("{:02x}", b));
+            let mut meta = test_meta(None, &nar, &nar, false);
+            meta.file_hash = Some(base32.parse().unwrap());
+            meta.nar_hash = base16.parse().unwrap();
+            verify_nar(&path, &meta).unwrap();
+            meta.nar_hash = base32.parse().unwrap();
+            verify_nar(&path, &meta).unwrap();
+            meta.nar_hash = format!("{}:{}", name, "0".repeat(base16.len() - name.len() - 1))
+                .parse()
+                .unwrap();
+            assert!(verify_nar(&path, &meta).is_err());
+        }
+        let mut corrupted = nar.clone();
+        corrupted[0] ^= 1;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(verify_nar(&path, &test_meta(None, &nar, &nar, false)).is_err());
+    }
+}